@@ -0,0 +1,59 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use neat_tetris::{
+    net::{Activation, Aggregation, FeedForwardNetwork, NodeEval},
+    pos::{Position, FEATURE_COUNT},
+};
+
+/// Network reading every feature through 8 hidden nodes, about the size of
+/// the evolved ones.
+fn network() -> FeedForwardNetwork {
+    let inputs: Vec<i64> = (1..=FEATURE_COUNT as i64).map(|i| -i).collect();
+    let mut node_evals: Vec<NodeEval> = (1..=8)
+        .map(|node| {
+            let links = inputs
+                .iter()
+                .map(|&input| (input, ((node * input) % 7) as f64 / 7.))
+                .collect();
+            (node, Activation::Tanh, Aggregation::Sum, 0.1, 1., links)
+        })
+        .collect();
+    let links = (1..=8).map(|node| (node, 1. / node as f64)).collect();
+    node_evals.push((0, Activation::Sigmoid, Aggregation::Sum, 0., 1., links));
+
+    FeedForwardNetwork::new(inputs, vec![0], node_evals)
+}
+
+/// Features of the positions after each placement of the first piece, as
+/// the search scores them.
+fn inputs() -> Vec<Vec<f64>> {
+    let pos = Position::with_seed(1);
+
+    pos.legal_moves()[0]
+        .iter()
+        .map(|&(p, x, y, rot, swap)| pos.apply_move(p, x, y, rot, swap, false).feature_vector())
+        .collect()
+}
+
+fn evaluate_batch(c: &mut Criterion) {
+    let net = network();
+    let inputs = inputs();
+
+    let mut group = c.benchmark_group("evaluate_batch");
+    group.bench_function("batch", |b| {
+        b.iter(|| net.evaluate_batch(black_box(&inputs)))
+    });
+    group.bench_function("single", |b| {
+        let mut net = net.clone();
+        b.iter(|| {
+            black_box(&inputs)
+                .iter()
+                .map(|input| net.activate(input.clone()))
+                .collect::<Vec<_>>()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, evaluate_batch);
+criterion_main!(benches);
@@ -0,0 +1,124 @@
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    str::FromStr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use neat_tetris::pos::{Color, Position};
+
+/// System allocator counting the allocations made, to report them next to
+/// the timings.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Number of allocations `f` makes.
+fn allocations<R>(f: impl FnOnce() -> R) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    black_box(f());
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+/// Boards from empty to a stack a few rows from the top, with a T to place.
+fn positions() -> [(&'static str, Position); 3] {
+    [
+        ("empty", Position::from_str("////////////////////// T L 0").unwrap()),
+        (
+            "mid",
+            Position::from_str(
+                "///////////////2SI2L3/2SS2I3/3S2IT2/2ZZ2ITT1/3ZZ1IT2/ T L 0",
+            )
+            .unwrap(),
+        ),
+        (
+            "high",
+            Position::from_str(
+                "///3SS5/2SS6/3ZZ4I/4ZZ3I/4LL3I/1J3L3I/1JJJ1L2OO/OO3JJ1OO/OO1L1J2Z1/1LLL1JTZZ1/\
+                 TTT2TTZ2/1T1I2TOO1/JJJI1SSOO1/2JISSLLL1/2SI2L3/2SS2I3/3S2IT2/2ZZ2ITT1/3ZZ1IT2/ T L 0",
+            )
+            .unwrap(),
+        ),
+    ]
+}
+
+fn legal_moves(c: &mut Criterion) {
+    let mut group = c.benchmark_group("legal_moves");
+    for (name, pos) in positions() {
+        group.bench_function(name, |b| b.iter(|| black_box(&pos).legal_moves()));
+    }
+    group.finish();
+}
+
+fn legal_moves_into(c: &mut Criterion) {
+    let mut group = c.benchmark_group("legal_moves_into");
+    for (name, pos) in positions() {
+        let mut buf = Vec::new();
+        pos.legal_moves_into(&mut buf);
+
+        println!(
+            "legal_moves/{}: {} allocations, legal_moves_into/{}: {} allocations",
+            name,
+            allocations(|| pos.legal_moves()),
+            name,
+            allocations(|| pos.legal_moves_into(&mut buf))
+        );
+        group.bench_function(name, |b| {
+            b.iter(|| black_box(&pos).legal_moves_into(&mut buf))
+        });
+    }
+    group.finish();
+}
+
+fn apply_move(c: &mut Criterion) {
+    let mut group = c.benchmark_group("apply_move");
+    for (name, pos) in positions() {
+        let moves = pos.legal_moves()[0].clone();
+        let play_all = || {
+            for &(p, x, y, rot, swap) in &moves {
+                black_box(pos.apply_move(p, x, y, rot, swap, true));
+            }
+        };
+
+        println!(
+            "apply_move/{}: {} allocations for {} moves",
+            name,
+            allocations(play_all),
+            moves.len()
+        );
+        group.bench_function(name, |b| b.iter(play_all));
+    }
+
+    // The I in the well clears four rows
+    let pos = Position::from_str(
+        "/////////////////I9/IIIIIIIII1/IIIIIIIII1/IIIIIIIII1/IIIIIIIII1/ I O 0",
+    )
+    .unwrap();
+    group.bench_function("tetris", |b| {
+        b.iter(|| black_box(&pos).apply_move(Color::I, 9, 18, 1, false, true))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, legal_moves, legal_moves_into, apply_move);
+criterion_main!(benches);
@@ -31,15 +31,45 @@ class Engine:
         }
 
         self.send_message(msg)
+        return self.receive_message()
 
-    def play_game(self):
-        msg = { "type": "PlayGame" }
+    def load_file(self, path):
+        msg = { "type": "LoadFile", "path": path }
+        self.send_message(msg)
+        return self.receive_message()
+
+    def add_network(self, input_nodes, output_nodes, node_evals):
+        msg = {
+            "type": "AddNetwork",
+            "input_nodes": input_nodes,
+            "output_nodes": output_nodes,
+            "node_evals": node_evals
+        }
+
+        self.send_message(msg)
+        return self.receive_message()
+
+    def play_game(self, record=False, report_every=0, on_progress=None, max_repeats=0,
+                  max_pieces=None):
+        msg = {
+            "type": "PlayGame",
+            "record": record,
+            "report_every": report_every,
+            "max_repeats": max_repeats,
+            "max_pieces": max_pieces
+        }
         self.send_message(msg)
         response = self.receive_message()
+        while response["type"] == "Progress":
+            if on_progress is not None:
+                on_progress(response)
+            response = self.receive_message()
+        if record:
+            return response["score"], response["record"]
         return response["score"]
 
-    def pos(self, tpn):
-        msg = { "type": "Pos", "tpn": tpn }
+    def pos(self, tpn, bag=None, pocket=None):
+        msg = { "type": "Pos", "tpn": tpn, "bag": bag, "pocket": pocket }
         self.send_message(msg)
 
     def go(self):
@@ -49,16 +79,66 @@ class Engine:
 
     
     def peek(self):
-        msg = { "type": "Peek" }
+        msg = { "type": "Peek", "diff": False }
         self.send_message(msg)
         out = self.receive_message()
         print(out)
         return tpn.loads(out["tpn"])
 
+    def peek_diff(self, position):
+        msg = { "type": "Peek", "diff": True }
+        self.send_message(msg)
+        return tpn.apply_diff(position, self.receive_message())
+
+    def apply_move(self, col, rotation, swap=False):
+        msg = { "type": "ApplyMove", "col": col, "rotation": rotation, "swap": swap }
+        self.send_message(msg)
+        return self.receive_message()
+
+    def features(self):
+        msg = { "type": "Features" }
+        self.send_message(msg)
+        return self.receive_message()
+
+    def evaluate(self):
+        msg = { "type": "Evaluate" }
+        self.send_message(msg)
+        return self.receive_message()["outputs"]
+
+    def legal_moves(self):
+        msg = { "type": "LegalMoves" }
+        self.send_message(msg)
+        return self.receive_message()["moves"]
+
+    def save_state(self, path):
+        msg = { "type": "SaveState", "path": path }
+        self.send_message(msg)
+        return self.receive_message()
+
+    def load_state(self, path):
+        msg = { "type": "LoadState", "path": path }
+        self.send_message(msg)
+        return self.receive_message()
+
+    def reset(self, seed=None):
+        msg = { "type": "Reset", "seed": seed }
+        self.send_message(msg)
+        return self.receive_message()
+
+    def hello(self, version=2):
+        msg = { "type": "Hello", "version": version }
+        self.send_message(msg)
+        return self.receive_message()
+
     def ready(self):
         msg = { "type": "Ready" }
         self.send_message(msg)
         return self.receive_message()
 
+    def quit(self):
+        msg = { "type": "Quit" }
+        self.send_message(msg)
+        self.process.wait()
+
     def terminate(self):
         self.process.terminate()
\ No newline at end of file
@@ -124,6 +124,7 @@ class Graphic():
         self.fps = fps
         self.board = board
         self.current_piece = -1
+        self.hold_piece = 0
         self.next_pieces = []
         self.action_list = []
         self.score = 0
@@ -263,6 +264,13 @@ class Graphic():
                 rot = (rot - 1) % rot_num
                 x_offset -= offsets[0]
                 y_offset -= offsets[1]
+            elif action == "Hold":
+                # Holding with an empty pocket takes the next piece
+                held = self.current_piece
+                self.current_piece = self.hold_piece or self.next_pieces[0]
+                self.hold_piece = held
+                x_offset, y_offset = SPAWNS[self.current_piece-1]
+                rot = 0
             elif action == "RotateClockwise":
                 rot_num = len(ROTATION_TABLE[self.current_piece - 1])
                 offsets = ROTATION_TABLE[self.current_piece - 1][rot]
@@ -65,6 +65,10 @@ def convert_command(commands, num_columns):
 
     return [command_1, command_2]
 
+def function_name(func):
+    # neat-python functions are named like "tanh_activation" or "sum_aggregation"
+    return func.__name__.rsplit("_", 1)[0]
+
 def eval_genome(genome, config):
     play_engine = engine.Engine("./target/release/neat-tetris")
 
@@ -72,10 +76,15 @@ def eval_genome(genome, config):
     cleaned_node_evals = []
 
     for e in net.node_evals:
-        a, _, _, b, c, d = e
-        cleaned_node_evals.append((a, b, c, d))
+        a, act, agg, b, c, d = e
+        cleaned_node_evals.append((a, function_name(act), function_name(agg), b, c, d))
+
+    response = play_engine.load(net.input_nodes, net.output_nodes, cleaned_node_evals)
+    if response["type"] == "Ko":
+        # Networks with a disconnected output are rejected
+        play_engine.terminate()
+        return 0
 
-    play_engine.load(net.input_nodes, net.output_nodes, cleaned_node_evals)
     res = play_engine.play_game()
     play_engine.terminate()
     return res
@@ -127,11 +136,13 @@ if __name__ == "__main__":
 
         cleaned_node_evals = []
         for e in net.node_evals:
-            a, _, _, b, c, d = e
-            cleaned_node_evals.append((a, b, c, d))
+            a, act, agg, b, c, d = e
+            cleaned_node_evals.append((a, function_name(act), function_name(agg), b, c, d))
 
-        play_engine.load(net.input_nodes, net.output_nodes, cleaned_node_evals)
+        response = play_engine.load(net.input_nodes, net.output_nodes, cleaned_node_evals)
+        assert response["type"] == "Ok", response["reason"]
 
+        play_engine.reset()
         pos = play_engine.peek()
 
         board = np.array(pos.board)
@@ -0,0 +1,301 @@
+use arrayvec::ArrayVec;
+use lazy_static::lazy_static;
+
+use crate::pos::{piece_shape, Board, Cell, Color, Piece, BOARD_HEIGHT, BOARD_WIDTH, PIECE_COUNT};
+
+/// Largest side of a piece
+const MAX_PIECE_SIZE: usize = 4;
+
+/// Rows of a piece as bit masks, bit `i` standing for column `i` of the
+/// piece, from top to bottom.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PieceMask {
+    pub rows: ArrayVec<u16, MAX_PIECE_SIZE>,
+    pub width: usize,
+}
+
+impl PieceMask {
+    pub fn new(piece: &Piece) -> PieceMask {
+        PieceMask {
+            rows: piece
+                .iter()
+                .map(|row| {
+                    row.iter()
+                        .enumerate()
+                        .filter(|(_, cell)| !cell.is_empty())
+                        .fold(0, |mask, (i, _)| mask | 1 << i)
+                })
+                .collect(),
+            width: piece[0].len(),
+        }
+    }
+
+    pub fn height(&self) -> usize {
+        self.rows.len()
+    }
+}
+
+lazy_static! {
+    static ref PIECE_MASKS: Vec<Vec<PieceMask>> = (1..=PIECE_COUNT)
+        .map(|piece| {
+            (0..)
+                .map_while(|rotation| piece_shape(piece, rotation))
+                .map(PieceMask::new)
+                .collect()
+        })
+        .collect();
+}
+
+/// Mask of `piece` in the given rotation, see `piece_shape`.
+pub fn piece_mask(piece: Color, rotation: usize) -> &'static PieceMask {
+    &PIECE_MASKS[piece as usize - 1][rotation]
+}
+
+/// Occupancy of a board, one row per `u16` with bit `x` set when column `x`
+/// is filled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BitBoard {
+    rows: [u16; BOARD_HEIGHT],
+    width: usize,
+    height: usize,
+}
+
+impl BitBoard {
+    pub fn new(width: usize, height: usize) -> Self {
+        assert!(width <= u16::BITS as usize && height <= BOARD_HEIGHT);
+
+        BitBoard {
+            rows: [0; BOARD_HEIGHT],
+            width,
+            height,
+        }
+    }
+
+    /// Only the top left `width` x `height` corner of `board` is read.
+    pub fn from_board<T: Cell>(board: &Board<T>, width: usize, height: usize) -> Self {
+        let mut bits = BitBoard::new(width, height);
+
+        for (y, row) in board[..height].iter().enumerate() {
+            bits.rows[y] = row[..width]
+                .iter()
+                .enumerate()
+                .filter(|(_, cell)| !cell.is_empty())
+                .fold(0, |mask, (x, _)| mask | 1 << x);
+        }
+
+        bits
+    }
+
+    /// Board with the filled cells set to `color`, the colors of the pieces
+    /// being lost in the conversion.
+    pub fn to_board(&self, color: Color) -> Board<Color> {
+        let mut board = [[Color::Empty; BOARD_WIDTH]; BOARD_HEIGHT];
+
+        for y in 0..self.height {
+            for x in 0..self.width {
+                if self.is_filled(x, y) {
+                    board[y][x] = color;
+                }
+            }
+        }
+
+        board
+    }
+
+    fn full_row(&self) -> u16 {
+        (1 << self.width) - 1
+    }
+
+    pub fn is_filled(&self, x: usize, y: usize) -> bool {
+        self.rows[y] & 1 << x != 0
+    }
+
+    pub fn fill(&mut self, x: usize, y: usize) {
+        self.rows[y] |= 1 << x;
+    }
+
+    /// Whether `piece` with its top left corner at `(x, y)` overlaps a filled
+    /// cell or sticks out of the board.
+    pub fn collides(&self, piece: &PieceMask, x: i32, y: i32) -> bool {
+        if x < 0
+            || y < 0
+            || x as usize + piece.width > self.width
+            || y as usize + piece.height() > self.height
+        {
+            return true;
+        }
+
+        piece
+            .rows
+            .iter()
+            .zip(&self.rows[y as usize..])
+            .any(|(piece_row, row)| piece_row << x & row != 0)
+    }
+
+    /// Columns `x` such that `piece` at `(x, y)` does not collide, as bits.
+    pub fn free_columns(&self, piece: &PieceMask, y: usize) -> u16 {
+        if piece.width > self.width || y + piece.height() > self.height {
+            return 0;
+        }
+
+        let mut blocked = 0;
+        for (&piece_row, &row) in piece.rows.iter().zip(&self.rows[y..]) {
+            // Filled cells block the columns that would put a cell of the
+            // piece on them
+            let mut cells = piece_row;
+            while cells != 0 {
+                blocked |= row >> cells.trailing_zeros();
+                cells &= cells - 1;
+            }
+        }
+
+        !blocked & ((1 << (self.width - piece.width + 1)) - 1)
+    }
+
+    /// Whether `piece` fits at `(x, y)` and rests on the stack or the floor.
+    pub fn is_lock(&self, piece: &PieceMask, x: i32, y: i32) -> bool {
+        !self.collides(piece, x, y) && self.collides(piece, x, y + 1)
+    }
+
+    pub fn place(&mut self, piece: &PieceMask, x: usize, y: usize) {
+        for (j, piece_row) in piece.rows.iter().enumerate() {
+            self.rows[y + j] |= piece_row << x;
+        }
+    }
+
+    /// Indices of the full rows, from top to bottom.
+    pub fn full_rows(&self) -> ArrayVec<usize, BOARD_HEIGHT> {
+        let full_row = self.full_row();
+
+        (0..self.height)
+            .filter(|&y| self.rows[y] == full_row)
+            .collect()
+    }
+
+    /// Removes the full rows, shifting the ones above down, and returns how
+    /// many there were.
+    pub fn clear_rows(&mut self) -> usize {
+        let full_row = self.full_row();
+        let mut top = self.height;
+
+        for y in (0..self.height).rev() {
+            if self.rows[y] != full_row {
+                top -= 1;
+                self.rows[top] = self.rows[y];
+            }
+        }
+
+        // Every row above `top` was cleared
+        self.rows[..top].fill(0);
+
+        top
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::pos::Position;
+
+    const BOARDS: [&str; 3] = [
+        "////////////////////// T O 0",
+        "//////////////4I5/3III4/2IIIII3/1IIII1III1/IIII2IIII/IIIII1IIII/IIIIII1III/IIIIIII1II/ T O 0",
+        "////////////II8/I9/I1IIIIIIII/I2IIIIIII/I1IIIIIIII////// S Z 0",
+    ];
+
+    // Reference implementation, cell by cell
+    fn collides(pos: &Position, piece: &Piece, x: i32, y: i32) -> bool {
+        let width = piece[0].len() as i32;
+        let height = piece.len() as i32;
+
+        if x < 0 || y < 0 || x + width > pos.width as i32 || y + height > pos.height as i32 {
+            return true;
+        }
+
+        (0..height).any(|j| {
+            (0..width).any(|i| {
+                !piece[j as usize][i as usize].is_empty()
+                    && !pos.board[(y + j) as usize][(x + i) as usize].is_empty()
+            })
+        })
+    }
+
+    #[test]
+    fn test_collides() {
+        for tpn in BOARDS {
+            let pos = Position::from_str(tpn).unwrap();
+            let bits = BitBoard::from_board(&pos.board, pos.width, pos.height);
+
+            for piece in 1..=PIECE_COUNT {
+                let color = Color::piece(piece).unwrap();
+
+                for (rotation, shape) in (0..).map_while(|r| piece_shape(piece, r)).enumerate() {
+                    for x in -2..BOARD_WIDTH as i32 + 2 {
+                        for y in -2..BOARD_HEIGHT as i32 + 2 {
+                            assert_eq!(
+                                bits.collides(piece_mask(color, rotation), x, y),
+                                collides(&pos, shape, x, y),
+                                "{:?} in rotation {} at ({}, {}) on {}",
+                                color,
+                                rotation,
+                                x,
+                                y,
+                                tpn
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_free_columns() {
+        for tpn in BOARDS {
+            let pos = Position::from_str(tpn).unwrap();
+            let bits = BitBoard::from_board(&pos.board, pos.width, pos.height);
+
+            for piece in 1..=PIECE_COUNT {
+                let color = Color::piece(piece).unwrap();
+
+                for (rotation, shape) in (0..).map_while(|r| piece_shape(piece, r)).enumerate() {
+                    for y in 0..BOARD_HEIGHT {
+                        let free = (0..BOARD_WIDTH)
+                            .filter(|&x| !collides(&pos, shape, x as i32, y as i32))
+                            .fold(0, |free, x| free | 1 << x);
+                        assert_eq!(bits.free_columns(piece_mask(color, rotation), y), free);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_clear_rows() {
+        let pos =
+            Position::from_str("//////////////////I9/IIIIIIIIII/1IIIIIIIII/IIIIIIIIII/ T O 0")
+                .unwrap();
+        let mut bits = BitBoard::from_board(&pos.board, pos.width, pos.height);
+
+        assert_eq!(bits.full_rows().as_slice(), &[19, 21]);
+        assert_eq!(bits.clear_rows(), 2);
+        assert!(bits.full_rows().is_empty());
+
+        let expected = Position::from_str("////////////////////I9/1IIIIIIIII/ T O 0").unwrap();
+        assert_eq!(bits.to_board(Color::I), expected.board);
+    }
+
+    #[test]
+    fn test_place() {
+        let mut bits = BitBoard::new(6, 6);
+        bits.place(piece_mask(Color::T, 0), 2, 4);
+
+        assert!(bits.is_filled(2, 4) && bits.is_filled(3, 4) && bits.is_filled(4, 4));
+        assert!(bits.is_filled(3, 5));
+        assert!(!bits.is_filled(2, 5));
+        assert!(bits.is_lock(piece_mask(Color::O, 0), 2, 2));
+        assert!(!bits.is_lock(piece_mask(Color::O, 0), 0, 2));
+    }
+}
@@ -1,15 +1,42 @@
-use std::{io, str::FromStr, time::Instant};
+use std::{
+    fs,
+    io::{self, BufRead, Write},
+    path::Path,
+    str::FromStr,
+    time::Instant,
+};
 
+use arrayvec::ArrayVec;
+use log::{debug, trace};
 use serde::{Deserialize, Serialize};
-use lazy_static::lazy_static;
-use std::sync::Mutex;
 
 use crate::{
-    net::FeedForwardNetwork,
-    pos::{Action, Position},
-    search::Search,
+    net::{FeedForwardNetwork, NetworkSpec},
+    pos::{Action, Board, Color, Features, MoveResult, Position, BOARD_HEIGHT, BOARD_WIDTH},
+    search::{check_network_inputs, Ensemble, EvalMode, Evaluator, Search},
+    selfplay::{play_game, record_game, CycleDetector, Recorder, MAX_PIECES},
 };
 
+/// Version of the protocol, only changed by incompatible changes. Additions
+/// are announced in `PROTOCOL_FEATURES` instead.
+const PROTOCOL_VERSION: u32 = 2;
+
+/// Optional parts of the protocol this build supports, sent by `Out::Hello`
+const PROTOCOL_FEATURES: &[&str] = &[
+    "t-spin",
+    "diff",
+    "ensemble",
+    "load-file",
+    "legal-moves",
+    "evaluate",
+    "bag-pocket",
+    "progress",
+    "max-repeats",
+    "max-pieces",
+    "reset",
+    "state",
+];
+
 // lazy_static! {
 //     pub static ref POSITION_HISTORY: Mutex<Vec<String>> = Mutex::new(Vec::new());
 // }
@@ -17,112 +44,1366 @@ use crate::{
 #[derive(Deserialize)]
 #[serde(tag = "type")]
 enum In {
-    Load {
-        input_nodes: Vec<i64>,
-        output_nodes: Vec<i64>,
-        node_evals: Vec<(i64, f64, f64, Vec<(i64, f64)>)>,
+    Load(NetworkSpec),
+    /// Same as `Load`, with the network read from a JSON file
+    LoadFile {
+        path: String,
     },
+    /// Adds a network to the loaded ones, which then vote on every position
+    AddNetwork(NetworkSpec),
     Pos {
         tpn: String,
+        bag: Option<Vec<usize>>,
+        pocket: Option<usize>,
+    },
+    Peek {
+        /// Whether to only send the cells changed since the last board sent
+        #[serde(default)]
+        diff: bool,
+    },
+    Features,
+    /// Placements of the current piece, as `ApplyMove` takes them
+    LegalMoves,
+    /// Every output of the loaded networks for the current position
+    Evaluate,
+    SaveState {
+        path: String,
+    },
+    LoadState {
+        path: String,
+    },
+    ApplyMove {
+        col: usize,
+        rotation: usize,
+        swap: bool,
+    },
+    PlayGame {
+        /// Whether to send back a record of the game
+        #[serde(default)]
+        record: bool,
+        /// Placements between two `Progress` messages, 0 for none
+        #[serde(default)]
+        report_every: usize,
+        /// Times a board may come back with the same piece before the game
+        /// is stopped as a draw, 0 for no limit
+        #[serde(default)]
+        max_repeats: usize,
+        /// Placements after which the game is stopped, at most and by
+        /// default `MAX_PIECES`
+        max_pieces: Option<usize>,
+    },
+    /// Starts over from an empty board, the loaded networks being kept
+    Reset {
+        /// Seed of the pieces, random if missing
+        seed: Option<u64>,
+    },
+    /// Asks for the protocol version and features, the driver sending the
+    /// version it speaks
+    Hello {
+        version: u32,
     },
-    Peek,
-    PlayGame,
     Ready,
+    /// Plays the move found for the position given by `Pos`, `Reset` or
+    /// `LoadState`
     Go,
+    Quit,
 }
 
 #[derive(Serialize)]
 #[serde(tag = "type")]
 enum Out {
-    Move { action_list: Vec<Action> },
-    Pos { tpn: String },
-    GameResult { score: i64 },
+    Move {
+        col: usize,
+        rotation: usize,
+        swap: bool,
+        action_list: Vec<Action>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        score: Option<f64>,
+    },
+    /// Position with the bag and pocket, which `In::Pos` takes back as is
+    Pos {
+        tpn: String,
+        bag: Vec<usize>,
+        pocket: Option<usize>,
+    },
+    PosDiff {
+        /// Column, row and piece id of the changed cells, 0 for an empty one
+        cells: Vec<(usize, usize, u8)>,
+        /// Rows cleared by each move played since the last board sent, from
+        /// the top, to remove in order before changing `cells`. Those are the
+        /// `MoveOutcome::cleared_rows`, the cells moved by `Gravity::Sticky`
+        /// being in `cells`
+        cleared_rows: Vec<Vec<usize>>,
+        current_piece: u8,
+        next_piece: u8,
+        score: i64,
+    },
+    Features(Features),
+    Evaluation {
+        outputs: Vec<f64>,
+    },
+    Moves {
+        /// Column, rotation and whether the move holds
+        moves: Vec<(usize, usize, bool)>,
+    },
+    Progress {
+        pieces: usize,
+        lines: usize,
+        score: i64,
+    },
+    GameResult {
+        score: i64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        record: Option<Box<Recorder>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reason: Option<EndReason>,
+    },
+    Hello {
+        version: u32,
+        features: Vec<String>,
+    },
+    Saved,
+    Loaded,
     Ok,
-    Ko,
+    Ko {
+        error_code: ErrorCode,
+        reason: String,
+    },
 }
 
-fn send(msg: &Out) -> io::Result<()> {
-    println!("{}", serde_json::to_string(msg)?);
-    Ok(())
+/// Why `PlayGame` stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+enum EndReason {
+    GameOver,
+    /// The same board and piece came back more than `max_repeats` times
+    Repetition,
+    /// `max_pieces` placements were played
+    PieceLimit,
+    /// No move was found
+    Stopped,
+}
+
+/// Kind of failure reported by `Out::Ko`, for clients to react to without
+/// parsing the message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+enum ErrorCode {
+    /// The line is not a known message
+    InvalidMessage,
+    InvalidPosition,
+    /// The network does not parse, is not feed-forward, or does not match
+    /// the ones already loaded
+    InvalidNetwork,
+    NoNetwork,
+    /// `Go` was sent before any position
+    NoPosition,
+    IllegalMove,
+    GameOver,
+    /// A field of the message is out of its range
+    InvalidArgument,
+    /// A path does not exist or cannot be read or written
+    File,
+    /// The driver speaks another version of the protocol
+    Version,
+}
+
+/// Failure of a message, sent back as `Out::Ko`.
+type Failure = (ErrorCode, String);
+
+fn ko(error_code: ErrorCode, reason: impl Into<String>) -> Out {
+    Out::Ko {
+        error_code,
+        reason: reason.into(),
+    }
+}
+
+/// Removes `rows` from `board`, moving the rows above them down.
+fn clear_rows(board: &mut Board<Color>, rows: &[usize]) {
+    for &row in rows {
+        board.copy_within(..row, 1);
+        board[0] = [Color::Empty; BOARD_WIDTH];
+    }
+}
+
+/// Cells of `pos` differing from `last_board`.
+fn board_diff(last_board: &Board<Color>, pos: &Position) -> Vec<(usize, usize, u8)> {
+    let mut cells = Vec::new();
+
+    for (y, (last_row, row)) in last_board.iter().zip(&pos.board).enumerate() {
+        for (x, (&last_cell, &cell)) in last_row.iter().zip(row).enumerate() {
+            if last_cell != cell {
+                cells.push((x, y, cell as u8));
+            }
+        }
+    }
+
+    cells
+}
+
+/// Placements of the current piece, none if it is not known yet.
+fn legal_moves(pos: &Position) -> Vec<(usize, usize, bool)> {
+    if pos.current_piece == Color::Random {
+        return Vec::new();
+    }
+
+    pos.legal_moves()
+        .into_iter()
+        .next()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(_, x, _, rotation, swap)| (x, rotation, swap))
+        .collect()
+}
+
+/// Pieces are numbered from 1 to 7, a missing bag means a fresh one.
+fn parse_pos(tpn: &str, bag: Option<Vec<usize>>, pocket: Option<usize>) -> Option<Position> {
+    let mut pos = Position::from_str(tpn).ok()?;
+
+    if let Some(bag) = bag {
+        let pieces = bag
+            .into_iter()
+            .map(Color::piece)
+            .collect::<Option<Vec<Color>>>()?;
+        pos.bag = ArrayVec::try_from(pieces.as_slice()).ok()?;
+    }
+
+    pos.pocket = match pocket {
+        Some(id) => Some(Color::piece(id)?),
+        None => None,
+    };
+
+    Some(pos)
+}
+
+fn pos_message(pos: &Position) -> Out {
+    Out::Pos {
+        tpn: pos.to_string(),
+        bag: pos.bag.iter().map(|&piece| piece as usize).collect(),
+        pocket: pos.pocket.map(|piece| piece as usize),
+    }
+}
+
+fn save_state(pos: &Position, path: &str) -> Result<(), Failure> {
+    let path = Path::new(path);
+    if path.as_os_str().is_empty() || path.is_dir() {
+        return Err((ErrorCode::File, format!("Invalid save path {:?}", path)));
+    }
+
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        if !parent.is_dir() {
+            return Err((ErrorCode::File, format!("No such directory {:?}", parent)));
+        }
+    }
+
+    fs::write(path, pos.to_json()).map_err(|err| (ErrorCode::File, err.to_string()))
+}
+
+fn read_file(path: &str) -> Result<String, Failure> {
+    let path = Path::new(path);
+    if !path.is_file() {
+        return Err((ErrorCode::File, format!("No such file {:?}", path)));
+    }
+
+    fs::read_to_string(path).map_err(|err| (ErrorCode::File, err.to_string()))
+}
+
+fn load_state(path: &str) -> Result<Position, Failure> {
+    let json = read_file(path)?;
+    Position::from_json(&json).map_err(|err| (ErrorCode::InvalidPosition, err.to_string()))
+}
+
+fn read_spec(path: &str) -> Result<NetworkSpec, Failure> {
+    let json = read_file(path)?;
+    serde_json::from_str(&json).map_err(|err| (ErrorCode::InvalidNetwork, err.to_string()))
+}
+
+fn send<W: Write>(output: &mut W, msg: &Out) -> io::Result<()> {
+    writeln!(output, "{}", serde_json::to_string(msg)?)
 }
 
 pub fn start() -> io::Result<()> {
+    run(io::stdin().lock(), io::stdout().lock())
+}
+
+/// Checks `spec` and adds it to `nets`, which are emptied first unless
+/// `append` is set.
+fn load_network(
+    search: &mut Search,
+    nets: &mut Ensemble,
+    spec: NetworkSpec,
+    append: bool,
+) -> Result<(), Failure> {
+    let new_net = FeedForwardNetwork::from_spec(spec);
+    new_net
+        .validate()
+        .map_err(|err| (ErrorCode::InvalidNetwork, err.to_string()))?;
+
+    check_network_inputs(&new_net).map_err(|err| (ErrorCode::InvalidNetwork, err))?;
+
+    let mode = EvalMode::for_network(&new_net);
+    if append && !nets.nets.is_empty() && mode != search.mode() {
+        return Err((
+            ErrorCode::InvalidNetwork,
+            format!(
+                "Cannot add a {:?} network to {:?} ones",
+                mode,
+                search.mode()
+            ),
+        ));
+    }
+    if append && !nets.nets.is_empty() && new_net.num_outputs() != nets.num_outputs() {
+        return Err((
+            ErrorCode::InvalidNetwork,
+            format!(
+                "Cannot add a network with {} outputs to ones with {}",
+                new_net.num_outputs(),
+                nets.num_outputs()
+            ),
+        ));
+    }
+
+    if !append {
+        nets.nets.clear();
+    }
+    search.set_mode(mode);
+    search.clear_tt();
+    nets.nets.push(Box::new(new_net));
+
+    Ok(())
+}
+
+fn run<R: BufRead, W: Write>(mut input: R, mut output: W) -> io::Result<()> {
     let mut buffer = String::new();
-    let stdin = io::stdin(); // We get `Stdin` here.
     let mut pos: Position = Position::default();
-    let mut net: Option<FeedForwardNetwork> = None;
+    // Whether the driver gave the position, pieces included, that `Go`
+    // searches
+    let mut pos_set = false;
+    let mut nets = Ensemble::default();
     let mut search = Search::new();
+    // Board the client last received, which `Peek` diffs are relative to
+    let mut last_board = [[Color::Empty; BOARD_WIDTH]; BOARD_HEIGHT];
+    // Rows cleared since then, which `last_board` already has removed
+    let mut cleared_rows = Vec::new();
 
     loop {
         buffer.clear();
-        let lenght = stdin.read_line(&mut buffer)?;
+        let lenght = input.read_line(&mut buffer)?;
         if lenght == 0 {
             break;
         }
 
-        let msg_in: In = serde_json::from_str(&buffer).expect("");
+        trace!("Received {}", buffer.trim_end());
+        let msg_in: In = match serde_json::from_str(&buffer) {
+            Ok(msg_in) => msg_in,
+            Err(err) => {
+                debug!("Invalid message: {}", err);
+                send(&mut output, &ko(ErrorCode::InvalidMessage, err.to_string()))?;
+                continue;
+            }
+        };
 
         match msg_in {
-            In::Load {
-                input_nodes,
-                output_nodes,
-                node_evals,
-            } => {
-                net = Some(FeedForwardNetwork::new(
-                    input_nodes,
-                    output_nodes,
-                    node_evals,
-                ));
-            }
-            In::Pos { tpn } => {
-                // TODO: Clean error handling
-                pos = Position::from_str(&tpn).unwrap();
+            In::Load(spec) => match load_network(&mut search, &mut nets, spec, false) {
+                Ok(()) => send(&mut output, &Out::Ok)?,
+                Err((code, reason)) => send(&mut output, &ko(code, reason))?,
+            },
+            In::LoadFile { path } => {
+                match read_spec(&path)
+                    .and_then(|spec| load_network(&mut search, &mut nets, spec, false))
+                {
+                    Ok(()) => send(&mut output, &Out::Ok)?,
+                    Err((code, reason)) => send(&mut output, &ko(code, reason))?,
+                }
             }
+            In::AddNetwork(spec) => match load_network(&mut search, &mut nets, spec, true) {
+                Ok(()) => send(&mut output, &Out::Ok)?,
+                Err((code, reason)) => send(&mut output, &ko(code, reason))?,
+            },
+            // Only failures get a reply
+            In::Pos { tpn, bag, pocket } => match parse_pos(&tpn, bag, pocket) {
+                Some(new_pos) => match new_pos.validate() {
+                    Ok(()) => {
+                        pos = new_pos;
+                        pos_set = true;
+                    }
+                    Err(err) => send(
+                        &mut output,
+                        &ko(ErrorCode::InvalidPosition, err.to_string()),
+                    )?,
+                },
+                None => send(
+                    &mut output,
+                    &ko(
+                        ErrorCode::InvalidPosition,
+                        format!("Invalid position {:?}", tpn),
+                    ),
+                )?,
+            },
+            In::Go if !pos_set => send(
+                &mut output,
+                &ko(
+                    ErrorCode::NoPosition,
+                    "No position to search, send Pos with the current and next pieces first",
+                ),
+            )?,
             In::Go => {
-                if let Some(nn) = &mut net {
-                    let start = Instant::now();
-
-                    match search.run(nn, &pos) {
-                        Some((best, action_list)) => {
-                            pos = pos
-                                .apply_move(pos.current_piece, best.0, best.1, best.2, true);
-                            let end = Instant::now();
-                            eprintln!("Thinking time: {}", (end - start).as_millis());
-                            send(&Out::Move { action_list })?;
-                        },
-                        None => send(&Out::GameResult { score: pos.score })?
+                let start = Instant::now();
+
+                match search.run(&mut nets, &pos) {
+                    Some(best_move) => {
+                        let (p, x, y, rot, swap) = best_move.placement();
+                        let outcome = pos.apply_move_detailed(p, x, y, rot, swap, true);
+                        pos = outcome.position;
+                        if !outcome.cleared_rows.is_empty() {
+                            clear_rows(&mut last_board, &outcome.cleared_rows);
+                            cleared_rows.push(outcome.cleared_rows);
+                        }
+                        debug!(
+                            "Move chosen: {:?} at column {}, rotation {}{}, score {} after {} ms",
+                            p,
+                            x,
+                            rot,
+                            if swap { " from the hold" } else { "" },
+                            best_move.score,
+                            start.elapsed().as_millis()
+                        );
+                        send(
+                            &mut output,
+                            &Out::Move {
+                                col: best_move.x,
+                                rotation: best_move.rotation,
+                                swap: best_move.swap,
+                                action_list: best_move.action_list,
+                                score: Some(best_move.score),
+                            },
+                        )?;
+                    }
+                    None => {
+                        debug!("No move found, game over with score {}", pos.score);
+                        send(
+                            &mut output,
+                            &Out::GameResult {
+                                score: pos.score,
+                                record: None,
+                                reason: None,
+                            },
+                        )?
                     }
                 }
             }
-            In::Peek => {
-                println!(
-                    "{}",
-                    serde_json::to_string(&Out::Pos {
-                        tpn: pos.to_string()
-                    })?
-                )
+            In::Peek { diff } => {
+                let msg = if diff {
+                    Out::PosDiff {
+                        cells: board_diff(&last_board, &pos),
+                        cleared_rows: std::mem::take(&mut cleared_rows),
+                        current_piece: pos.current_piece as u8,
+                        next_piece: pos.next_piece as u8,
+                        score: pos.score,
+                    }
+                } else {
+                    pos_message(&pos)
+                };
+                last_board = pos.board;
+                cleared_rows.clear();
+                send(&mut output, &msg)?;
             }
-            In::PlayGame => {
-                if let Some(nn) = &mut net {
-                    for _ in 0..1000 {
-                        match search.run(nn, &pos) {
-                            Some((mv, _)) => {
-                                pos = pos.apply_move(pos.current_piece, mv.0, mv.1, mv.2, true);
-                                // POSITION_HISTORY.lock().unwrap().push(pos.to_string());
-                            },
-                            None => break
+            In::Features => send(&mut output, &Out::Features(pos.features()))?,
+            In::Evaluate => {
+                if nets.nets.is_empty() {
+                    send(&mut output, &ko(ErrorCode::NoNetwork, "No network loaded"))?
+                } else {
+                    send(
+                        &mut output,
+                        &Out::Evaluation {
+                            outputs: nets.evaluate(&pos),
+                        },
+                    )?
+                }
+            }
+            In::LegalMoves => send(
+                &mut output,
+                &Out::Moves {
+                    moves: legal_moves(&pos),
+                },
+            )?,
+            In::SaveState { path } => match save_state(&pos, &path) {
+                Ok(()) => send(&mut output, &Out::Saved)?,
+                Err((code, reason)) => send(&mut output, &ko(code, reason))?,
+            },
+            In::LoadState { path } => match load_state(&path) {
+                Ok(loaded) => {
+                    pos = loaded;
+                    pos_set = true;
+                    send(&mut output, &Out::Loaded)?;
+                }
+                Err((code, reason)) => send(&mut output, &ko(code, reason))?,
+            },
+            In::ApplyMove {
+                col,
+                rotation,
+                swap,
+            } => {
+                let piece = pos.played_piece(swap);
+                let result = match pos.landing_row(col, rotation, swap) {
+                    Some(row) => pos.try_apply_move(piece, col, row, rotation, swap, true),
+                    None => MoveResult::Illegal,
+                };
+
+                match result {
+                    MoveResult::Ok(new_pos) => {
+                        pos = new_pos;
+                        last_board = pos.board;
+                        cleared_rows.clear();
+                        send(&mut output, &pos_message(&pos))?;
+                    }
+                    MoveResult::GameOver(new_pos) => {
+                        pos = new_pos;
+                        send(&mut output, &ko(ErrorCode::GameOver, "Game over"))?;
+                    }
+                    MoveResult::Illegal => send(
+                        &mut output,
+                        &ko(
+                            ErrorCode::IllegalMove,
+                            format!("Illegal move {:?} at column {}", piece, col),
+                        ),
+                    )?,
+                }
+            }
+            In::PlayGame {
+                max_pieces: Some(max_pieces),
+                ..
+            } if max_pieces > MAX_PIECES => send(
+                &mut output,
+                &ko(
+                    ErrorCode::InvalidArgument,
+                    format!(
+                        "max_pieces is {}, games are limited to {} pieces",
+                        max_pieces, MAX_PIECES
+                    ),
+                ),
+            )?,
+            In::PlayGame {
+                record,
+                report_every,
+                max_repeats,
+                max_pieces,
+            } => {
+                let max_pieces = max_pieces.unwrap_or(MAX_PIECES);
+                let mut pieces = 0;
+                let mut report_error = None;
+                let mut cycles = CycleDetector::new(max_repeats);
+                let mut repeated = false;
+                let choose = |pos: &Position| {
+                    if cycles.repeated(pos) {
+                        repeated = true;
+                        return None;
+                    }
+                    if pieces == max_pieces {
+                        return None;
+                    }
+
+                    if report_every > 0 && pieces > 0 && pieces % report_every == 0 {
+                        let progress = Out::Progress {
+                            pieces,
+                            lines: pos.total_lines,
+                            score: pos.score,
+                        };
+                        if let Err(err) = send(&mut output, &progress) {
+                            // Nobody is listening anymore, stop the game
+                            report_error = Some(err);
+                            return None;
                         }
                     }
-                    send(&Out::GameResult { score: pos.score })?;
-                    pos = Position::default();
+                    pieces += 1;
+
+                    search
+                        .run(&mut nets, pos)
+                        .map(|best_move| best_move.placement())
+                };
+                let record = if record {
+                    let (end, record) = record_game(pos, choose);
+                    pos = end;
+                    Some(Box::new(record))
+                } else {
+                    pos = play_game(pos, choose);
+                    None
+                };
+                if let Some(err) = report_error {
+                    return Err(err);
+                }
+                let reason = if repeated {
+                    EndReason::Repetition
+                } else if pos.is_game_over() {
+                    EndReason::GameOver
+                } else if pieces == max_pieces {
+                    EndReason::PieceLimit
+                } else {
+                    EndReason::Stopped
                 };
+                debug!(
+                    "Game completed after {} pieces: {} lines, score {}, {:?}",
+                    pieces, pos.total_lines, pos.score, reason
+                );
+
+                send(
+                    &mut output,
+                    &Out::GameResult {
+                        score: pos.score,
+                        record,
+                        reason: Some(reason),
+                    },
+                )?;
+                pos = Position::default();
+            }
+            In::Reset { seed } => {
+                pos = seed.map_or_else(Position::default, Position::with_seed);
+                pos_set = true;
+                search.clear_tt();
+                send(&mut output, &Out::Ok)?;
+            }
+            In::Hello { version } => {
+                if version == PROTOCOL_VERSION {
+                    send(
+                        &mut output,
+                        &Out::Hello {
+                            version: PROTOCOL_VERSION,
+                            features: PROTOCOL_FEATURES.iter().map(|&f| f.to_owned()).collect(),
+                        },
+                    )?
+                } else {
+                    send(
+                        &mut output,
+                        &ko(
+                            ErrorCode::Version,
+                            format!(
+                                "Protocol version {} is not supported, expected {}",
+                                version, PROTOCOL_VERSION
+                            ),
+                        ),
+                    )?
+                }
+            }
+            In::Quit => break,
+            In::Ready => {
+                if nets.nets.is_empty() {
+                    send(&mut output, &ko(ErrorCode::NoNetwork, "No network loaded"))?
+                } else {
+                    send(&mut output, &Out::Ok)?
+                }
             }
-            In::Ready => match net {
-                Some(_) => send(&Out::Ok)?,
-                None => send(&Out::Ko)?,
-            },
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::search::MOVE_SPACE;
+
+    const LOAD_HOLES: &str = r#"{"type":"Load","input_nodes":[-1,-2,-3],"output_nodes":[0],"node_evals":[[0,"tanh","sum",0.0,1.0,[[-1,-1.0]]]]}"#;
+
+    fn run_script(lines: &[&str]) -> Vec<serde_json::Value> {
+        let mut output = Vec::new();
+        run(lines.join("\n").as_bytes(), &mut output).unwrap();
+
+        String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_pos_message() {
+        let tpn = "/////////////////////4OO4/ T I 0";
+
+        let msg = format!(r#"{{"type":"Pos","tpn":"{}"}}"#, tpn);
+        let Ok(In::Pos { tpn, bag, pocket }) = serde_json::from_str(&msg) else {
+            panic!()
+        };
+        let pos = parse_pos(&tpn, bag, pocket).unwrap();
+        assert_eq!(pos.bag.len(), 7);
+        assert_eq!(pos.pocket, None);
+
+        let msg = format!(r#"{{"type":"Pos","tpn":"{}","bag":[3,7],"pocket":5}}"#, tpn);
+        let Ok(In::Pos { tpn, bag, pocket }) = serde_json::from_str(&msg) else {
+            panic!()
+        };
+        let pos = parse_pos(&tpn, bag, pocket).unwrap();
+        assert_eq!(pos.bag.as_slice(), &[Color::J, Color::Z]);
+        assert_eq!(pos.pocket, Some(Color::S));
+        assert_eq!(pos.to_string(), "/////////////////////4OO4/ T I 0");
+
+        assert!(parse_pos(&tpn, Some(vec![8]), None).is_none());
+        assert!(parse_pos(&tpn, None, Some(0)).is_none());
+    }
+
+    #[test]
+    fn test_peek_bag_pocket() {
+        let out = run_script(&[
+            r#"{"type":"Pos","tpn":"/////////////////////4OO4/ T I 0","bag":[3,7],"pocket":5}"#,
+            r#"{"type":"Peek"}"#,
+            r#"{"type":"Pos","tpn":"/////////////////////4OO4/ T I 0","bag":[],"pocket":null}"#,
+            r#"{"type":"Peek"}"#,
+        ]);
+
+        assert_eq!(out[0]["bag"], serde_json::json!([3, 7]));
+        assert_eq!(out[0]["pocket"], 5);
+        assert_eq!(out[1]["bag"], serde_json::json!([]));
+        assert!(out[1]["pocket"].is_null());
+
+        // Sending a peeked position back gives the same position
+        let msg = serde_json::to_string(&out[0]).unwrap();
+        let out_again = run_script(&[&msg, r#"{"type":"Peek"}"#]);
+        assert_eq!(out_again[0], out[0]);
+    }
+
+    #[test]
+    fn test_play_game_message() {
+        // Playing a whole game with the full search takes too long for a test,
+        // the recording itself is tested in `selfplay`
+        let Ok(In::PlayGame {
+            record,
+            report_every,
+            max_repeats,
+            max_pieces,
+        }) = serde_json::from_str(r#"{"type":"PlayGame"}"#)
+        else {
+            panic!()
+        };
+        assert!(!record);
+        assert_eq!(report_every, 0);
+        assert_eq!(max_repeats, 0);
+        assert_eq!(max_pieces, None);
+
+        let msg = r#"{"type":"PlayGame","max_repeats":3}"#;
+        let Ok(In::PlayGame { max_repeats, .. }) = serde_json::from_str(msg) else {
+            panic!()
+        };
+        assert_eq!(max_repeats, 3);
+
+        let msg = r#"{"type":"PlayGame","record":true}"#;
+        let Ok(In::PlayGame { record, .. }) = serde_json::from_str(msg) else {
+            panic!()
+        };
+        assert!(record);
+    }
+
+    /// Keeps the messages of every record logged by the tests.
+    struct CaptureLogger(Mutex<Vec<String>>);
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: CaptureLogger = CaptureLogger(Mutex::new(Vec::new()));
+
+    #[test]
+    fn test_go_logs_move() {
+        // The logger is global, records from other tests may come in too
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(log::LevelFilter::Debug);
+
+        let out = run_script(&[
+            LOAD_HOLES,
+            r#"{"type":"Pos","tpn":"4/4/4/4/1I2/II1I/ Z S 0"}"#,
+            r#"{"type":"Go"}"#,
+        ]);
+        assert_eq!(out[1]["type"], "Move");
+
+        let column = format!("at column {},", out[1]["col"]);
+        assert!(LOGGER
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|msg| msg.starts_with("Move chosen: ") && msg.contains(&column)));
+    }
+
+    #[test]
+    fn test_go_swap() {
+        // Any S placement on a flat board leaves a hole, the O in the queue does not
+        let out = run_script(&[
+            LOAD_HOLES,
+            r#"{"type":"Pos","tpn":"////////////////////// S O 0"}"#,
+            r#"{"type":"Go"}"#,
+            r#"{"type":"Peek"}"#,
+        ]);
+
+        assert_eq!(out[0]["type"], "Ok");
+        assert_eq!(out[1]["type"], "Move");
+        assert_eq!(out[1]["action_list"][0], "Hold");
+        assert!(out[1]["score"].is_f64());
+        assert!(out[2]["tpn"].as_str().unwrap().contains("OO"));
+    }
+
+    #[test]
+    fn test_features() {
+        let out = run_script(&[
+            r#"{"type":"Pos","tpn":"////////////////////1I8/I9/ T O 0"}"#,
+            r#"{"type":"Features"}"#,
+        ]);
+
+        assert_eq!(out[0]["type"], "Features");
+        assert_eq!(out[0]["holes"], 1.);
+        assert_eq!(out[0]["bumpiness"], 3.);
+        assert_eq!(out[0]["aggregate_height"], 3.);
+        assert_eq!(out[0]["completed_lines"], 0.);
+    }
+
+    #[test]
+    fn test_apply_move() {
+        let out = run_script(&[
+            r#"{"type":"Pos","tpn":"////////////////////I9/IIIIII4/ I O 0"}"#,
+            r#"{"type":"ApplyMove","col":6,"rotation":0,"swap":false}"#,
+            r#"{"type":"ApplyMove","col":8,"rotation":0,"swap":false}"#,
+            r#"{"type":"ApplyMove","col":10,"rotation":0,"swap":false}"#,
+            r#"{"type":"Peek"}"#,
+        ]);
+
+        assert_eq!(out[0]["type"], "Pos");
+        assert!(out[0]["tpn"].as_str().unwrap().contains("/I9/ O "));
+        assert!(out[0]["tpn"].as_str().unwrap().ends_with(" 40"));
+        assert_eq!(out[1]["type"], "Pos");
+        assert_eq!(out[2]["type"], "Ko");
+        assert!(out[3]["tpn"].as_str().unwrap().contains("/I7OO/"));
+        assert!(out[3]["tpn"].as_str().unwrap().ends_with(" 40"));
+    }
+
+    #[test]
+    fn test_go_move() {
+        let out = run_script(&[
+            LOAD_HOLES,
+            r#"{"type":"Pos","tpn":"////////////////////// S O 0"}"#,
+            r#"{"type":"Go"}"#,
+        ]);
+
+        // The O is held in, it has a single rotation and is two columns wide
+        assert_eq!(out[1]["type"], "Move");
+        assert_eq!(out[1]["swap"], true);
+        assert_eq!(out[1]["rotation"], 0);
+        assert!(out[1]["col"].as_u64().unwrap() <= 8);
+        assert_eq!(out[1]["action_list"][0], "Hold");
+    }
+
+    #[test]
+    fn test_save_load_state() {
+        let dir = std::env::temp_dir().join(format!("neat-tetris-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state.json");
+        let path = path.to_str().unwrap();
+
+        let out = run_script(&[
+            r#"{"type":"Pos","tpn":"/////////////////////4OO4/ T I 0","bag":[3,7],"pocket":5}"#,
+            &format!(r#"{{"type":"SaveState","path":{:?}}}"#, path),
+            r#"{"type":"Pos","tpn":"////////////////////// O I 0"}"#,
+            &format!(r#"{{"type":"LoadState","path":{:?}}}"#, path),
+            r#"{"type":"Peek"}"#,
+            r#"{"type":"LoadState","path":"/nonexistent/state.json"}"#,
+            r#"{"type":"SaveState","path":"/nonexistent/state.json"}"#,
+            r#"{"type":"Ready"}"#,
+        ]);
+
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(out[0]["type"], "Saved");
+        assert_eq!(out[1]["type"], "Loaded");
+        assert_eq!(out[2]["tpn"], "/////////////////////4OO4/ T I 0");
+        for msg in &out[3..] {
+            assert_eq!(msg["type"], "Ko");
+            assert!(!msg["reason"].as_str().unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_malformed_line() {
+        let out = run_script(&[
+            "garbage",
+            r#"{"type":"Teleport"}"#,
+            LOAD_HOLES,
+            r#"{"type":"Ready"}"#,
+        ]);
+
+        assert_eq!(out.len(), 4);
+        assert_eq!(out[0]["type"], "Ko");
+        assert_eq!(out[1]["type"], "Ko");
+        assert!(out[1]["reason"].as_str().unwrap().contains("Teleport"));
+        assert_eq!(out[2]["type"], "Ok");
+        assert_eq!(out[3]["type"], "Ok");
+    }
+
+    #[test]
+    fn test_quit() {
+        let out = run_script(&[
+            r#"{"type":"Ready"}"#,
+            r#"{"type":"Quit"}"#,
+            r#"{"type":"Ready"}"#,
+        ]);
+
+        assert_eq!(out.len(), 1);
+
+        // End of input stops the loop as well
+        assert!(run_script(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_go_without_network() {
+        let out = run_script(&[
+            r#"{"type":"Pos","tpn":"4/4/4/4/4/4/ T O 0"}"#,
+            r#"{"type":"Go"}"#,
+            r#"{"type":"Ready"}"#,
+        ]);
+
+        assert_eq!(out[0]["type"], "Move");
+        assert_eq!(out[1]["type"], "Ko");
+    }
+
+    #[test]
+    fn test_go_before_pos() {
+        let out = run_script(&[
+            LOAD_HOLES,
+            r#"{"type":"Go"}"#,
+            r#"{"type":"Reset"}"#,
+            r#"{"type":"Go"}"#,
+        ]);
+
+        assert_eq!(out[1]["type"], "Ko");
+        assert_eq!(out[1]["error_code"], "NoPosition");
+        assert!(out[1]["reason"].as_str().unwrap().contains("pieces"));
+        assert_eq!(out[3]["type"], "Move");
+    }
+
+    #[test]
+    fn test_load_invalid_network() {
+        let out = run_script(&[
+            r#"{"type":"Load","input_nodes":[-1],"output_nodes":[0],"node_evals":[[0,"tanh","sum",0.0,1.0,[[3,1.0]]]]}"#,
+            r#"{"type":"Ready"}"#,
+            r#"{"type":"Load","input_nodes":[-1],"output_nodes":[],"node_evals":[[0,"tanh","sum",0.0,1.0,[[-1,1.0]]]]}"#,
+            r#"{"type":"Ready"}"#,
+        ]);
+
+        assert_eq!(out[0]["type"], "Ko");
+        assert!(out[0]["reason"]
+            .as_str()
+            .unwrap()
+            .contains("missing node 3"));
+        assert_eq!(out[1]["type"], "Ko");
+
+        assert_eq!(out[2]["error_code"], "InvalidNetwork");
+        assert!(out[2]["reason"].as_str().unwrap().contains("no output"));
+        assert_eq!(out[3]["type"], "Ko");
+    }
+
+    #[test]
+    fn test_add_network() {
+        let add_holes = LOAD_HOLES.replace("Load", "AddNetwork");
+        let nodes: Vec<_> = (0..MOVE_SPACE).collect();
+        let policy = format!(
+            r#"{{"type":"AddNetwork","input_nodes":[-1,-2,-3],"output_nodes":{:?},"node_evals":{}}}"#,
+            nodes,
+            serde_json::to_string(
+                &nodes
+                    .iter()
+                    .map(|&node| (node, "relu", "sum", 0., 1., vec![(-1, 1.)]))
+                    .collect::<Vec<_>>()
+            )
+            .unwrap()
+        );
+        let out = run_script(&[
+            &add_holes,
+            &add_holes,
+            &policy,
+            r#"{"type":"Ready"}"#,
+            r#"{"type":"Pos","tpn":"4/4/4/4/4/4/ T O 0"}"#,
+            r#"{"type":"Go"}"#,
+            r#"{"type":"Load","input_nodes":[-1,-2,-3],"output_nodes":[0,1],"node_evals":[[0,"tanh","sum",0.0,1.0,[[-1,-1.0]]],[1,"tanh","sum",0.0,1.0,[[-2,-1.0]]]]}"#,
+            &add_holes,
+            r#"{"type":"Evaluate"}"#,
+        ]);
+
+        assert_eq!(out[0]["type"], "Ok");
+        assert_eq!(out[1]["type"], "Ok");
+        assert_eq!(out[2]["type"], "Ko");
+        assert_eq!(out[2]["error_code"], "InvalidNetwork");
+        assert_eq!(
+            out[2]["reason"],
+            "Cannot add a MovePolicy network to BoardValue ones"
+        );
+        assert_eq!(out[3]["type"], "Ok");
+        assert_eq!(out[4]["type"], "Move");
+
+        // Members must have as many outputs for the ensemble to combine them
+        assert_eq!(out[5]["type"], "Ok");
+        assert_eq!(out[6]["error_code"], "InvalidNetwork");
+        assert_eq!(
+            out[6]["reason"],
+            "Cannot add a network with 1 outputs to ones with 2"
+        );
+        assert_eq!(out[7]["type"], "Evaluation");
+    }
+
+    #[test]
+    fn test_legal_moves() {
+        // 2 columns for the flat T rotations, 3 for the upright ones, and 3
+        // for the O played by holding
+        let out = run_script(&[
+            r#"{"type":"Pos","tpn":"4/4/4/4/4/4/ T O 0"}"#,
+            r#"{"type":"LegalMoves"}"#,
+        ]);
+
+        assert_eq!(out[0]["type"], "Moves");
+        let moves = out[0]["moves"].as_array().unwrap();
+        assert_eq!(moves.len(), 13);
+        assert_eq!(moves.iter().filter(|mv| mv[2] == true).count(), 3);
+        assert!(moves.contains(&serde_json::json!([1, 1, false])));
+        assert!(!moves.contains(&serde_json::json!([2, 0, false])));
+    }
+
+    #[test]
+    fn test_peek_diff() {
+        let before = "4/4/4/4/1I2/II1I/ T S 0";
+        let after = "4/4/4/4/4/1I2/ O Z 120";
+        let out = run_script(&[
+            &format!(r#"{{"type":"Pos","tpn":"{}"}}"#, before),
+            r#"{"type":"Peek","diff":true}"#,
+            &format!(r#"{{"type":"Pos","tpn":"{}"}}"#, after),
+            r#"{"type":"Peek","diff":true}"#,
+            r#"{"type":"Peek","diff":true}"#,
+        ]);
+
+        // The first diff is relative to an empty board
+        assert_eq!(out[0]["type"], "PosDiff");
+        assert_eq!(out[0]["cells"].as_array().unwrap().len(), 4);
+
+        let mut board = Position::from_str(before).unwrap().board;
+        for cell in out[1]["cells"].as_array().unwrap() {
+            let (x, y, id) = serde_json::from_value::<(usize, usize, usize)>(cell.clone()).unwrap();
+            board[y][x] = Color::piece(id).unwrap_or(Color::Empty);
+        }
+        assert_eq!(board, Position::from_str(after).unwrap().board);
+        assert_eq!(out[1]["current_piece"], 2);
+        assert_eq!(out[1]["next_piece"], 7);
+        assert_eq!(out[1]["score"], 120);
+
+        assert!(out[2]["cells"].as_array().unwrap().is_empty());
+        assert!(out[2]["cleared_rows"].as_array().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_peek_diff_cleared_rows() {
+        let before = "4/4/4/4/OO2/OOI1/ O T 0";
+        let out = run_script(&[
+            LOAD_HOLES,
+            &format!(r#"{{"type":"Pos","tpn":"{}"}}"#, before),
+            r#"{"type":"Peek","diff":true}"#,
+            r#"{"type":"Go"}"#,
+            r#"{"type":"Peek","diff":true}"#,
+            r#"{"type":"Peek"}"#,
+        ]);
+
+        // Holding the O, the T fills both rows with one cell left above them
+        assert_eq!(out[2]["col"], 2);
+        assert_eq!(out[2]["swap"], true);
+        assert_eq!(out[3]["cleared_rows"], serde_json::json!([[4, 5]]));
+        assert_eq!(out[3]["cells"].as_array().unwrap().len(), 1);
+
+        let mut board = Position::from_str(before).unwrap().board;
+        for rows in
+            serde_json::from_value::<Vec<Vec<usize>>>(out[3]["cleared_rows"].clone()).unwrap()
+        {
+            clear_rows(&mut board, &rows);
+        }
+        for cell in out[3]["cells"].as_array().unwrap() {
+            let (x, y, id) = serde_json::from_value::<(usize, usize, usize)>(cell.clone()).unwrap();
+            board[y][x] = Color::piece(id).unwrap_or(Color::Empty);
+        }
+        let after = Position::from_str(out[4]["tpn"].as_str().unwrap()).unwrap();
+        assert_eq!(board, after.board);
+    }
+
+    /// `Load` message of a policy preferring the leftmost columns, which tops
+    /// out quickly without any lookahead.
+    fn load_left_policy() -> String {
+        let nodes: Vec<_> = (0..MOVE_SPACE).collect();
+        format!(
+            r#"{{"type":"Load","input_nodes":[-1,-2,-3],"output_nodes":{:?},"node_evals":{}}}"#,
+            nodes,
+            serde_json::to_string(
+                &nodes
+                    .iter()
+                    .map(|&node| (
+                        node,
+                        "relu",
+                        "sum",
+                        (MOVE_SPACE - node) as f64,
+                        1.,
+                        vec![(-1, 0.)]
+                    ))
+                    .collect::<Vec<_>>()
+            )
+            .unwrap()
+        )
+    }
+
+    #[test]
+    fn test_play_game_progress() {
+        let load = load_left_policy();
+
+        let out = run_script(&[
+            &load,
+            r#"{"type":"Reset"}"#,
+            r#"{"type":"PlayGame","report_every":2}"#,
+        ]);
+
+        assert_eq!(out[1]["type"], "Ok");
+        let (result, progress) = out[2..].split_last().unwrap();
+        assert_eq!(result["type"], "GameResult");
+        assert_eq!(result["reason"], "GameOver");
+        assert!(!progress.is_empty());
+
+        for (i, msg) in progress.iter().enumerate() {
+            assert_eq!(msg["type"], "Progress");
+            assert_eq!(msg["pieces"], 2 * (i + 1));
+        }
+    }
+
+    #[test]
+    fn test_play_game_max_pieces() {
+        // Flat play on a board four cells wide and eight high outlasts 50
+        // pieces with these
+        let dir = std::env::temp_dir().join(format!("neat-tetris-narrow-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("narrow.json");
+        let mut narrow = Position::from_str(&format!("{} T O 0", "4/".repeat(8))).unwrap();
+        narrow.seed = Some(5);
+        fs::write(&path, narrow.to_json()).unwrap();
+        let path = path.to_str().unwrap();
+
+        let load = load_left_policy();
+        let out = run_script(&[
+            &load,
+            r#"{"type":"Reset","seed":7}"#,
+            r#"{"type":"PlayGame","record":true,"max_pieces":3}"#,
+            LOAD_HOLES,
+            &format!(r#"{{"type":"LoadState","path":{:?}}}"#, path),
+            r#"{"type":"PlayGame","record":true,"max_pieces":50}"#,
+            &format!(r#"{{"type":"PlayGame","max_pieces":{}}}"#, MAX_PIECES + 1),
+        ]);
+
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(out[2]["reason"], "PieceLimit");
+        assert_eq!(out[2]["record"]["moves"].as_array().unwrap().len(), 3);
+
+        assert_eq!(out[5]["reason"], "PieceLimit");
+        assert_eq!(out[5]["record"]["moves"].as_array().unwrap().len(), 50);
+
+        assert_eq!(out[6]["type"], "Ko");
+        assert_eq!(out[6]["error_code"], "InvalidArgument");
+    }
+
+    #[test]
+    fn test_reset() {
+        let out = run_script(&[
+            LOAD_HOLES,
+            r#"{"type":"Pos","tpn":"////////////////////I9/IIIIII4/ I O 0"}"#,
+            r#"{"type":"ApplyMove","col":6,"rotation":0,"swap":false}"#,
+            r#"{"type":"ApplyMove","col":8,"rotation":0,"swap":false}"#,
+            r#"{"type":"Reset"}"#,
+            r#"{"type":"Peek"}"#,
+            r#"{"type":"Ready"}"#,
+            r#"{"type":"Reset","seed":3}"#,
+            r#"{"type":"Peek"}"#,
+            r#"{"type":"Reset","seed":3}"#,
+            r#"{"type":"Peek"}"#,
+        ]);
+
+        assert_eq!(out[3]["type"], "Ok");
+        let pos = Position::from_str(out[4]["tpn"].as_str().unwrap()).unwrap();
+        assert!(pos.board.iter().flatten().all(|&cell| cell == Color::Empty));
+        assert_eq!(pos.score, 0);
+        assert_eq!(out[5]["type"], "Ok");
+
+        // Seeded resets deal the same pieces
+        assert_eq!(out[7]["tpn"], out[9]["tpn"]);
+        assert_eq!(out[7]["tpn"], Position::with_seed(3).to_string().as_str());
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let out = run_script(&[
+            r#"{"type":"Evaluate"}"#,
+            r#"{"type":"Load","input_nodes":[-1,-2,-3],"output_nodes":[0,1],"node_evals":[[0,"relu","sum",0.0,1.0,[[-1,1.0]]],[1,"relu","sum",0.0,1.0,[[-3,1.0]]]]}"#,
+            r#"{"type":"Pos","tpn":"4/4/4/4/1I2/II1I/ T S 0"}"#,
+            r#"{"type":"Evaluate"}"#,
+        ]);
+
+        assert_eq!(out[0]["type"], "Ko");
+        assert_eq!(out[2]["type"], "Evaluation");
+        // No hole, and heights 1, 2, 0 and 1
+        assert_eq!(out[2]["outputs"], serde_json::json!([0.0, 4.0]));
+    }
+
+    #[test]
+    fn test_load_file() {
+        let dir = std::env::temp_dir().join(format!("neat-tetris-genome-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let genome = dir.join("genome.json");
+        let genome = genome.to_str().unwrap();
+        fs::write(
+            genome,
+            r#"{"input_nodes":[-1,-2,-3],"output_nodes":[0],"node_evals":[[0,"tanh","sum",0.0,1.0,[[-1,-1.0]]]]}"#,
+        )
+        .unwrap();
+        let malformed = dir.join("malformed.json");
+        let malformed = malformed.to_str().unwrap();
+        fs::write(malformed, "{").unwrap();
+
+        let out = run_script(&[
+            &format!(r#"{{"type":"LoadFile","path":{:?}}}"#, malformed),
+            r#"{"type":"LoadFile","path":"/nonexistent/genome.json"}"#,
+            r#"{"type":"Ready"}"#,
+            &format!(r#"{{"type":"LoadFile","path":{:?}}}"#, genome),
+            r#"{"type":"Ready"}"#,
+        ]);
+
+        fs::remove_dir_all(&dir).unwrap();
+
+        for msg in &out[..3] {
+            assert_eq!(msg["type"], "Ko");
+        }
+        assert_eq!(out[3]["type"], "Ok");
+        assert_eq!(out[4]["type"], "Ok");
+    }
+
+    #[test]
+    fn test_load_clears_tt() {
+        let pos = r#"{"type":"Pos","tpn":"4/4/4/4/1I2/II1I/ T S 0"}"#;
+        let out = run_script(&[pos, r#"{"type":"Go"}"#, LOAD_HOLES, pos, r#"{"type":"Go"}"#]);
+        let fresh = run_script(&[LOAD_HOLES, pos, r#"{"type":"Go"}"#]);
+
+        // Nothing is left from the linear evaluation
+        assert_eq!(out[0]["type"], "Move");
+        assert_ne!(out[0]["score"], fresh[1]["score"]);
+        assert_eq!(out[2], fresh[1]);
+    }
+
+    #[test]
+    fn test_load_input_count() {
+        let load = |inputs: i64| {
+            format!(
+                r#"{{"type":"Load","input_nodes":{:?},"output_nodes":[0],"node_evals":[[0,"tanh","sum",0.0,1.0,[[-1,-1.0]]]]}}"#,
+                (1..=inputs).map(|i| -i).collect::<Vec<_>>()
+            )
+        };
+        let out = run_script(&[
+            &load(31),
+            r#"{"type":"Ready"}"#,
+            &load(30),
+            &load(1),
+            r#"{"type":"Ready"}"#,
+        ]);
+
+        assert_eq!(out[0]["error_code"], "InvalidNetwork");
+        assert_eq!(out[0]["reason"], "Expected at most 30 inputs, got 31");
+        assert_eq!(out[1]["type"], "Ko");
+        assert_eq!(out[2]["type"], "Ok");
+        assert_eq!(out[3]["type"], "Ok");
+        assert_eq!(out[4]["type"], "Ok");
+    }
+
+    #[test]
+    fn test_error_codes() {
+        let out = run_script(&[
+            r#"{"type":"Unknown"}"#,
+            r#"{"type":"Pos","tpn":"not a position"}"#,
+            r#"{"type":"Ready"}"#,
+            r#"{"type":"Load","input_nodes":[-1],"output_nodes":[0],"node_evals":[]}"#,
+            r#"{"type":"LoadState","path":"/nonexistent/state.json"}"#,
+            r#"{"type":"Pos","tpn":"////////////////////// O I 0"}"#,
+            r#"{"type":"ApplyMove","col":9,"rotation":0,"swap":false}"#,
+        ]);
+
+        let codes: Vec<_> = out
+            .iter()
+            .map(|msg| msg["error_code"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            codes,
+            [
+                "InvalidMessage",
+                "InvalidPosition",
+                "NoNetwork",
+                "InvalidNetwork",
+                "File",
+                "IllegalMove"
+            ]
+        );
+        assert!(out.iter().all(|msg| msg["type"] == "Ko"));
+        assert!(out[1]["reason"]
+            .as_str()
+            .unwrap()
+            .contains("not a position"));
+    }
+
+    #[test]
+    fn test_hello() {
+        let out = run_script(&[
+            &format!(r#"{{"type":"Hello","version":{}}}"#, PROTOCOL_VERSION),
+            r#"{"type":"Hello","version":0}"#,
+            &format!(r#"{{"type":"Hello","version":{}}}"#, PROTOCOL_VERSION + 1),
+        ]);
+
+        assert_eq!(out[0]["type"], "Hello");
+        assert_eq!(out[0]["version"], PROTOCOL_VERSION);
+        let features = out[0]["features"].as_array().unwrap();
+        assert!(!features.is_empty());
+        assert!(features.contains(&"diff".into()));
+
+        for msg in &out[1..] {
+            assert_eq!(msg["error_code"], "Version");
+        }
+    }
+
+    #[test]
+    fn test_pos_validation() {
+        let out = run_script(&[
+            r#"{"type":"Pos","tpn":"4/4/1I2/II1I/ T S 0"}"#,
+            r#"{"type":"Pos","tpn":"4/4/1?2/II1I/ T S 0"}"#,
+            r#"{"type":"Pos","tpn":"4/4/1I3/II1I/ T S 0"}"#,
+            r#"{"type":"Peek"}"#,
+            r#"{"type":"Pos","tpn":"3/3/3/3/ T S 0"}"#,
+            r#"{"type":"Go"}"#,
+            r#"{"type":"Pos","tpn":"/ T O 0"}"#,
+            r#"{"type":"LegalMoves"}"#,
+        ]);
+
+        assert_eq!(out.len(), 7);
+        assert_eq!(out[0]["error_code"], "InvalidPosition");
+        assert!(out[0]["reason"].as_str().unwrap().contains("(1, 2)"));
+        // Rows of different widths do not even parse
+        assert_eq!(out[1]["error_code"], "InvalidPosition");
+        assert_eq!(out[2]["tpn"], "4/4/1I2/II1I/ T S 0");
+
+        // Boards smaller than a piece are refused, the position set before
+        // them being kept
+        assert_eq!(out[3]["error_code"], "InvalidPosition");
+        assert_eq!(out[4]["type"], "Move");
+        assert_eq!(out[5]["error_code"], "InvalidPosition");
+        assert_eq!(out[6]["type"], "Moves");
+    }
+}
@@ -0,0 +1,86 @@
+use crate::pos::{MoveResult, Placement, Position};
+
+/// Positions of a game from its start, to step back through them without
+/// keeping clones by hand. There is always a current position.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct History {
+    positions: Vec<Position>,
+}
+
+impl History {
+    pub fn new(start: Position) -> History {
+        History {
+            positions: vec![start],
+        }
+    }
+
+    pub fn current(&self) -> &Position {
+        self.positions.last().expect("History is never empty")
+    }
+
+    /// Number of positions that can be undone.
+    pub fn undo_count(&self) -> usize {
+        self.positions.len() - 1
+    }
+
+    /// Makes `pos` the current position.
+    pub fn push(&mut self, pos: Position) {
+        self.positions.push(pos);
+    }
+
+    /// Goes back to the previous position and returns the one undone, or
+    /// `None` at the start of the history.
+    pub fn undo(&mut self) -> Option<Position> {
+        if self.positions.len() > 1 {
+            self.positions.pop()
+        } else {
+            None
+        }
+    }
+
+    /// Plays `mv` from the current position, drawing the next pieces, and
+    /// returns the new current position, or `None` if the move is illegal.
+    /// Placements that top out are kept like the others.
+    pub fn apply(&mut self, (p, x, y, rot, swap): Placement) -> Option<&Position> {
+        match self.current().try_apply_move(p, x, y, rot, swap, true) {
+            MoveResult::Ok(pos) | MoveResult::GameOver(pos) => {
+                self.push(pos);
+                Some(self.current())
+            }
+            MoveResult::Illegal => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_undo() {
+        let mut history = History::new(Position::with_seed(3));
+        assert_eq!(history.undo(), None);
+
+        let mut snapshots = vec![history.current().clone()];
+        for _ in 0..3 {
+            let mv = history.current().legal_moves()[0][0];
+            let pos = history.apply(mv).unwrap().clone();
+            snapshots.push(pos);
+        }
+        assert_eq!(history.undo_count(), 3);
+
+        assert_eq!(history.undo(), Some(snapshots[3].clone()));
+        assert_eq!(history.undo(), Some(snapshots[2].clone()));
+        assert_eq!(history.current(), &snapshots[1]);
+        assert_eq!(history.current().seed, snapshots[1].seed);
+        assert_eq!(history.undo_count(), 1);
+
+        // Playing again from there draws the same pieces
+        let mv = history.current().legal_moves()[0][0];
+        assert_eq!(history.apply(mv), Some(&snapshots[2]));
+
+        let (p, _, y, rot, swap) = mv;
+        assert_eq!(history.apply((p, 42, y, rot, swap)), None);
+        assert_eq!(history.undo_count(), 2);
+    }
+}
@@ -0,0 +1,100 @@
+use arrayvec::ArrayVec;
+
+use crate::pos::Color;
+
+pub const KICK_COUNT: usize = 5;
+
+type Offsets = [[(i32, i32); KICK_COUNT]; 4];
+
+// SRS offset tables, indexed by state (0, R, 2, L) with y pointing up as in
+// the guideline. The kicks for a rotation are the offsets of the source state
+// minus those of the destination one.
+const JLSTZ_OFFSETS: Offsets = [
+    [(0, 0); KICK_COUNT],
+    [(0, 0), (1, 0), (1, -1), (0, 2), (1, 2)],
+    [(0, 0); KICK_COUNT],
+    [(0, 0), (-1, 0), (-1, -1), (0, 2), (-1, 2)],
+];
+
+const I_OFFSETS: Offsets = [
+    [(0, 0), (-1, 0), (2, 0), (-1, 0), (2, 0)],
+    [(-1, 0), (0, 0), (0, 0), (0, 1), (0, -2)],
+    [(-1, 1), (1, 1), (-2, 1), (1, 0), (-2, 0)],
+    [(0, 1), (0, 1), (0, 1), (0, -1), (0, 2)],
+];
+
+/// SRS state of `piece` in the given rotation.
+///
+/// J, L and T store their flat side up orientation first, which is state 2.
+/// I, S and Z only have two rotations, the first one being state 0 and the
+/// second one state R, so they rotate clockwise to 2 and counterclockwise to 0.
+fn srs_state(piece: Color, rotation: usize) -> usize {
+    match piece {
+        Color::J | Color::L | Color::T => (rotation + 2) % 4,
+        _ => rotation,
+    }
+}
+
+/// Translations to try in order when rotating `piece` from `rotation`, on top
+/// of the basic rotation offset. The first one is always `(0, 0)`, and y
+/// points down as on the board.
+pub fn kicks(piece: Color, rotation: usize, clockwise: bool) -> ArrayVec<(i32, i32), KICK_COUNT> {
+    let offsets = match piece {
+        Color::I => &I_OFFSETS,
+        Color::J | Color::L | Color::S | Color::T | Color::Z => &JLSTZ_OFFSETS,
+        _ => return [(0, 0)].into_iter().collect(),
+    };
+
+    let from = srs_state(piece, rotation);
+    let to = if clockwise {
+        (from + 1) % 4
+    } else {
+        (from + 3) % 4
+    };
+
+    let kick = |i: usize| {
+        (
+            offsets[from][i].0 - offsets[to][i].0,
+            offsets[from][i].1 - offsets[to][i].1,
+        )
+    };
+
+    // The I offsets also hold the pivot translation, which the basic
+    // rotation offset already accounts for
+    let base = kick(0);
+
+    (0..KICK_COUNT)
+        .map(kick)
+        .map(|(x, y)| (x - base.0, base.1 - y))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_guideline_tables() {
+        // T from 0 to R
+        assert_eq!(
+            kicks(Color::T, 2, true).as_slice(),
+            &[(0, 0), (-1, 0), (-1, -1), (0, 2), (-1, 2)]
+        );
+        // T from 0 to L
+        assert_eq!(
+            kicks(Color::T, 2, false).as_slice(),
+            &[(0, 0), (1, 0), (1, -1), (0, 2), (1, 2)]
+        );
+        // I from 0 to R
+        assert_eq!(
+            kicks(Color::I, 0, true).as_slice(),
+            &[(0, 0), (-2, 0), (1, 0), (-2, 1), (1, -2)]
+        );
+        // I from R to 2
+        assert_eq!(
+            kicks(Color::I, 1, true).as_slice(),
+            &[(0, 0), (-1, 0), (2, 0), (-1, -2), (2, 1)]
+        );
+        assert_eq!(kicks(Color::O, 0, true).as_slice(), &[(0, 0)]);
+    }
+}
@@ -0,0 +1,13 @@
+#![allow(clippy::needless_range_loop)]
+
+pub mod bitboard;
+pub mod comm;
+pub mod history;
+pub mod kicks;
+pub mod net;
+pub mod pos;
+pub mod search;
+pub mod selfplay;
+pub mod transposition;
+#[cfg(feature = "wasm")]
+pub mod wasm;
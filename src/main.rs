@@ -1,9 +1,3 @@
-mod comm;
-mod net;
-mod pos;
-mod search;
-mod transposition;
-
 fn main() {
-    comm::start().unwrap();
+    neat_tetris::comm::start().unwrap();
 }
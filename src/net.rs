@@ -1,21 +1,134 @@
-use rustc_hash::FxHashMap;
+use rustc_hash::{FxHashMap, FxHashSet};
+use serde::Deserialize;
+use std::{
+    error::Error,
+    fmt::{self, Write},
+};
 
+/// `(node, activation, aggregation, bias, response, links)`, where each link
+/// is `(input node, weight)`
+pub type NodeEval = (i64, Activation, Aggregation, f64, f64, Vec<(i64, f64)>);
+
+/// Activation functions, named as in neat-python.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Activation {
+    Sigmoid,
+    Tanh,
+    Relu,
+    Sin,
+}
+
+/// Aggregation functions, named as in neat-python.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Aggregation {
+    Sum,
+    Product,
+    Max,
+    Min,
+}
+
+pub fn activate(kind: Activation, x: f64) -> f64 {
+    match kind {
+        Activation::Sigmoid => 1. / (1. + (-x).exp()),
+        Activation::Tanh => x.tanh(),
+        Activation::Relu => x.max(0.),
+        Activation::Sin => x.sin(),
+    }
+}
+
+/// Aggregates the weighted inputs of a node, which are 0 for a node without
+/// any unless multiplied.
+pub fn aggregate<I: Iterator<Item = f64>>(kind: Aggregation, inputs: I) -> f64 {
+    match kind {
+        Aggregation::Sum => inputs.sum(),
+        Aggregation::Product => inputs.product(),
+        Aggregation::Max => inputs.reduce(f64::max).unwrap_or(0.),
+        Aggregation::Min => inputs.reduce(f64::min).unwrap_or(0.),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NetworkError {
+    /// A link comes from a node that is neither an input nor evaluated
+    DanglingLink { node: i64, input: i64 },
+    /// A link comes from a node evaluated at the same time or later
+    Cycle { node: i64, input: i64 },
+    /// An output node is neither an input nor evaluated
+    UnreachableOutput(i64),
+    /// The network has no output node, so nothing to read a score from
+    NoOutputs,
+}
+
+impl fmt::Display for NetworkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetworkError::DanglingLink { node, input } => {
+                write!(f, "Node {} has a link from missing node {}", node, input)
+            }
+            NetworkError::Cycle { node, input } => write!(
+                f,
+                "Node {} has a link from node {}, which is not evaluated before it",
+                node, input
+            ),
+            NetworkError::UnreachableOutput(node) => {
+                write!(f, "Output node {} is never evaluated", node)
+            }
+            NetworkError::NoOutputs => write!(f, "The network has no output node"),
+        }
+    }
+}
+
+impl Error for NetworkError {}
+
+/// Network description as sent by the `Load` message, with the same fields
+/// as neat-python's `FeedForwardNetwork`.
+#[derive(Debug, Deserialize)]
+pub struct NetworkSpec {
+    pub input_nodes: Vec<i64>,
+    pub output_nodes: Vec<i64>,
+    pub node_evals: Vec<NodeSpec>,
+    /// Whether the network takes the features scaled to [0, 1], see
+    /// `Position::scaled_feature_vector`. Older genomes take them raw.
+    #[serde(default)]
+    pub scaled_inputs: bool,
+}
+
+/// Named version of `NodeEval`. It can also be deserialized from the
+/// positional `NodeEval` layout.
+#[derive(Debug, Deserialize)]
+pub struct NodeSpec {
+    pub node: i64,
+    pub activation: Activation,
+    pub aggregation: Aggregation,
+    pub bias: f64,
+    pub response: f64,
+    pub links: Vec<Link>,
+}
+
+/// Incoming connection of a node. It can also be deserialized from a
+/// positional `[from, weight]` pair.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct Link {
+    pub from: i64,
+    pub weight: f64,
+}
+
+#[derive(Clone)]
 pub struct FeedForwardNetwork {
     input_nodes: Vec<i64>,
     output_nodes: Vec<i64>,
-    node_evals: Vec<(i64, f64, f64, Vec<(i64, f64)>)>,
+    node_evals: Vec<NodeEval>,
     values: FxHashMap<i64, f64>,
+    scaled_inputs: bool,
 }
 
 impl FeedForwardNetwork {
-    pub fn new(
-        inputs: Vec<i64>,
-        outputs: Vec<i64>,
-        node_evals: Vec<(i64, f64, f64, Vec<(i64, f64)>)>,
-    ) -> Self {
+    pub fn new(inputs: Vec<i64>, outputs: Vec<i64>, node_evals: Vec<NodeEval>) -> Self {
         let mut values = FxHashMap::default();
         for key in inputs.iter().chain(outputs.iter()) {
-            values.insert(*key, 0.0 as f64);
+            values.insert(*key, 0.0);
         }
 
         FeedForwardNetwork {
@@ -23,10 +136,226 @@ impl FeedForwardNetwork {
             output_nodes: outputs,
             node_evals,
             values,
+            scaled_inputs: false,
         }
     }
 
+    pub fn from_spec(spec: NetworkSpec) -> Self {
+        let node_evals = spec
+            .node_evals
+            .into_iter()
+            .map(|node| {
+                (
+                    node.node,
+                    node.activation,
+                    node.aggregation,
+                    node.bias,
+                    node.response,
+                    node.links
+                        .into_iter()
+                        .map(|link| (link.from, link.weight))
+                        .collect(),
+                )
+            })
+            .collect();
+
+        FeedForwardNetwork {
+            scaled_inputs: spec.scaled_inputs,
+            ..FeedForwardNetwork::new(spec.input_nodes, spec.output_nodes, node_evals)
+        }
+    }
+
+    pub fn scaled_inputs(&self) -> bool {
+        self.scaled_inputs
+    }
+
+    pub fn set_scaled_inputs(&mut self, scaled_inputs: bool) {
+        self.scaled_inputs = scaled_inputs;
+    }
+
+    pub fn from_json(s: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(s).map(FeedForwardNetwork::from_spec)
+    }
+
+    /// Checks that the nodes are evaluated in a feed-forward order, each link
+    /// coming from an input or an already evaluated node, and that there
+    /// are outputs which all get a value.
+    pub fn validate(&self) -> Result<(), NetworkError> {
+        if self.output_nodes.is_empty() {
+            return Err(NetworkError::NoOutputs);
+        }
+
+        let all_nodes: FxHashSet<i64> = self
+            .input_nodes
+            .iter()
+            .copied()
+            .chain(self.node_evals.iter().map(|eval| eval.0))
+            .collect();
+        let mut evaluated: FxHashSet<i64> = self.input_nodes.iter().copied().collect();
+
+        for (node, _, _, _, _, links) in &self.node_evals {
+            for &(input, _) in links {
+                if !all_nodes.contains(&input) {
+                    return Err(NetworkError::DanglingLink { node: *node, input });
+                }
+
+                if !evaluated.contains(&input) {
+                    return Err(NetworkError::Cycle { node: *node, input });
+                }
+            }
+
+            evaluated.insert(*node);
+        }
+
+        match self
+            .output_nodes
+            .iter()
+            .find(|node| !evaluated.contains(node))
+        {
+            Some(&node) => Err(NetworkError::UnreachableOutput(node)),
+            None => Ok(()),
+        }
+    }
+
+    pub fn num_inputs(&self) -> usize {
+        self.input_nodes.len()
+    }
+
+    pub fn num_outputs(&self) -> usize {
+        self.output_nodes.len()
+    }
+
+    /// Graphviz description of the network, to be rendered with `dot`. Inputs
+    /// are boxes, outputs double circles, and each link is an edge labeled
+    /// with its weight.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph network {\n    rankdir=LR;\n");
+
+        for node in &self.input_nodes {
+            writeln!(dot, "    \"{}\" [shape=box];", node).unwrap();
+        }
+
+        for (node, activation, aggregation, bias, _, _) in &self.node_evals {
+            let shape = if self.output_nodes.contains(node) {
+                "doublecircle"
+            } else {
+                "circle"
+            };
+            writeln!(
+                dot,
+                "    \"{}\" [shape={}, label=\"{}\\n{:?} {:?}\\nbias {}\"];",
+                node, shape, node, activation, aggregation, bias
+            )
+            .unwrap();
+        }
+
+        for (node, _, _, _, _, links) in &self.node_evals {
+            for (from, weight) in links {
+                writeln!(
+                    dot,
+                    "    \"{}\" -> \"{}\" [label=\"{}\"];",
+                    from, node, weight
+                )
+                .unwrap();
+            }
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Forgets the node values of the previous evaluations, so that links
+    /// read before their node is evaluated see 0 as on a new network.
+    pub fn reset(&mut self) {
+        self.values.clear();
+        for key in self.input_nodes.iter().chain(self.output_nodes.iter()) {
+            self.values.insert(*key, 0.0);
+        }
+    }
+
+    /// Evaluates the network from a clean state, see `reset`. Panics if there
+    /// are not `num_inputs` inputs.
     pub fn activate(&mut self, inputs: Vec<f64>) -> Vec<f64> {
+        self.reset();
+
+        let mut values = std::mem::take(&mut self.values);
+        let outputs = self.run(&mut values, &inputs);
+        self.values = values;
+
+        outputs
+    }
+
+    /// Same as calling `activate` on each input. Node ids are resolved once,
+    /// the node values then live in a single buffer cleared for each input.
+    /// Panics if an input does not have `num_inputs` values.
+    pub fn evaluate_batch(&self, inputs: &[Vec<f64>]) -> Vec<Vec<f64>> {
+        let mut indices = FxHashMap::default();
+        let mut values = Vec::new();
+        let mut index = |node: i64, values: &mut Vec<f64>| {
+            *indices.entry(node).or_insert_with(|| {
+                values.push(0.);
+                values.len() - 1
+            })
+        };
+
+        let input_indices: Vec<usize> = self
+            .input_nodes
+            .iter()
+            .map(|&node| index(node, &mut values))
+            .collect();
+
+        let node_evals: Vec<_> = self
+            .node_evals
+            .iter()
+            .map(|(node, activation, aggregation, bias, response, links)| {
+                let links: Vec<(usize, f64)> = links
+                    .iter()
+                    .map(|&(i, w)| (index(i, &mut values), w))
+                    .collect();
+                (
+                    index(*node, &mut values),
+                    *activation,
+                    *aggregation,
+                    *bias,
+                    *response,
+                    links,
+                )
+            })
+            .collect();
+
+        let output_indices: Vec<usize> = self
+            .output_nodes
+            .iter()
+            .map(|&node| index(node, &mut values))
+            .collect();
+
+        inputs
+            .iter()
+            .map(|inputs| {
+                if input_indices.len() != inputs.len() {
+                    panic!(
+                        "Expected {} inputs, got {}",
+                        input_indices.len(),
+                        inputs.len()
+                    );
+                }
+
+                values.fill(0.);
+                for (&i, &value) in input_indices.iter().zip(inputs) {
+                    values[i] = value;
+                }
+
+                for (node, activation, aggregation, bias, response, links) in &node_evals {
+                    let s = aggregate(*aggregation, links.iter().map(|&(i, w)| values[i] * w));
+                    values[*node] = activate(*activation, bias + response * s);
+                }
+
+                output_indices.iter().map(|&i| values[i]).collect()
+            })
+            .collect()
+    }
+
+    fn run(&self, values: &mut FxHashMap<i64, f64>, inputs: &[f64]) -> Vec<f64> {
         if self.input_nodes.len() != inputs.len() {
             panic!(
                 "Expected {} inputs, got {}",
@@ -35,19 +364,342 @@ impl FeedForwardNetwork {
             );
         }
 
-        for (key, value) in self.input_nodes.iter().zip(inputs) {
-            *self.values.get_mut(key).unwrap() = value;
+        for (key, &value) in self.input_nodes.iter().zip(inputs) {
+            *values.get_mut(key).unwrap() = value;
         }
 
-        for (node, bias, response, links) in &self.node_evals {
-            let node_inputs: Vec<f64> = links.iter().map(|(i, w)| self.values[i] * w).collect();
-            let s: f64 = node_inputs.iter().sum();
-            self.values.insert(*node, (bias + response * s).tanh());
+        for (node, activation, aggregation, bias, response, links) in &self.node_evals {
+            let s = aggregate(*aggregation, links.iter().map(|(i, w)| values[i] * w));
+            values.insert(*node, activate(*activation, bias + response * s));
         }
 
         self.output_nodes
             .iter()
-            .map(|&i| *self.values.get(&i).unwrap())
+            .map(|&i| *values.get(&i).unwrap())
             .collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network() -> FeedForwardNetwork {
+        FeedForwardNetwork::new(
+            vec![-1, -2, -3],
+            vec![0, 1],
+            vec![
+                (
+                    2,
+                    Activation::Tanh,
+                    Aggregation::Sum,
+                    0.1,
+                    1.5,
+                    vec![(-1, 0.7), (-2, -1.3)],
+                ),
+                (
+                    0,
+                    Activation::Sin,
+                    Aggregation::Max,
+                    -0.4,
+                    0.9,
+                    vec![(2, 2.0), (-3, 0.25)],
+                ),
+                (
+                    1,
+                    Activation::Sigmoid,
+                    Aggregation::Product,
+                    0.0,
+                    1.0,
+                    vec![(0, -1.0), (2, 0.5), (-1, 0.3)],
+                ),
+            ],
+        )
+    }
+
+    #[test]
+    fn test_evaluate_batch() {
+        let inputs: Vec<Vec<f64>> = (0..20)
+            .map(|i| {
+                let i = i as f64;
+                vec![i, -0.5 * i, (i * 0.3).sin()]
+            })
+            .collect();
+
+        let batch = network().evaluate_batch(&inputs);
+
+        let mut net = network();
+        for (input, outputs) in inputs.iter().zip(batch) {
+            let single = net.activate(input.clone());
+
+            assert_eq!(single.len(), outputs.len());
+            for (a, b) in single.iter().zip(outputs) {
+                assert!((a - b).abs() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn test_activations() {
+        assert_eq!(activate(Activation::Sigmoid, 0.5), 0.6224593312018546);
+        assert_eq!(activate(Activation::Tanh, 0.5), 0.46211715726000974);
+        assert_eq!(activate(Activation::Relu, 0.5), 0.5);
+        assert_eq!(activate(Activation::Relu, -0.5), 0.);
+        assert_eq!(activate(Activation::Sin, 0.5), 0.479425538604203);
+
+        let net = FeedForwardNetwork::new(
+            vec![-1],
+            vec![0],
+            vec![(
+                0,
+                Activation::Relu,
+                Aggregation::Sum,
+                0.5,
+                2.0,
+                vec![(-1, 1.0)],
+            )],
+        );
+        assert_eq!(
+            net.evaluate_batch(&[vec![1.], vec![-1.]]),
+            vec![vec![2.5], vec![0.]]
+        );
+    }
+
+    fn two_inputs(aggregation: Aggregation) -> FeedForwardNetwork {
+        FeedForwardNetwork::new(
+            vec![-1, -2],
+            vec![0],
+            vec![(
+                0,
+                Activation::Relu,
+                aggregation,
+                0.5,
+                2.0,
+                vec![(-1, 1.5), (-2, -0.5)],
+            )],
+        )
+    }
+
+    #[test]
+    fn test_aggregations() {
+        // Weighted inputs are 3 and -2
+        let inputs = vec![2., 4.];
+
+        assert_eq!(
+            two_inputs(Aggregation::Sum).activate(inputs.clone()),
+            vec![2.5]
+        );
+        assert_eq!(
+            two_inputs(Aggregation::Product).activate(inputs.clone()),
+            vec![0.]
+        );
+        assert_eq!(
+            two_inputs(Aggregation::Max).activate(inputs.clone()),
+            vec![6.5]
+        );
+        assert_eq!(
+            two_inputs(Aggregation::Min).activate(inputs.clone()),
+            vec![0.]
+        );
+
+        let inputs = vec![2., -4.];
+        assert_eq!(
+            two_inputs(Aggregation::Product).activate(inputs.clone()),
+            vec![12.5]
+        );
+        assert_eq!(two_inputs(Aggregation::Min).activate(inputs), vec![4.5]);
+    }
+
+    #[test]
+    fn test_from_json() {
+        let mut net = FeedForwardNetwork::from_json(
+            r#"{
+                "input_nodes": [-1, -2],
+                "output_nodes": [0],
+                "node_evals": [
+                    {
+                        "node": 1,
+                        "activation": "relu",
+                        "aggregation": "sum",
+                        "bias": 0.0,
+                        "response": 1.0,
+                        "links": [[-1, 1.0], [-2, 1.0]]
+                    },
+                    [0, "relu", "product", 0.5, 2.0, [[1, 1.0], [-1, 1.0]]]
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(net.activate(vec![1., 2.]), vec![6.5]);
+        assert!(!net.scaled_inputs());
+
+        let net = FeedForwardNetwork::from_json(
+            r#"{"input_nodes": [-1], "output_nodes": [-1], "node_evals": [], "scaled_inputs": true}"#,
+        )
+        .unwrap();
+        assert!(net.scaled_inputs());
+
+        assert!(FeedForwardNetwork::from_json(r#"{"input_nodes": [-1]}"#).is_err());
+    }
+
+    #[test]
+    fn test_named_node_evals() {
+        let mut positional = FeedForwardNetwork::from_json(
+            r#"{
+                "input_nodes": [-1, -2],
+                "output_nodes": [0],
+                "node_evals": [[0, "tanh", "sum", 0.1, 2.0, [[-1, 0.5], [-2, -1.5]]]]
+            }"#,
+        )
+        .unwrap();
+        let mut named = FeedForwardNetwork::from_json(
+            r#"{
+                "input_nodes": [-1, -2],
+                "output_nodes": [0],
+                "node_evals": [{
+                    "node": 0,
+                    "activation": "tanh",
+                    "aggregation": "sum",
+                    "bias": 0.1,
+                    "response": 2.0,
+                    "links": [{"from": -1, "weight": 0.5}, {"from": -2, "weight": -1.5}]
+                }]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(named.node_evals, positional.node_evals);
+        assert_eq!(
+            named.activate(vec![0.3, 0.2]),
+            positional.activate(vec![0.3, 0.2])
+        );
+
+        // Fields are matched by name, not position
+        let link: Link = serde_json::from_str(r#"{"weight": 2.0, "from": 3}"#).unwrap();
+        assert_eq!(
+            link,
+            Link {
+                from: 3,
+                weight: 2.0
+            }
+        );
+    }
+
+    const ADDER: &str = r#"{
+        "input_nodes": [-1, -2],
+        "output_nodes": [0],
+        "node_evals": [[0, "relu", "sum", 0.0, 1.0, [[-1, 1.0], [-2, 1.0]]]]
+    }"#;
+
+    #[test]
+    fn test_to_dot() {
+        let net = FeedForwardNetwork::from_json(
+            r#"{
+                "input_nodes": [-1, -2],
+                "output_nodes": [0],
+                "node_evals": [
+                    [1, "relu", "sum", 0.5, 1.0, [[-1, 1.0], [-2, -2.0]]],
+                    [0, "tanh", "sum", 0.0, 1.0, [[1, 0.25], [-1, 3.0]]]
+                ]
+            }"#,
+        )
+        .unwrap();
+        let dot = net.to_dot();
+
+        assert!(dot.starts_with("digraph network {\n"));
+        assert!(dot.ends_with("}\n"));
+        assert_eq!(
+            dot.lines().filter(|line| line.contains("[shape=")).count(),
+            4
+        );
+        assert_eq!(dot.lines().filter(|line| line.contains(" -> ")).count(), 4);
+
+        assert!(dot.contains("    \"-2\" [shape=box];\n"));
+        assert!(dot.contains("    \"0\" [shape=doublecircle, label=\"0\\nTanh Sum\\nbias 0\"];\n"));
+        assert!(dot.contains("    \"1\" [shape=circle, label=\"1\\nRelu Sum\\nbias 0.5\"];\n"));
+        assert!(dot.contains("    \"-2\" -> \"1\" [label=\"-2\"];\n"));
+    }
+
+    #[test]
+    fn test_arity() {
+        let mut net = FeedForwardNetwork::from_json(ADDER).unwrap();
+        assert_eq!(net.num_inputs(), 2);
+        assert_eq!(net.num_outputs(), 1);
+        assert_eq!(net.activate(vec![1., 2.]), vec![3.]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected 2 inputs, got 3")]
+    fn test_activate_wrong_input_count() {
+        let mut net = FeedForwardNetwork::from_json(ADDER).unwrap();
+        net.activate(vec![1., 2., 3.]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected 2 inputs, got 1")]
+    fn test_evaluate_batch_wrong_input_count() {
+        let net = FeedForwardNetwork::from_json(ADDER).unwrap();
+        net.evaluate_batch(&[vec![1., 2.], vec![1.]]);
+    }
+
+    #[test]
+    fn test_reset() {
+        let node = |node, links| (node, Activation::Relu, Aggregation::Sum, 0., 1., links);
+
+        // The hidden node reads the output before it is evaluated
+        let mut net = FeedForwardNetwork::new(
+            vec![-1],
+            vec![0],
+            vec![node(1, vec![(-1, 1.), (0, 1.)]), node(0, vec![(1, 1.)])],
+        );
+
+        let first = net.activate(vec![1.]);
+        net.activate(vec![5.]);
+        assert_eq!(net.activate(vec![1.]), first);
+        assert_eq!(net.evaluate_batch(&[vec![5.], vec![1.]])[1], first);
+
+        net.reset();
+        assert_eq!(net.values.values().filter(|&&value| value != 0.).count(), 0);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(network().validate(), Ok(()));
+
+        let node = |node, links| (node, Activation::Tanh, Aggregation::Sum, 0., 1., links);
+
+        let dangling =
+            FeedForwardNetwork::new(vec![-1], vec![0], vec![node(0, vec![(-1, 1.), (5, 1.)])]);
+        assert_eq!(
+            dangling.validate(),
+            Err(NetworkError::DanglingLink { node: 0, input: 5 })
+        );
+
+        let cyclic = FeedForwardNetwork::new(
+            vec![-1],
+            vec![0],
+            vec![node(1, vec![(-1, 1.), (0, 1.)]), node(0, vec![(1, 1.)])],
+        );
+        assert_eq!(
+            cyclic.validate(),
+            Err(NetworkError::Cycle { node: 1, input: 0 })
+        );
+
+        let self_loop = FeedForwardNetwork::new(vec![-1], vec![0], vec![node(0, vec![(0, 1.)])]);
+        assert_eq!(
+            self_loop.validate(),
+            Err(NetworkError::Cycle { node: 0, input: 0 })
+        );
+
+        let unreachable =
+            FeedForwardNetwork::new(vec![-1], vec![0, 1], vec![node(0, vec![(-1, 1.)])]);
+        assert_eq!(
+            unreachable.validate(),
+            Err(NetworkError::UnreachableOutput(1))
+        );
+
+        let no_outputs = FeedForwardNetwork::new(vec![-1], vec![], vec![node(0, vec![(-1, 1.)])]);
+        assert_eq!(no_outputs.validate(), Err(NetworkError::NoOutputs));
+    }
+}
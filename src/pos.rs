@@ -1,23 +1,48 @@
 use arrayvec::ArrayVec;
 use lazy_static::lazy_static;
-use rand::{distributions::Distribution, rngs::SmallRng, Rng, SeedableRng};
-use rustc_hash::{FxHashMap, FxHashSet};
-use serde::Serialize;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use rustc_hash::FxHashMap;
+use serde::{Deserialize, Serialize};
 use std::{
     cmp::{self, Ordering},
-    collections::BinaryHeap,
+    collections::{BinaryHeap, VecDeque},
+    error::Error,
     fmt::{self, Debug},
+    hash::{Hash, Hasher},
     str::FromStr,
 };
 
-use crate::comm;
-
-const BOARD_WIDTH: usize = 10;
-const BOARD_HEIGHT: usize = 22;
-const PIECE_NUMBER: usize = 7;
+use crate::{
+    bitboard::{piece_mask, BitBoard},
+    kicks::kicks,
+};
 
-type Board<T> = [[T; BOARD_WIDTH]; BOARD_HEIGHT];
-type Piece = Vec<Vec<Color>>;
+// Default board size, which is also the largest supported one
+pub const BOARD_WIDTH: usize = 10;
+pub const BOARD_HEIGHT: usize = 22;
+/// Smallest board width and height, the length of the I piece
+pub const MIN_BOARD_SIZE: usize = 4;
+pub const PIECE_COUNT: usize = 7;
+/// Length of `Position::feature_vector`
+pub const FEATURE_COUNT: usize = 9 + 3 * PIECE_COUNT;
+const COLOR_NUMBER: usize = 8;
+pub const PERFECT_CLEAR_BONUS: i64 = 2000;
+/// Lines to clear to go up a level
+pub const LINES_PER_LEVEL: usize = 10;
+/// Frames per second the gravity is given for
+const TICKS_PER_SECOND: f64 = 60.;
+/// Level from which pieces fall to the floor in one tick. The guideline
+/// curve is past `BOARD_HEIGHT` rows per tick there, and goes negative
+/// from level 116
+const MAX_GRAVITY_LEVEL: usize = 20;
+/// Garbage lines added by each clear in a combo, the first one included
+const COMBO_ATTACK: [usize; 12] = [0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 4, 5];
+/// Color of the cells of garbage rows, which have no piece of their own
+pub const GARBAGE_COLOR: Color = Color::Z;
+
+pub type Board<T> = [[T; BOARD_WIDTH]; BOARD_HEIGHT];
+pub type Piece = Vec<Vec<Color>>;
 
 macro_rules! piece {
     ($color:expr, $( $vec:expr ),* ) => {
@@ -57,111 +82,111 @@ const PIECE_ARRAY: [Color; 7] = [
 
 lazy_static! {
     #[rustfmt::skip]
-    static ref PIECES: [Vec<Piece>; PIECE_NUMBER] = [
+    static ref PIECES: [Vec<Piece>; PIECE_COUNT] = [
         piece!(
             Color::I,
-            vec![
-                vec![1, 1, 1, 1]
+            [
+                [1, 1, 1, 1]
             ],
-            vec![
-                vec![1],
-                vec![1],
-                vec![1],
-                vec![1],
+            [
+                [1],
+                [1],
+                [1],
+                [1],
             ]
         ),
         piece!(
             Color::O,
-            vec![
-                vec![1, 1],
-                vec![1, 1]
+            [
+                [1, 1],
+                [1, 1]
             ]
         ),
         piece!(
             Color::J,
-            vec![
-                vec![1, 1, 1],
-                vec![0, 0, 1],
+            [
+                [1, 1, 1],
+                [0, 0, 1],
             ],
-            vec![
-                vec![0, 1],
-                vec![0, 1],
-                vec![1, 1],
+            [
+                [0, 1],
+                [0, 1],
+                [1, 1],
             ],
-            vec![
-                vec![1, 0, 0],
-                vec![1, 1, 1],
+            [
+                [1, 0, 0],
+                [1, 1, 1],
             ],
-            vec![
-                vec![1, 1],
-                vec![1, 0],
-                vec![1, 0],
+            [
+                [1, 1],
+                [1, 0],
+                [1, 0],
             ]
         ),
         piece!(
             Color::L,
-            vec![
-                vec![1, 1, 1],
-                vec![1, 0, 0],
+            [
+                [1, 1, 1],
+                [1, 0, 0],
             ],
-            vec![
-                vec![1, 1],
-                vec![0, 1],
-                vec![0, 1],
+            [
+                [1, 1],
+                [0, 1],
+                [0, 1],
             ],
-            vec![
-                vec![0, 0, 1],
-                vec![1, 1, 1],
+            [
+                [0, 0, 1],
+                [1, 1, 1],
             ],
-            vec![
-                vec![1, 0],
-                vec![1, 0],
-                vec![1, 1],
+            [
+                [1, 0],
+                [1, 0],
+                [1, 1],
             ]
         ),
         piece!(
             Color::S,
-            vec![
-                vec![0, 1, 1],
-                vec![1, 1, 0],
+            [
+                [0, 1, 1],
+                [1, 1, 0],
             ],
-            vec![
-                vec![1, 0],
-                vec![1, 1],
-                vec![0, 1],
+            [
+                [1, 0],
+                [1, 1],
+                [0, 1],
             ]
         ),
         piece!(
             Color::T,
-            vec![
-                vec![1, 1, 1],
-                vec![0, 1, 0],
+            [
+                [1, 1, 1],
+                [0, 1, 0],
             ],
-            vec![
-                vec![0, 1],
-                vec![1, 1],
-                vec![0, 1],
+            [
+                [0, 1],
+                [1, 1],
+                [0, 1],
             ],
-            vec![
-                vec![0, 1, 0],
-                vec![1, 1, 1],
+            [
+                [0, 1, 0],
+                [1, 1, 1],
             ],
-            vec![
-                vec![1, 0],
-                vec![1, 1],
-                vec![1, 0],
+            [
+                [1, 0],
+                [1, 1],
+                [1, 0],
             ]
         ),
         piece!(
             Color::Z,
-            vec![
-                vec![1, 1, 0],
-                vec![0, 1, 1],
+            [
+                [1, 1, 0],
+                [0, 1, 1],
             ],
-            vec![
-                vec![0, 1],
-                vec![1, 1],
-                vec![1, 0],
+            [
+                [0, 1],
+                [1, 1],
+                [1, 0],
             ]
         ),
     ];
@@ -176,29 +201,31 @@ lazy_static! {
         vec![(1, -1), (-1, 1)],
     ];
 
-    static ref SPAWNS: Vec<(i32, i32, i32)> = vec![(3, 1, 0), (4, 0, 0), (3, 0, 0), (3, 0, 0), (3, 0, 0), (3, 0, 0), (3, 0, 0)];
+    // Spawn rows, pieces are horizontally centered on the board
+    static ref SPAWNS: Vec<i32> = vec![1, 0, 0, 0, 0, 0, 0];
 
-    static ref ZOBRISTS: Vec<Vec<u64>> = {
-        let mut rng = SmallRng::seed_from_u64(0xDEADBEEF12345678);
-
-        let mut board = Vec::new();
-        for _ in  0..BOARD_HEIGHT {
-            let mut row = Vec::new();
-            for _ in 0..BOARD_WIDTH {
-                row.push(rng.gen::<u64>());
-            }
-            board.push(row);
-        }
+    // One key per cell and per color, indexed by `(y * BOARD_WIDTH + x) * COLOR_NUMBER + color`
+    static ref ZOBRISTS: Vec<u64> = {
+        let mut rng = ChaCha8Rng::seed_from_u64(0xDEADBEEF12345678);
 
-        board
+        (0..BOARD_HEIGHT * BOARD_WIDTH * COLOR_NUMBER)
+            .map(|_| rng.gen::<u64>())
+            .collect()
     };
 }
 
+/// Shape of `piece` (1 to `PIECE_COUNT`, same numbering as `Color`) in the
+/// given rotation, or `None` if either index is out of range.
+pub fn piece_shape(piece: usize, rotation: usize) -> Option<&'static Piece> {
+    let rotations: &'static [Piece] = PIECES.get(piece.checked_sub(1)?)?;
+    rotations.get(rotation)
+}
+
 pub trait Cell {
     fn is_empty(&self) -> bool;
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[repr(u8)]
 pub enum Color {
     Empty,
@@ -212,6 +239,24 @@ pub enum Color {
     Random,
 }
 
+impl Color {
+    /// Piece with the given id, numbered from 1 to `PIECE_COUNT` as in `PIECE_ARRAY`.
+    pub fn piece(id: usize) -> Option<Color> {
+        PIECE_ARRAY.get(id.checked_sub(1)?).copied()
+    }
+
+    /// Piece whose shapes are the left-right reflections of this one's.
+    pub fn mirror(self) -> Color {
+        match self {
+            Color::J => Color::L,
+            Color::L => Color::J,
+            Color::S => Color::Z,
+            Color::Z => Color::S,
+            color => color,
+        }
+    }
+}
+
 impl From<u8> for Color {
     fn from(value: u8) -> Self {
         match value {
@@ -235,19 +280,6 @@ impl Cell for Color {
     }
 }
 
-#[repr(u8)]
-#[derive(Clone, Copy, PartialEq, Debug)]
-pub enum Mask {
-    Unset,
-    Set,
-}
-
-impl Cell for Mask {
-    fn is_empty(&self) -> bool {
-        *self == Mask::Unset
-    }
-}
-
 // TODO: custom error type
 impl TryFrom<char> for Color {
     type Error = ();
@@ -289,11 +321,94 @@ impl fmt::Display for Color {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Features {
     pub holes: f64,
     pub bumpiness: f64,
     pub aggregate_height: f64,
+    pub row_transitions: f64,
+    pub column_transitions: f64,
+    pub cumulative_wells: f64,
+    /// Lines cleared by the last placement
+    pub completed_lines: f64,
+    /// Filled cells above each hole, up to the top of its column
+    pub covered_cells: f64,
+    /// Height of the highest column
+    pub max_height: f64,
+}
+
+impl From<&Position> for Features {
+    fn from(pos: &Position) -> Self {
+        pos.features()
+    }
+}
+
+/// Largest value each board feature of `Position::feature_vector` can take
+/// on a board of a given size, used to bring them to [0, 1]. The pieces
+/// are already one-hot encoded and left as is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeatureScaler {
+    pub maxima: [f64; 9],
+}
+
+impl FeatureScaler {
+    pub fn new(width: usize, height: usize) -> FeatureScaler {
+        let (w, h) = (width as f64, height as f64);
+
+        FeatureScaler {
+            maxima: [
+                // holes
+                w * h,
+                // bumpiness
+                (w - 1.) * h,
+                // aggregate_height
+                w * h,
+                // completed_lines
+                4.,
+                // row_transitions, walls included
+                (w + 1.) * h,
+                // column_transitions, floor and top included
+                w * (h + 1.),
+                // cumulative_wells, a single well as deep as the board per column
+                w * h * (h + 1.) / 2.,
+                // covered_cells, half of each column covering the other half
+                w * h * h / 4.,
+                // max_height
+                h,
+            ],
+        }
+    }
+
+    /// Divides the leading entries of `vector` by their maxima.
+    pub fn scale(&self, vector: &mut [f64]) {
+        for (feature, max) in vector.iter_mut().zip(self.maxima) {
+            *feature /= max.max(1.);
+        }
+    }
+}
+
+impl Features {
+    fn values(&self) -> [f64; 9] {
+        [
+            self.holes,
+            self.bumpiness,
+            self.aggregate_height,
+            self.row_transitions,
+            self.column_transitions,
+            self.cumulative_wells,
+            self.completed_lines,
+            self.covered_cells,
+            self.max_height,
+        ]
+    }
+
+    /// Whether every feature is within `epsilon` of the one in `other`.
+    pub fn approx_eq(&self, other: &Features, epsilon: f64) -> bool {
+        self.values()
+            .iter()
+            .zip(other.values())
+            .all(|(a, b)| (a - b).abs() <= epsilon)
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Hash, Serialize)]
@@ -303,9 +418,14 @@ pub enum Action {
     SoftDrop,
     RotateCounterclockwise,
     RotateClockwise,
+    Hold,
     None,
 }
 
+/// `(piece, x, y, rotation, swap)`, `swap` meaning the piece was taken from
+/// the hold (pocket), or from the queue if the hold was empty.
+pub type Placement = (Color, usize, usize, usize, bool);
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Hash)]
 struct Move {
     action: Action,
@@ -342,250 +462,540 @@ impl PartialOrd for OrderedMove {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Position {
     pub score: i64,
     pub last_piece: Color,
     pub current_piece: Color,
     pub next_piece: Color,
+    /// Previews after `next_piece`
+    #[serde(default)]
+    pub queue: VecDeque<Color>,
+    /// Number of previews, `next_piece` included
+    #[serde(default = "default_queue_length")]
+    pub queue_length: usize,
+    pub pocket: Option<Color>,
+    /// Pieces left to draw from the current 7-bag, a new one starts once it is empty
+    pub bag: ArrayVec<Color, PIECE_COUNT>,
     pub board: Board<Color>,
+    pub width: usize,
+    pub height: usize,
     pub hash: u64,
     pub min_y: usize,
+    /// Consecutive placements that cleared lines
+    #[serde(default)]
+    pub combo: i32,
+    /// Consecutive difficult clears (tetrises and T-spins), placements that
+    /// clear no lines do not break the chain
+    #[serde(default)]
+    pub b2b: i32,
+    /// Points added when a clear leaves the board empty
+    #[serde(default = "default_perfect_clear_bonus")]
+    pub perfect_clear_bonus: i64,
+    /// Points per row a piece falls below its spawn row, 0 to only score
+    /// line clears
+    #[serde(default)]
+    pub hard_drop_points: i64,
+    #[serde(default)]
+    pub scoring: ScoringProfile,
+    #[serde(default)]
+    pub gravity: Gravity,
+    /// Whether the last placement held, which forbids holding for this one
+    #[serde(default)]
+    pub hold_used: bool,
+    /// Seed of the generator drawing the next pieces, which is reseeded after
+    /// each draw. Without one, pieces are drawn from a generator seeded by the
+    /// OS, or by the browser with the `wasm` feature.
+    #[serde(default)]
+    pub seed: Option<u64>,
+    /// Lines cleared by the placement that led to this position
+    #[serde(default)]
+    pub lines_cleared: usize,
+    /// Lines cleared since the start of the game
+    #[serde(default)]
+    pub total_lines: usize,
+    /// Height of each column, kept up to date by `apply_move` like `hash`
+    #[serde(skip)]
+    pub heights: [usize; BOARD_WIDTH],
+    /// Features last computed by `cached_features`
+    #[serde(skip)]
+    pub features_cache: FeatureCache,
+}
+
+/// Features of a position along with the state they were computed from, so
+/// that they are recomputed once any of it changes. Caches never make two
+/// positions differ.
+#[derive(Debug, Clone, Default)]
+pub struct FeatureCache(Option<(FeatureKey, Features)>);
+
+impl PartialEq for FeatureCache {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for FeatureCache {}
+
+/// Everything `Position::features` reads. The board is summarized by both
+/// its hash and its signature, as the hash is only updated by `apply_move`.
+#[derive(Debug, Clone, PartialEq)]
+struct FeatureKey {
+    hash: u64,
+    signature: u64,
+    width: usize,
+    height: usize,
+    heights: [usize; BOARD_WIDTH],
+    lines_cleared: usize,
+}
+
+/// Points awarded for line clears.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum ScoringProfile {
+    /// 40, 100, 300 and 1200 points for 1 to 4 lines, whatever the level
+    #[default]
+    Nes,
+    /// 100, 300, 500 and 800 points for 1 to 4 lines, the clear and combo
+    /// points being multiplied by the level. The back-to-back bonus, half
+    /// the clear points, follows them, the perfect clear and drop points do
+    /// not
+    Guideline,
+    /// Points for 1 to 4 lines, whatever the level
+    Custom([i64; 4]),
+}
+
+impl ScoringProfile {
+    /// Points for clearing `lines` rows at `level`, before the back-to-back,
+    /// combo and perfect clear bonuses. T-spins score 400, 800, 1200 and
+    /// 1600 points for 0 to 3 lines in every profile.
+    pub fn clear_points(self, lines: usize, t_spin: bool, level: usize) -> i64 {
+        let points = if t_spin {
+            [400, 800, 1200, 1600][lines.min(3)]
+        } else if lines == 0 {
+            0
+        } else {
+            let table = match self {
+                ScoringProfile::Nes => [40, 100, 300, 1200],
+                ScoringProfile::Guideline => [100, 300, 500, 800],
+                ScoringProfile::Custom(table) => table,
+            };
+            table[lines.min(4) - 1]
+        };
+
+        points * self.level_factor(level)
+    }
+
+    /// Multiplier of the points scored at `level`.
+    pub fn level_factor(self, level: usize) -> i64 {
+        match self {
+            ScoringProfile::Guideline => level as i64,
+            _ => 1,
+        }
+    }
+}
+
+/// How the cells left above cleared rows fall, as opposed to the falling
+/// speed of the pieces in `gravity_cells_per_tick`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Gravity {
+    /// Rows move down by the number of cleared rows under them, leaving
+    /// cells hanging over holes as they were
+    #[default]
+    Naive,
+    /// After the rows move down, each group of connected filled cells falls
+    /// on its own until it rests on the floor or another group. The rows
+    /// filled that way are cleared in turn.
+    Sticky,
+}
+
+/// Reason a position given from outside cannot be played, see
+/// `Position::validate`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PositionError {
+    /// The board is smaller than a piece or larger than `BOARD_WIDTH` x
+    /// `BOARD_HEIGHT`
+    Dimensions { width: usize, height: usize },
+    /// A cell of the board holds something other than a piece
+    InvalidCell { x: usize, y: usize, color: Color },
+    /// A cell past the width or the height of the board is filled
+    CellOutsideBoard { x: usize, y: usize },
+}
+
+impl fmt::Display for PositionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PositionError::Dimensions { width, height } => write!(
+                f,
+                "Board of {}x{} cells, expected from {}x{} to {}x{}",
+                width, height, MIN_BOARD_SIZE, MIN_BOARD_SIZE, BOARD_WIDTH, BOARD_HEIGHT
+            ),
+            PositionError::InvalidCell { x, y, color } => {
+                write!(
+                    f,
+                    "Cell ({}, {}) holds {:?}, which is not a piece",
+                    x, y, color
+                )
+            }
+            PositionError::CellOutsideBoard { x, y } => {
+                write!(f, "Cell ({}, {}) is filled but outside the board", x, y)
+            }
+        }
+    }
+}
+
+impl Error for PositionError {}
+
+/// Result of `Position::try_apply_move`.
+#[derive(Debug)]
+pub enum MoveResult {
+    /// The piece cannot be placed there
+    Illegal,
+    /// The placement tops out, the next piece cannot spawn
+    GameOver(Position),
+    Ok(Position),
+}
+
+/// Result of `Position::apply_move_detailed`.
+#[derive(Debug)]
+pub struct MoveOutcome {
+    pub position: Position,
+    /// Indices of the rows the placement fills, in the board before they
+    /// are cleared, from the top. Under `Gravity::Sticky`, rows filled later
+    /// by falling groups are not listed, as they were not rows of that board
+    pub cleared_rows: Vec<usize>,
+    /// Number of rows cleared, the `cleared_rows` and under
+    /// `Gravity::Sticky` the rows then filled by falling groups
+    pub lines_cleared: usize,
+    /// Points scored by the placement, `drop_points` included
+    pub score_delta: i64,
+    /// Points for dropping the piece, see `Position::hard_drop_points`
+    pub drop_points: i64,
+    pub t_spin: bool,
+    pub perfect_clear: bool,
+    /// Garbage lines sent to the opponent, see `attack`
+    pub attack: usize,
+    /// Height of the middle of the placed piece before any line is cleared,
+    /// the bottom row being at height 1 as in the column heights
+    pub landing_height: f64,
 }
 
 impl Position {
+    /// Only the top left `width` x `height` corner of `board` is used, the
+    /// rest must be left empty.
     pub fn new(
         last_piece: Color,
         current_piece: Color,
         next_piece: Color,
         score: i64,
         board: Board<Color>,
-        hash: u64,
-        min_y: usize,
+        width: usize,
+        height: usize,
     ) -> Self {
+        assert!(width <= BOARD_WIDTH && height <= BOARD_HEIGHT);
+
+        let min_y = (0..height)
+            .find(|&y| board[y][..width].iter().any(|cell| !cell.is_empty()))
+            .unwrap_or(height - 1);
+
         Position {
             last_piece,
             current_piece,
             next_piece,
+            pocket: None,
+            bag: ArrayVec::from(PIECE_ARRAY),
             score,
+            hash: hash_board(&board),
+            heights: column_heights(&board, width, height),
+            features_cache: FeatureCache::default(),
             board,
-            hash,
+            width,
+            height,
             min_y,
+            combo: 0,
+            b2b: 0,
+            perfect_clear_bonus: PERFECT_CLEAR_BONUS,
+            hard_drop_points: 0,
+            scoring: ScoringProfile::default(),
+            gravity: Gravity::default(),
+            hold_used: false,
+            seed: None,
+            queue: VecDeque::new(),
+            queue_length: 1,
+            lines_cleared: 0,
+            total_lines: 0,
         }
     }
 
-    pub fn path(&self, goal: (i32, i32, i32)) -> Vec<Action> {
-        let rot_num = ROTATION_OFFSETS[self.current_piece as usize - 1].len() as i32;
+    /// Empty board of the given size.
+    pub fn with_size(width: usize, height: usize) -> Self {
+        let default = Position::default();
+
+        Position::new(
+            default.last_piece,
+            default.current_piece,
+            default.next_piece,
+            0,
+            default.board,
+            width,
+            height,
+        )
+    }
 
-        let mut goal_mv = None;
+    /// Empty board with a deterministic piece sequence.
+    pub fn with_seed(seed: u64) -> Self {
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+        let mut bag = ArrayVec::new();
+        let current_piece = draw_piece(&mut bag, &mut rng);
+        let next_piece = draw_piece(&mut bag, &mut rng);
 
-        let start = SPAWNS[self.current_piece as usize - 1];
-        let start_move = OrderedMove::new(Move::new(Action::None, start), 0);
+        Position {
+            current_piece,
+            next_piece,
+            bag,
+            seed: Some(rng.gen()),
+            ..Position::default()
+        }
+    }
 
-        let mut frontier = BinaryHeap::new();
-        frontier.push(start_move);
+    /// Empty board scoring clears according to `scoring`.
+    pub fn with_scoring(scoring: ScoringProfile) -> Self {
+        Position {
+            scoring,
+            ..Position::default()
+        }
+    }
 
-        let mut came_from = FxHashMap::default();
-        let mut cost_so_far = FxHashMap::default();
-        came_from.insert(start, None);
-        cost_so_far.insert(start, 0);
+    /// Empty board showing `queue_length` previews.
+    pub fn with_queue_length(queue_length: usize) -> Self {
+        assert!(queue_length > 0);
 
-        while !frontier.is_empty() {
-            let current = frontier.pop().unwrap().mv;
-            let dest = current.dest;
+        let mut pos = Position::default();
+        let mut rng = ChaCha8Rng::from_entropy();
+        while pos.queue.len() + 1 < queue_length {
+            pos.queue.push_back(draw_piece(&mut pos.bag, &mut rng));
+        }
+        pos.queue_length = queue_length;
 
-            if dest == goal {
-                goal_mv = Some(current);
-                break;
-            }
+        pos
+    }
 
-            let mut piece = &PIECES[self.current_piece as usize - 1]
-                [wrap_rot(current.dest.2, rot_num) as usize];
+    /// Zobrist hash of the board, computed from scratch.
+    ///
+    /// `hash` is kept up to date incrementally and should always be equal to this.
+    pub fn zobrist(&self) -> u64 {
+        hash_board(&self.board)
+    }
 
-            let mut move_list: ArrayVec<Move, 5> = ArrayVec::new();
+    /// Full state as JSON, unlike TPN it keeps the bag and the pocket.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("Positions always serialize")
+    }
 
-            if !check_collision(&self.board, piece, dest.0 - 1, dest.1) {
-                move_list.push(Move::new(Action::MoveLeft, (dest.0 - 1, dest.1, dest.2)));
-            }
+    /// Position saved by `to_json`, which must pass `validate`. The hash
+    /// and the column heights are computed again from the board.
+    pub fn from_json(s: &str) -> serde_json::Result<Position> {
+        let mut deserializer = serde_json::Deserializer::from_str(s);
+        let pos = Position::deserialize_checked(&mut deserializer)?;
+        deserializer.end()?;
 
-            if !check_collision(&self.board, piece, dest.0 + 1, dest.1) {
-                move_list.push(Move::new(Action::MoveRight, (dest.0 + 1, dest.1, dest.2)));
-            }
+        Ok(pos)
+    }
 
-            if !check_collision(&self.board, piece, dest.0, dest.1 + 1) {
-                move_list.push(Move::new(Action::SoftDrop, (dest.0, dest.1 + 1, dest.2)));
-            }
+    /// Same as `from_json` for a position inside another value, to use
+    /// with `#[serde(deserialize_with)]`.
+    pub fn deserialize_checked<'de, D>(deserializer: D) -> Result<Position, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let mut pos = Position::deserialize(deserializer)?;
+        pos.validate().map_err(serde::de::Error::custom)?;
+        pos.hash = hash_board(&pos.board);
+        pos.heights = column_heights(&pos.board, pos.width, pos.height);
+
+        Ok(pos)
+    }
 
-            let mut rot = wrap_rot(dest.2 - 1, rot_num) as usize;
-            let mut rot_offset = ROTATION_OFFSETS[self.current_piece as usize - 1][rot];
-
-            piece = &PIECES[self.current_piece as usize - 1][rot];
-
-            if !check_collision(
-                &self.board,
-                piece,
-                dest.0 - rot_offset.0,
-                dest.1 - rot_offset.1,
-            ) {
-                move_list.push(Move::new(
-                    Action::RotateCounterclockwise,
-                    (
-                        dest.0 - rot_offset.0,
-                        dest.1 - rot_offset.1,
-                        wrap_rot(dest.2 - 1, rot_num),
-                    ),
-                ));
+    /// Checks that the board has a supported size and only holds pieces,
+    /// its cells past `width` and `height` being empty. Boards from outside
+    /// are otherwise trusted, and break the move generation in odd ways.
+    pub fn validate(&self) -> Result<(), PositionError> {
+        if !(MIN_BOARD_SIZE..=BOARD_WIDTH).contains(&self.width)
+            || !(MIN_BOARD_SIZE..=BOARD_HEIGHT).contains(&self.height)
+        {
+            return Err(PositionError::Dimensions {
+                width: self.width,
+                height: self.height,
+            });
+        }
+
+        for (y, row) in self.board.iter().enumerate() {
+            for (x, &color) in row.iter().enumerate() {
+                if color.is_empty() {
+                    continue;
+                }
+                if x >= self.width || y >= self.height {
+                    return Err(PositionError::CellOutsideBoard { x, y });
+                }
+                if !PIECE_ARRAY.contains(&color) {
+                    return Err(PositionError::InvalidCell { x, y, color });
+                }
             }
+        }
+
+        Ok(())
+    }
+
+    /// Board drawn one row per line, `.` for an empty cell and a piece id
+    /// from 1 to `PIECE_COUNT` for a filled one, or `None` if the grid is
+    /// malformed. The size of the board is that of the grid, blank lines and
+    /// surrounding spaces are ignored.
+    pub fn from_ascii(s: &str) -> Option<Position> {
+        let mut board = [[Color::Empty; BOARD_WIDTH]; BOARD_HEIGHT];
+        let rows: Vec<&str> = s
+            .lines()
+            .map(str::trim)
+            .filter(|row| !row.is_empty())
+            .collect();
+
+        let width = rows.first()?.len();
+        if rows.len() > BOARD_HEIGHT || width > BOARD_WIDTH {
+            return None;
+        }
 
-            rot = wrap_rot(dest.2 + 1, rot_num) as usize;
-            rot_offset = ROTATION_OFFSETS[self.current_piece as usize - 1][dest.2 as usize];
-            piece = &PIECES[self.current_piece as usize - 1][rot];
-
-            if !check_collision(
-                &self.board,
-                piece,
-                dest.0 + rot_offset.0,
-                dest.1 + rot_offset.1,
-            ) {
-                move_list.push(Move::new(
-                    Action::RotateClockwise,
-                    (
-                        dest.0 + rot_offset.0,
-                        dest.1 + rot_offset.1,
-                        wrap_rot(dest.2 + 1, rot_num),
-                    ),
-                ));
+        for (y, row) in rows.iter().enumerate() {
+            if row.len() != width {
+                return None;
             }
 
-            for &next in move_list.iter() {
-                // Lower costs to higher actions
-                let c = match next.action {
-                    Action::SoftDrop => 1,
-                    _ => next.dest.1 + 1,
+            for (x, c) in row.chars().enumerate() {
+                board[y][x] = match c {
+                    '.' => Color::Empty,
+                    _ => Color::piece(c.to_digit(10)? as usize)?,
                 };
-
-                let new_cost = cost_so_far.get(&current.dest).unwrap() + c;
-                if !cost_so_far.contains_key(&next.dest)
-                    || new_cost < *cost_so_far.get(&next.dest).unwrap()
-                {
-                    cost_so_far.insert(next.dest, new_cost);
-                    let priority = new_cost + proximity(goal, next.dest, rot_num);
-                    frontier.push(OrderedMove::new(next, priority));
-                    came_from.insert(next.dest, Some(current));
-                }
             }
         }
 
-        let mut current = goal;
-        let mut path = Vec::new();
-        let mut mv: Option<Move> = goal_mv;
-        while current != start {
-            if let Some(mv) = mv {
-                path.push(mv.action);
-            }
+        let default = Position::default();
 
-            match came_from.get(&current) {
-                Some(&m) => mv = m,
-                None => {
-                    eprintln!("Move {:?} [{}] on {}", goal, self.min_y, self);
-                    // eprintln!("{:?}", comm::POSITION_HISTORY.lock().unwrap());
-                    panic!()
-                }
+        Some(Position::new(
+            default.last_piece,
+            default.current_piece,
+            default.next_piece,
+            0,
+            board,
+            width,
+            rows.len(),
+        ))
+    }
+
+    /// Board in a box next to the current piece, the hold, the previews,
+    /// the lines cleared by the last placement and the score, for debugging.
+    pub fn render_full(&self) -> String {
+        let pocket = self
+            .pocket
+            .map_or("-".to_string(), |piece| piece.to_string());
+        let queue: Vec<String> = std::iter::once(self.next_piece)
+            .chain(self.queue.iter().copied())
+            .map(|piece| piece.to_string())
+            .collect();
+        let panel = [
+            format!("Current {}", self.current_piece),
+            format!("Hold    {}", pocket),
+            format!("Next    {}", queue.join(" ")),
+            format!("Lines   {}", self.lines_cleared),
+            format!("Score   {}", self.score),
+        ];
+
+        let border = "─".repeat(self.width);
+        let mut s = format!("┌{}┐\n", border);
+
+        for (y, row) in self.board[..self.height].iter().enumerate() {
+            s.push('│');
+            for cell in &row[..self.width] {
+                s.push_str(&cell.to_string());
             }
+            s.push('│');
 
-            current = mv.unwrap().dest;
+            if let Some(line) = panel.get(y) {
+                s.push_str("  ");
+                s.push_str(line);
+            }
+            s.push('\n');
         }
 
-        path.reverse();
+        s.push_str(&format!("└{}┘\n", border));
 
-        path
+        s
     }
 
-    fn path_reverse(
-        &self,
-        piece: Color,
-        start: (i32, i32, i32),
-        frontier: &mut BinaryHeap<OrderedMove>,
-    ) -> bool {
-        let rot_num = ROTATION_OFFSETS[piece as usize - 1].len() as i32;
+    /// Every field of the position, for when the compact `Debug` output
+    /// is not enough.
+    pub fn debug_verbose(&self) -> String {
+        format!("{:#?}", self)
+    }
 
-        let goal = SPAWNS[piece as usize - 1];
-        let start_move = OrderedMove::new(Move::new(Action::None, start), 0);
+    /// Board in the format read by `from_ascii`.
+    pub fn to_ascii(&self) -> String {
+        let mut s = String::new();
 
-        frontier.push(start_move);
+        for row in &self.board[..self.height] {
+            for &cell in &row[..self.width] {
+                match cell {
+                    Color::Empty => s.push('.'),
+                    _ => s.push_str(&(cell as u8).to_string()),
+                }
+            }
+            s.push('\n');
+        }
 
-        let mut came_from = FxHashMap::default();
-        let mut cost_so_far = FxHashMap::default();
-        came_from.insert(start, None);
-        cost_so_far.insert(start, 0);
+        s
+    }
 
-        while !frontier.is_empty() {
-            let current = frontier.pop().unwrap().mv;
-            let dest = current.dest;
+    fn spawn(&self, piece: Color) -> (i32, i32, i32) {
+        let size_x = PIECES[piece as usize - 1][0][0].len();
 
-            if dest == goal {
-                return true;
-            }
+        (
+            ((self.width - size_x) / 2) as i32,
+            SPAWNS[piece as usize - 1],
+            0,
+        )
+    }
 
-            let mut piece = &PIECES[self.current_piece as usize - 1]
-                [wrap_rot(current.dest.2, rot_num) as usize];
+    fn collides<T: Cell>(&self, board: &Board<T>, piece: &Piece, x: i32, y: i32) -> bool {
+        check_collision(board, piece, x, y, self.width, self.height)
+    }
 
-            let mut move_list: ArrayVec<Move, 5> = ArrayVec::new();
+    /// Inputs bringing the played piece from its spawn to `goal`, holding
+    /// first if `swap`, or `None` if it cannot get there.
+    pub fn path(&self, swap: bool, goal: (i32, i32, i32)) -> Option<Vec<Action>> {
+        let piece_color = self.played_piece(swap);
+        let piece_idx = piece_color as usize - 1;
+        let rot_num = ROTATION_OFFSETS[piece_idx].len() as i32;
 
-            if !check_collision(&self.board, piece, dest.0 - 1, dest.1) {
-                move_list.push(Move::new(Action::MoveRight, (dest.0 - 1, dest.1, dest.2)));
-            }
+        let start = self.spawn(piece_color);
+        if self.collides(&self.board, &PIECES[piece_idx][0], start.0, start.1) {
+            return None;
+        }
 
-            if !check_collision(&self.board, piece, dest.0 + 1, dest.1) {
-                move_list.push(Move::new(Action::MoveLeft, (dest.0 + 1, dest.1, dest.2)));
-            }
+        let start_move = OrderedMove::new(Move::new(Action::None, start), 0);
 
-            if !check_collision(&self.board, piece, dest.0, dest.1 - 1) {
-                move_list.push(Move::new(Action::SoftDrop, (dest.0, dest.1 - 1, dest.2)));
-            }
+        let mut frontier = BinaryHeap::new();
+        frontier.push(start_move);
 
-            let mut rot = wrap_rot(dest.2 - 1, rot_num) as usize;
-            let mut rot_offset = ROTATION_OFFSETS[self.current_piece as usize - 1][rot];
-
-            piece = &PIECES[self.current_piece as usize - 1][rot];
-
-            if !check_collision(
-                &self.board,
-                piece,
-                dest.0 - rot_offset.0,
-                dest.1 - rot_offset.1,
-            ) {
-                move_list.push(Move::new(
-                    Action::RotateClockwise,
-                    (
-                        dest.0 - rot_offset.0,
-                        dest.1 - rot_offset.1,
-                        wrap_rot(dest.2 - 1, rot_num),
-                    ),
-                ));
-            }
+        let mut came_from = FxHashMap::default();
+        let mut cost_so_far = FxHashMap::default();
+        came_from.insert(start, None);
+        cost_so_far.insert(start, 0);
+
+        let bits = self.bitboard();
+        let mut goal_mv = None;
 
-            rot = wrap_rot(dest.2 + 1, rot_num) as usize;
-            rot_offset = ROTATION_OFFSETS[self.current_piece as usize - 1][dest.2 as usize];
-            piece = &PIECES[self.current_piece as usize - 1][rot];
-
-            if !check_collision(
-                &self.board,
-                piece,
-                dest.0 + rot_offset.0,
-                dest.1 + rot_offset.1,
-            ) {
-                move_list.push(Move::new(
-                    Action::RotateCounterclockwise,
-                    (
-                        dest.0 + rot_offset.0,
-                        dest.1 + rot_offset.1,
-                        wrap_rot(dest.2 + 1, rot_num),
-                    ),
-                ));
+        while let Some(OrderedMove { mv: current, .. }) = frontier.pop() {
+            if current.dest == goal {
+                goal_mv = Some(current);
+                break;
             }
 
-            for &next in move_list.iter() {
+            for next in self.next_states(&bits, piece_color, current.dest) {
                 // Lower costs to higher actions
                 let c = match next.action {
                     Action::SoftDrop => 1,
@@ -604,497 +1014,3080 @@ impl Position {
             }
         }
 
-        false
-    }
+        let mut mv = goal_mv?;
+        let mut path = Vec::new();
+        while mv.dest != start {
+            path.push(mv.action);
+            mv = came_from[&mv.dest]?;
+        }
 
-    fn pathfind_open_air(
-        &self,
-        open_air_mask: &Board<Mask>,
-        piece_idx: usize,
-        x: i32,
-        y: i32,
-        rot: i32,
-        cache: &mut FxHashSet<(i32, i32, i32)>,
-    ) -> bool {
-        if cache.contains(&(x, y, rot)) {
-            return false;
+        if swap {
+            path.push(Action::Hold);
         }
 
-        cache.insert((x, y, rot));
+        path.reverse();
+
+        Some(path)
+    }
+
+    /// States `piece` reaches from `dest` with a single input, along with
+    /// that input. `path` searches through them, and move generation only
+    /// keeps the placements they lead to from the spawn, so that every legal
+    /// placement has a path.
+    fn next_states(
+        &self,
+        bits: &BitBoard,
+        piece: Color,
+        dest: (i32, i32, i32),
+    ) -> ArrayVec<Move, 5> {
+        let (x, y, rot) = dest;
+        let mask = piece_mask(piece, rot as usize);
 
-        let piece = &PIECES[piece_idx][rot as usize];
+        let mut moves = ArrayVec::new();
 
-        if check_collision(&self.board, piece, x, y) {
-            return false;
+        if !bits.collides(mask, x - 1, y) {
+            moves.push(Move::new(Action::MoveLeft, (x - 1, y, rot)));
         }
 
-        if !check_collision(open_air_mask, piece, x, y) {
-            return true;
+        if !bits.collides(mask, x + 1, y) {
+            moves.push(Move::new(Action::MoveRight, (x + 1, y, rot)));
         }
 
-        if self.pathfind_open_air(open_air_mask, piece_idx, x + 1, y, rot, cache) {
-            return true;
+        if !bits.collides(mask, x, y + 1) {
+            moves.push(Move::new(Action::SoftDrop, (x, y + 1, rot)));
         }
 
-        if self.pathfind_open_air(open_air_mask, piece_idx, x - 1, y, rot, cache) {
-            return true;
+        if let Some(next) = self.rotate(bits, piece, dest, false) {
+            moves.push(Move::new(Action::RotateCounterclockwise, next));
         }
 
-        if self.pathfind_open_air(open_air_mask, piece_idx, x, y - 1, rot, cache) {
-            return true;
+        if let Some(next) = self.rotate(bits, piece, dest, true) {
+            moves.push(Move::new(Action::RotateClockwise, next));
         }
 
-        let rot_num = ROTATION_OFFSETS[piece_idx].len() as i32;
-        let mut rot_offset = ROTATION_OFFSETS[piece_idx][wrap_rot(rot - 1, rot_num) as usize];
+        moves
+    }
 
-        if self.pathfind_open_air(
-            open_air_mask,
-            piece_idx,
-            x - rot_offset.0,
-            y - rot_offset.1,
-            ((rot - 1) % rot_num + rot_num) % rot_num,
-            cache,
-        ) {
-            return true;
+    /// States of `piece` reachable from its spawn through `next_states`, in
+    /// the order they are found, none if the piece cannot spawn.
+    fn reachable_states(&self, bits: &BitBoard, piece: Color) -> Vec<(i32, i32, i32)> {
+        let start = self.spawn(piece);
+        if bits.collides(piece_mask(piece, 0), start.0, start.1) {
+            return Vec::new();
         }
 
-        rot_offset = ROTATION_OFFSETS[piece_idx][rot as usize];
-
-        if self.pathfind_open_air(
-            open_air_mask,
-            piece_idx,
-            x + rot_offset.0,
-            y + rot_offset.1,
-            (rot + 1) % rot_num,
-            cache,
-        ) {
-            return true;
+        // Columns visited in each rotation and row
+        let mut visited = [[0u16; BOARD_HEIGHT]; 4];
+        let mut states = vec![start];
+        visited[0][start.1 as usize] |= 1 << start.0;
+
+        let mut i = 0;
+        while let Some(&dest) = states.get(i) {
+            for next in self.next_states(bits, piece, dest) {
+                let (x, y, rot) = next.dest;
+                let row = &mut visited[rot as usize][y as usize];
+                if *row >> x & 1 == 0 {
+                    *row |= 1 << x;
+                    states.push((x, y, rot));
+                }
+            }
+            i += 1;
         }
 
-        false
+        states
     }
 
-    pub fn legal_moves(&self) -> ArrayVec<Vec<(Color, usize, usize, usize)>, 7> {
-        let mut legal_moves = ArrayVec::new();
-        let mut open_air_mask = [[Mask::Set; BOARD_WIDTH]; BOARD_HEIGHT];
-        let mut cache = FxHashSet::default();
-        let mut frontier_cache = BinaryHeap::new();
-
-        for x in 0..BOARD_WIDTH {
-            let mut y = 0;
-
-            while y < BOARD_HEIGHT && self.board[y][x].is_empty() {
-                open_air_mask[y][x] = Mask::Unset;
-                y += 1;
+    /// Same states as `reachable_states`, as the columns reached in each
+    /// rotation and row. Whole rows of states move at once, which is much
+    /// faster than going through them one by one.
+    fn reachable_grid(&self, bits: &BitBoard, piece: Color) -> [[u16; BOARD_HEIGHT]; 4] {
+        let piece_idx = piece as usize - 1;
+        let rot_num = ROTATION_OFFSETS[piece_idx].len();
+        let mut reached = [[0u16; BOARD_HEIGHT]; 4];
+
+        let start = self.spawn(piece);
+        if bits.collides(piece_mask(piece, 0), start.0, start.1) {
+            return reached;
+        }
+        reached[0][start.1 as usize] = 1 << start.0;
+
+        // Columns the piece fits in, in each rotation and row
+        let mut free = [[0u16; BOARD_HEIGHT]; 4];
+        for rot in 0..rot_num {
+            let mask = piece_mask(piece, rot);
+            for y in 0..self.height {
+                free[rot][y] = bits.free_columns(mask, y);
             }
         }
 
-        // Weird but works for the time being
-        let piece_list = if self.current_piece == Color::Random {
-            ArrayVec::from(PIECE_ARRAY)
-        } else {
-            let mut vec = ArrayVec::new();
-            vec.push(self.current_piece);
-            vec
-        };
+        let shift = |row: u16, dx: i32| if dx >= 0 { row << dx } else { row >> -dx };
 
-        for piece_color in piece_list {
-            let piece_kind = &PIECES[piece_color as usize - 1];
-            let mut piece_legal_moves = Vec::new();
-
-            for (rot, piece) in piece_kind.iter().enumerate() {
-                let size_x = piece[0].len();
-                let size_y = piece.len();
-                for x in 0..(BOARD_WIDTH - size_x + 1) {
-                    for y in (0..(BOARD_HEIGHT - size_y + 1)).rev() {
-                        let is_open_air =
-                            !check_collision(&open_air_mask, piece, x as i32, y as i32);
-                        if is_lock_fast(&self.board, piece, x, y, size_x, size_y) {
-                            if self.min_y < 4 {
-                                if self.path_reverse(
-                                    piece_color,
-                                    (x as i32, y as i32, rot as i32),
-                                    &mut frontier_cache,
-                                ) {
-                                    piece_legal_moves.push((piece_color, x, y, rot));
-                                }
-                                frontier_cache.clear();
-                            } else if !is_open_air {
-                                if self.pathfind_open_air(
-                                    &open_air_mask,
-                                    piece_color as usize - 1,
-                                    x as i32,
-                                    y as i32,
-                                    rot as i32,
-                                    &mut cache,
-                                ) {
-                                    piece_legal_moves.push((piece_color, x, y, rot));
-                                }
+        let mut changed = true;
+        while changed {
+            changed = false;
+
+            for rot in 0..rot_num {
+                for y in 0..self.height {
+                    // Soft drop from the row above, then sideways
+                    let mut row = reached[rot][y];
+                    if y > 0 {
+                        row |= reached[rot][y - 1] & free[rot][y];
+                    }
+                    row = spread(row, free[rot][y]);
+
+                    changed |= row != reached[rot][y];
+                    reached[rot][y] = row;
+                }
+            }
 
-                                cache.clear();
-                            } else {
-                                piece_legal_moves.push((piece_color, x, y, rot));
+            for rot in 0..rot_num {
+                for clockwise in [false, true] {
+                    let new_rot = wrap_rot(
+                        if clockwise {
+                            rot + 1
+                        } else {
+                            rot + rot_num - 1
+                        } as i32,
+                        rot_num as i32,
+                    ) as usize;
+                    let offset = rotation_offset(piece_idx, rot as i32, clockwise);
+                    let kicks = kicks(piece, rot, clockwise);
+
+                    for y in 0..self.height {
+                        // Columns still looking for a kick that fits
+                        let mut from = reached[rot][y];
+
+                        for &(kick_x, kick_y) in &kicks {
+                            let dx = offset.0 + kick_x;
+                            let to_y = y as i32 + offset.1 + kick_y;
+                            if from == 0 {
+                                break;
                             }
-                        } else if is_open_air {
-                            break;
+                            if to_y < 0 || to_y >= self.height as i32 {
+                                continue;
+                            }
+
+                            let to = shift(from, dx) & free[new_rot][to_y as usize];
+                            let row = &mut reached[new_rot][to_y as usize];
+                            changed |= to & !*row != 0;
+                            *row |= to;
+                            from &= !shift(to, -dx);
                         }
                     }
                 }
             }
-
-            legal_moves.push(piece_legal_moves)
         }
 
-        legal_moves
+        reached
     }
 
-    pub fn features(&self) -> Features {
-        let mut holes = 0;
-        let mut heights: [f64; BOARD_WIDTH] = [0.; BOARD_WIDTH];
+    /// Where `piece` ends up when rotated from `(x, y, rot)`, trying each kick
+    /// in order, or `None` if they all collide.
+    fn rotate(
+        &self,
+        bits: &BitBoard,
+        piece: Color,
+        (x, y, rot): (i32, i32, i32),
+        clockwise: bool,
+    ) -> Option<(i32, i32, i32)> {
+        let piece_idx = piece as usize - 1;
+        let rot_num = ROTATION_OFFSETS[piece_idx].len() as i32;
+        let new_rot = wrap_rot(if clockwise { rot + 1 } else { rot - 1 }, rot_num);
+        let mask = piece_mask(piece, new_rot as usize);
+        let offset = rotation_offset(piece_idx, rot, clockwise);
+
+        kicks(piece, rot as usize, clockwise)
+            .into_iter()
+            .map(|(kick_x, kick_y)| (x + offset.0 + kick_x, y + offset.1 + kick_y))
+            .find(|&(x, y)| !bits.collides(mask, x, y))
+            .map(|(x, y)| (x, y, new_rot))
+    }
 
-        for y in (1..BOARD_HEIGHT).rev() {
-            for x in 0..BOARD_WIDTH {
-                if !self.board[y][x].is_empty() {
-                    heights[x] = (BOARD_HEIGHT - y) as f64;
-                }
+    /// Top left corner of the current piece once rotated from `from_rotation`
+    /// to `to_rotation` at `(x, y)`, using the SRS wall kicks. Pieces with two
+    /// rotations are rotated clockwise.
+    pub fn rotate_piece(
+        &self,
+        from_rotation: usize,
+        to_rotation: usize,
+        x: usize,
+        y: usize,
+    ) -> Option<(usize, usize)> {
+        let rot_num = PIECES
+            .get((self.current_piece as usize).checked_sub(1)?)?
+            .len();
+        if from_rotation >= rot_num || to_rotation >= rot_num {
+            return None;
+        }
 
-                if !self.board[y - 1][x].is_empty() && self.board[y][x].is_empty() {
-                    holes += 1;
+        let clockwise = if to_rotation == (from_rotation + 1) % rot_num {
+            true
+        } else if to_rotation == (from_rotation + rot_num - 1) % rot_num {
+            false
+        } else {
+            return None;
+        };
 
-                    let mut l = 1;
+        let src = (x as i32, y as i32, from_rotation as i32);
+        let (x, y, _) = self.rotate(&self.bitboard(), self.current_piece, src, clockwise)?;
 
-                    while y + l < BOARD_HEIGHT && self.board[y + l][x].is_empty() {
-                        holes += 1;
-                        l += 1;
-                    }
-                }
-            }
+        Some((x as usize, y as usize))
+    }
+
+    /// Piece that gets placed, holding swaps the current piece for the pocket
+    /// one, or the next one if the pocket is empty.
+    pub fn played_piece(&self, swap: bool) -> Color {
+        if swap {
+            self.pocket.unwrap_or(self.next_piece)
+        } else {
+            self.current_piece
         }
+    }
 
-        let bumpiness = heights
-            .windows(2)
-            .map(|window| (window[0] - window[1]).abs())
-            .sum();
+    /// Whether holding is allowed and would change the piece to place. This
+    /// is never the case while the current piece is unknown.
+    fn can_swap(&self) -> bool {
+        let swap_piece = self.played_piece(true);
 
-        let aggregate_height = heights.iter().sum();
+        !self.hold_used
+            && self.current_piece != Color::Random
+            && swap_piece != Color::Random
+            && swap_piece != self.current_piece
+    }
 
-        Features {
-            holes: holes as f64,
-            aggregate_height,
-            bumpiness,
+    /// Pieces the current one can turn out to be when it is still unknown.
+    pub(crate) fn possible_pieces(&self) -> ArrayVec<Color, PIECE_COUNT> {
+        if self.bag.is_empty() {
+            ArrayVec::from(PIECE_ARRAY)
+        } else {
+            self.bag.clone()
         }
     }
 
-    pub fn apply_move(
+    /// Probability of `piece` being the next one drawn from the bag.
+    pub fn draw_probability(&self, piece: Color) -> f64 {
+        let possible_pieces = self.possible_pieces();
+
+        if possible_pieces.contains(&piece) {
+            1. / possible_pieces.len() as f64
+        } else {
+            0.
+        }
+    }
+
+    /// Board coordinates of the cells filled by `piece` in the given rotation
+    /// with its top left corner at `(x, y)`, see `piece_shape`.
+    pub fn piece_cells(
         &self,
-        piece_color: Color,
+        piece: usize,
+        rotation: usize,
         x: usize,
         y: usize,
-        rot: usize,
-        gen_next: bool,
-    ) -> Position {
-        let piece = &PIECES[piece_color as usize - 1][rot];
-        let size_x = piece[0].len();
-        let size_y = piece.len();
+    ) -> ArrayVec<(usize, usize), 4> {
+        let shape = piece_shape(piece, rotation).expect("Invalid piece or rotation");
+
+        shape
+            .iter()
+            .enumerate()
+            .flat_map(|(j, row)| {
+                row.iter()
+                    .enumerate()
+                    .filter(|(_, cell)| !cell.is_empty())
+                    .map(move |(i, _)| (x + i, y + j))
+            })
+            .collect()
+    }
 
-        let mut new_board = self.board.clone();
-        let mut new_score = self.score;
-        let mut new_hash = self.hash;
+    /// Row where the piece comes to rest when dropped straight down from
+    /// the top of column `x`, or `None` if it does not fit there.
+    pub fn landing_row(&self, x: usize, rotation: usize, swap: bool) -> Option<usize> {
+        let piece = piece_shape(self.played_piece(swap) as usize, rotation)?;
 
-        let mut new_min_y = self.min_y.min(y);
+        if self.collides(&self.board, piece, x as i32, 0) {
+            return None;
+        }
 
-        // Place the piece
-        for i in 0..size_x {
-            for j in 0..size_y {
-                if new_board[y + j][x + i].is_empty() && !piece[j][i].is_empty() {
-                    let piece_type = piece[j][i];
-                    new_board[y + j][x + i] = piece_type;
-                    new_hash ^= ZOBRISTS[y + j][x + i];
-                }
+        let mut y = 0;
+        while !self.collides(&self.board, piece, x as i32, y + 1) {
+            y += 1;
+        }
+
+        Some(y as usize)
+    }
+
+    /// Legal placements for each piece the current one can be. When the
+    /// current piece is known, there is a single list which also holds the swaps.
+    pub fn legal_moves(&self) -> ArrayVec<Vec<Placement>, 7> {
+        self.piece_lists()
+            .iter()
+            .map(|pieces| {
+                let mut moves = Vec::new();
+                self.push_legal_moves(pieces, &mut moves);
+                moves
+            })
+            .collect()
+    }
+
+    /// Same as `legal_moves`, with the lists one after the other in `buf`,
+    /// which is cleared first so that it can be reused from one position to
+    /// the next.
+    pub fn legal_moves_into(&self, buf: &mut Vec<Placement>) {
+        buf.clear();
+
+        for pieces in self.piece_lists() {
+            self.push_legal_moves(&pieces, buf);
+        }
+    }
+
+    /// Pieces with whether they are swapped in, for each list of `legal_moves`.
+    fn piece_lists(&self) -> ArrayVec<ArrayVec<(Color, bool), 2>, 7> {
+        // Weird but works for the time being
+        if self.current_piece == Color::Random {
+            self.possible_pieces()
+                .iter()
+                .map(|&color| [(color, false)].into_iter().collect())
+                .collect()
+        } else {
+            let mut vec = ArrayVec::new();
+            vec.push((self.current_piece, false));
+            if self.can_swap() {
+                vec.push((self.played_piece(true), true));
             }
+
+            [vec].into_iter().collect()
         }
+    }
 
-        // Update lines
-        let mut line_count = 0;
-        for j in 0..BOARD_HEIGHT {
-            let full_line = new_board[j].iter().all(|&cell| !cell.is_empty());
+    fn push_legal_moves(&self, pieces: &[(Color, bool)], legal_moves: &mut Vec<Placement>) {
+        let bits = self.bitboard();
 
-            if full_line {
-                let new_board_copy = new_board.clone();
-                line_count += 1;
-                for y in 0..j {
-                    for x in 0..BOARD_WIDTH {
-                        let piece_type = new_board_copy[y][x];
-                        let old_piece_type = new_board_copy[y + 1][x];
+        for &(piece_color, swap) in pieces {
+            let piece_kind = &PIECES[piece_color as usize - 1];
 
-                        if !old_piece_type.is_empty() {
-                            new_hash ^= ZOBRISTS[y + 1][x];
-                        }
+            let reached = self.reachable_grid(&bits, piece_color);
 
-                        if !piece_type.is_empty() {
-                            new_hash ^= ZOBRISTS[y + 1][x];
-                        }
+            for rot in 0..piece_kind.len() {
+                let mask = piece_mask(piece_color, rot);
 
-                        new_board[y + 1][x] = piece_type;
-                    }
+                // Columns reached from which the piece cannot go down
+                let mut locks = [0u16; BOARD_HEIGHT];
+                for y in 0..self.height {
+                    locks[y] = reached[rot][y] & !bits.free_columns(mask, y + 1);
                 }
 
-                // Clear the top line
-                // TODO: Test hashing
-                for x in 0..BOARD_WIDTH {
-                    if new_board[0][x] != Color::Empty {
-                        new_board[0][x] = Color::Empty;
-                        new_hash ^= ZOBRISTS[0][x];
+                for x in 0..(self.width - mask.width + 1) {
+                    for y in (0..(self.height - mask.height() + 1)).rev() {
+                        if locks[y] >> x & 1 == 1 {
+                            legal_moves.push((piece_color, x, y, rot, swap));
+                        }
                     }
                 }
             }
         }
+    }
 
-        new_score += match line_count {
-            1 => 40,
-            2 => 100,
-            3 => 300,
-            4 => 1200,
-            _ => 0,
-        };
+    /// Sequences of placements of the current piece and the following known
+    /// ones, up to `depth` pieces long. Sequences end early on a piece that
+    /// is not known yet, and those topping out are dropped. With `beam`, only
+    /// that many sequences are kept after each piece, those leaving the
+    /// fewest holes and then the lowest stack.
+    pub fn move_sequences(&self, depth: usize, beam: Option<usize>) -> Vec<Vec<Placement>> {
+        let mut frontier = vec![(Vec::new(), self.clone())];
+
+        for _ in 0..depth {
+            let mut next = Vec::new();
+
+            for (moves, pos) in frontier {
+                if pos.current_piece == Color::Random {
+                    next.push((moves, pos));
+                    continue;
+                }
 
-        new_min_y += line_count;
+                for mv in pos.legal_moves().into_iter().next().unwrap_or_default() {
+                    let (piece, x, y, rot, swap) = mv;
+                    let child = pos.apply_move(piece, x, y, rot, swap, false);
 
-        return Position::new(
-            piece_color,
-            self.next_piece,
-            if gen_next {
-                self.sample(&mut rand::thread_rng())
-            } else {
-                Color::Random
-            },
-            new_score,
-            new_board,
-            new_hash,
-            new_min_y,
-        );
-    }
-}
+                    if !child.is_game_over() {
+                        let mut moves = moves.clone();
+                        moves.push(mv);
+                        next.push((moves, child));
+                    }
+                }
+            }
 
-impl Default for Position {
-    fn default() -> Self {
-        let board = [[Color::Empty; BOARD_WIDTH]; BOARD_HEIGHT];
+            if let Some(beam) = beam {
+                let mut scored: Vec<_> = next
+                    .into_iter()
+                    .map(|(moves, pos)| {
+                        let features = pos.features();
+                        ((features.holes, features.aggregate_height), moves, pos)
+                    })
+                    .collect();
+                scored.sort_by(|(a, _, _), (b, _, _)| a.partial_cmp(b).unwrap());
+                scored.truncate(beam);
+
+                next = scored
+                    .into_iter()
+                    .map(|(_, moves, pos)| (moves, pos))
+                    .collect();
+            }
 
-        // TODO: Fix random
-        Self {
-            last_piece: Color::Empty,
-            current_piece: Color::I,
-            next_piece: Color::J,
-            score: 0,
-            hash: hash_board(&board),
-            board,
-            min_y: BOARD_HEIGHT - 1,
+            frontier = next;
         }
-    }
-}
 
-impl Distribution<Color> for Position {
-    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Color {
-        let roll = rng.gen_range(1..9);
+        frontier
+            .into_iter()
+            .map(|(moves, _)| moves)
+            .filter(|moves| !moves.is_empty())
+            .collect()
+    }
 
-        if roll == self.next_piece as u8 || roll == 8 {
-            let reroll = rng.gen_range(1..7);
-            Color::from(reroll)
+    /// Placements reachable from the spawn with left, right, rotation and soft
+    /// drop inputs, each flagged with whether it is a tuck, i.e. the piece
+    /// could not have been dropped straight down from the top of the board.
+    /// The pieces are the same as for `legal_moves`, in a single list.
+    pub fn reachable_moves(&self) -> Vec<(Placement, bool)> {
+        let pieces: ArrayVec<(Color, bool), PIECE_COUNT> = if self.current_piece == Color::Random {
+            self.possible_pieces()
+                .iter()
+                .map(|&color| (color, false))
+                .collect()
         } else {
-            Color::from(roll)
-        }
-    }
-}
+            let mut vec = ArrayVec::new();
+            vec.push((self.current_piece, false));
+            if self.can_swap() {
+                vec.push((self.played_piece(true), true));
+            }
 
-impl fmt::Display for Position {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut empty_cells = 0;
-        for y in 0..BOARD_HEIGHT {
-            for x in 0..BOARD_WIDTH {
-                if !self.board[y][x].is_empty() && empty_cells > 0 && empty_cells < 10 {
-                    write!(f, "{}", empty_cells)?;
-                    empty_cells = 0;
-                }
+            vec
+        };
 
-                if !self.board[y][x].is_empty() {
-                    write!(f, "{}", self.board[y][x])?;
-                } else {
-                    empty_cells += 1;
+        let bits = self.bitboard();
+        let mut moves = Vec::new();
+
+        for (piece_color, swap) in pieces {
+            for (x, y, rot) in self.reachable_states(&bits, piece_color) {
+                let mask = piece_mask(piece_color, rot as usize);
+
+                if bits.collides(mask, x, y + 1) {
+                    let tuck = (0..y).any(|y| bits.collides(mask, x, y));
+                    let placement = (piece_color, x as usize, y as usize, rot as usize, swap);
+                    moves.push((placement, tuck));
                 }
             }
+        }
 
-            if empty_cells > 0 && empty_cells < 10 {
-                write!(f, "{}", empty_cells)?;
-            }
+        moves
+    }
 
-            write!(f, "/")?;
-            empty_cells = 0;
+    /// Number of empty cells connected to the top row through empty cells.
+    /// Unlike the holes of `features`, cells under an overhang that a piece
+    /// can slide into from the side are counted, and sealed ones are not.
+    pub fn reachable_empty_cells(&self) -> usize {
+        let mut reached = [[false; BOARD_WIDTH]; BOARD_HEIGHT];
+        let mut stack: Vec<(usize, usize)> = (0..self.width)
+            .filter(|&x| self.board[0][x].is_empty())
+            .map(|x| (x, 0))
+            .collect();
+        let mut count = 0;
+
+        while let Some((x, y)) = stack.pop() {
+            if reached[y][x] {
+                continue;
+            }
+            reached[y][x] = true;
+            count += 1;
+
+            let neighbors = [
+                (x.wrapping_sub(1), y),
+                (x + 1, y),
+                (x, y.wrapping_sub(1)),
+                (x, y + 1),
+            ];
+
+            for (nx, ny) in neighbors {
+                if nx < self.width
+                    && ny < self.height
+                    && !reached[ny][nx]
+                    && self.board[ny][nx].is_empty()
+                {
+                    stack.push((nx, ny));
+                }
+            }
         }
 
-        write!(
-            f,
-            " {} {} {}",
-            self.current_piece, self.next_piece, self.score
-        )?;
-
-        Ok(())
+        count
     }
-}
 
-// TODO: overflow error handling
-impl FromStr for Position {
-    type Err = ();
+    /// Same as `features`, computed once and then kept until the board, the
+    /// column heights or the lines cleared change.
+    pub fn cached_features(&mut self) -> Features {
+        let key = FeatureKey {
+            hash: self.hash,
+            signature: self.signature(),
+            width: self.width,
+            height: self.height,
+            heights: self.heights,
+            lines_cleared: self.lines_cleared,
+        };
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut board = [[Color::Empty; BOARD_WIDTH]; BOARD_HEIGHT];
-        let mut curr_x = 0;
-        let mut curr_y = 0;
+        match &self.features_cache.0 {
+            Some((cached_key, features)) if *cached_key == key => features.clone(),
+            _ => {
+                let features = self.features();
+                self.features_cache = FeatureCache(Some((key, features.clone())));
+                features
+            }
+        }
+    }
 
-        let tokens: Vec<&str> = s.split(' ').collect();
-        if tokens.len() < 3 {
-            return Err(());
+    pub fn features(&self) -> Features {
+        // Every empty cell under the top of its column, counted once, and
+        // the filled cells above it
+        let mut holes = 0;
+        let mut covered_cells = 0;
+        for x in 0..self.width {
+            let mut filled = 0;
+            for y in self.height - self.heights[x]..self.height {
+                if self.board[y][x].is_empty() {
+                    holes += 1;
+                    covered_cells += filled;
+                } else {
+                    filled += 1;
+                }
+            }
         }
 
-        let board_tok = tokens[0];
-        let curr_piece_tok = tokens[1];
-        let next_piece_tok = tokens[2];
-        let score_tok = tokens[3];
+        let heights = &self.heights[..self.width];
 
-        let mut min_y_found = false;
-        let mut min_y = 0;
+        let bumpiness = heights
+            .windows(2)
+            .map(|window| window[0].abs_diff(window[1]) as f64)
+            .sum();
 
-        for x in board_tok.chars() {
-            match x {
-                '/' => {
-                    curr_x = 0;
-                    curr_y += 1;
+        let aggregate_height = heights.iter().sum::<usize>() as f64;
+
+        // The walls count as filled cells
+        let mut row_transitions = 0;
+        for row in self.board[..self.height].iter() {
+            let mut filled = true;
+            for cell in row[..self.width].iter() {
+                if cell.is_empty() == filled {
+                    row_transitions += 1;
+                    filled = !filled;
                 }
-                '1' | '2' | '3' | '4' | '5' | '6' | '7' | '8' | '9' => {
-                    if !min_y_found {
-                        min_y = curr_y;
-                        min_y_found = true;
-                    }
-                    curr_x += (x as usize) - ('0' as usize)
+            }
+
+            if !filled {
+                row_transitions += 1;
+            }
+        }
+
+        // The floor counts as a filled cell, the top of the board as an empty one
+        let mut column_transitions = 0;
+        for x in 0..self.width {
+            let mut filled = false;
+            for y in 0..self.height {
+                if self.board[y][x].is_empty() == filled {
+                    column_transitions += 1;
+                    filled = !filled;
                 }
-                _ => {
-                    let piece = Color::try_from(x)?;
-                    board[curr_y][curr_x] = piece;
-                    curr_x += 1;
+            }
+
+            if !filled {
+                column_transitions += 1;
+            }
+        }
+
+        // A well cell is an empty cell with both neighbors filled, the walls
+        // being infinitely high. Each well of depth d adds 1 + 2 + ... + d.
+        let mut cumulative_wells = 0;
+        for x in 0..self.width {
+            let mut depth = 0;
+            for y in 0..self.height {
+                let left_filled = x == 0 || !self.board[y][x - 1].is_empty();
+                let right_filled = x == self.width - 1 || !self.board[y][x + 1].is_empty();
+
+                if self.board[y][x].is_empty() && left_filled && right_filled {
+                    depth += 1;
+                    cumulative_wells += depth;
+                } else {
+                    depth = 0;
                 }
             }
         }
 
-        let current_piece = Color::try_from(curr_piece_tok.chars().next().ok_or(())?)?;
-        let next_piece = Color::try_from(next_piece_tok.chars().next().ok_or(())?)?;
-        let score = i64::from_str(score_tok).map_err(|_| ())?;
+        Features {
+            holes: holes as f64,
+            aggregate_height,
+            bumpiness,
+            row_transitions: row_transitions as f64,
+            column_transitions: column_transitions as f64,
+            cumulative_wells: cumulative_wells as f64,
+            completed_lines: self.lines_cleared as f64,
+            covered_cells: covered_cells as f64,
+            max_height: heights.iter().copied().max().unwrap_or(0) as f64,
+        }
+    }
 
-        let hash = hash_board(&board);
-        Ok(Position::new(
-            Color::Empty,
-            current_piece,
-            next_piece,
-            score,
-            board,
-            hash,
-            min_y,
-        ))
+    /// Height of each column, the ones past `width` being 0.
+    pub fn column_heights(&self) -> [usize; BOARD_WIDTH] {
+        self.heights
     }
-}
 
-fn check_collision<T: Cell>(board: &Board<T>, piece: &Piece, x: i32, y: i32) -> bool {
-    let size_x = piece[0].len() as i32;
-    let size_y = piece.len() as i32;
+    /// Occupancy of the board, see `BitBoard`.
+    pub fn bitboard(&self) -> BitBoard {
+        BitBoard::from_board(&self.board, self.width, self.height)
+    }
 
-    if x < 0 || x > BOARD_WIDTH as i32 - size_x || y < 0 || y > BOARD_HEIGHT as i32 - size_y {
-        return true;
+    /// Cheap summary of which cells are filled, computed independently from
+    /// the Zobrist hash so that boards whose hashes collide can be told apart.
+    pub fn signature(&self) -> u64 {
+        self.board[..self.height].iter().fold(0, |signature, row| {
+            let mask = row[..self.width]
+                .iter()
+                .fold(0, |mask, cell| (mask << 1) | !cell.is_empty() as u64);
+
+            signature.rotate_left(self.width as u32) ^ mask
+        })
     }
 
-    for i in 0..size_x {
-        for j in 0..size_y {
-            if !board[(y + j) as usize][(x + i) as usize].is_empty()
-                && !piece[j as usize][i as usize].is_empty()
-            {
-                return true;
+    /// Features in the order the networks take them as inputs:
+    ///
+    /// | Index | Feature              |
+    /// |-------|----------------------|
+    /// | 0     | `holes`              |
+    /// | 1     | `bumpiness`          |
+    /// | 2     | `aggregate_height`   |
+    /// | 3     | `completed_lines`    |
+    /// | 4     | `row_transitions`    |
+    /// | 5     | `column_transitions` |
+    /// | 6     | `cumulative_wells`   |
+    /// | 7     | `covered_cells`      |
+    /// | 8     | `max_height`         |
+    /// | 9-15  | `current_piece`      |
+    /// | 16-22 | `pocket`             |
+    /// | 23-29 | `next_piece`         |
+    ///
+    /// Pieces are one-hot encoded in the order of `PIECE_ARRAY`, an unknown
+    /// piece or an empty pocket being all zeros.
+    ///
+    /// Trained genomes depend on these indices, so new features must only
+    /// ever be appended.
+    pub fn feature_vector(&self) -> Vec<f64> {
+        let features = self.features();
+
+        let mut vector = vec![
+            features.holes,
+            features.bumpiness,
+            features.aggregate_height,
+            features.completed_lines,
+            features.row_transitions,
+            features.column_transitions,
+            features.cumulative_wells,
+            features.covered_cells,
+            features.max_height,
+        ];
+
+        for piece in [Some(self.current_piece), self.pocket, Some(self.next_piece)] {
+            let mut one_hot = [0.; PIECE_COUNT];
+            if let Some(i) = piece.and_then(|piece| PIECE_ARRAY.iter().position(|&p| p == piece)) {
+                one_hot[i] = 1.;
             }
+            vector.extend(one_hot);
         }
+
+        vector
     }
 
-    false
-}
+    /// `feature_vector` with the board features brought to [0, 1] by the
+    /// `FeatureScaler` of the board's size.
+    pub fn scaled_feature_vector(&self) -> Vec<f64> {
+        let mut vector = self.feature_vector();
+        FeatureScaler::new(self.width, self.height).scale(&mut vector);
 
-fn is_lock_fast(
-    board: &Board<Color>,
-    piece: &Piece,
-    x: usize,
-    y: usize,
-    size_x: usize,
-    size_y: usize,
-) -> bool {
-    if y == BOARD_HEIGHT - size_y {
-        return !check_collision(board, piece, x as i32, y as i32);
+        vector
     }
 
-    let mut is_lock = false;
+    /// Occupancy of the board, top row first, 1 for a filled cell whatever
+    /// its piece and 0 for an empty one.
+    pub fn to_binary_grid(&self) -> Vec<Vec<u8>> {
+        self.board[..self.height]
+            .iter()
+            .map(|row| {
+                row[..self.width]
+                    .iter()
+                    .map(|&cell| (cell != Color::Empty) as u8)
+                    .collect()
+            })
+            .collect()
+    }
 
-    for j in (0..size_y).rev() {
-        for i in 0..size_x {
-            if piece[j as usize][i as usize] as u8 != 0 {
-                if board[(y + j) as usize][(x + i) as usize] as u8 != 0 {
-                    return false;
-                } else if board[(y + j + 1) as usize][(x + i) as usize] as u8 != 0 {
-                    is_lock = true;
-                }
-            }
-        }
+    /// `to_binary_grid` flattened row by row, as a `height` by `width`
+    /// tensor.
+    pub fn to_flat_bits(&self) -> Vec<f32> {
+        self.to_binary_grid()
+            .into_iter()
+            .flatten()
+            .map(f32::from)
+            .collect()
     }
 
-    is_lock
-}
+    pub fn apply_move(
+        &self,
+        piece_color: Color,
+        x: usize,
+        y: usize,
+        rot: usize,
+        swap: bool,
+        gen_next: bool,
+    ) -> Position {
+        self.apply_move_detailed(piece_color, x, y, rot, swap, gen_next)
+            .position
+    }
 
-fn wrap_rot(rot: i32, dim: i32) -> i32 {
-    (rot % dim + dim) % dim
-}
+    /// Whether the current piece, or any piece it may turn out to be, cannot
+    /// spawn.
+    pub fn is_game_over(&self) -> bool {
+        let pieces = if self.current_piece == Color::Random {
+            self.possible_pieces()
+        } else {
+            [self.current_piece].into_iter().collect()
+        };
 
-fn proximity(a: (i32, i32, i32), b: (i32, i32, i32), rot_dim: i32) -> i32 {
-    (a.0 - b.0).abs() + cmp::min(wrap_rot(a.2 - b.2, rot_dim), wrap_rot(b.2 - a.2, rot_dim))
-}
+        pieces.into_iter().any(|piece| {
+            let (x, y, rot) = self.spawn(piece);
+            self.collides(&self.board, &PIECES[piece as usize - 1][rot as usize], x, y)
+        })
+    }
 
-fn hash_board(board: &Board<Color>) -> u64 {
-    let mut hash = 0;
+    /// Level of the game, starting at 1 and going up every `LINES_PER_LEVEL`
+    /// lines.
+    pub fn level(&self) -> usize {
+        1 + self.total_lines / LINES_PER_LEVEL
+    }
 
-    for x in 0..BOARD_WIDTH {
-        for y in 0..BOARD_HEIGHT {
-            let piece = board[y][x];
-            if !piece.is_empty() {
-                hash ^= ZOBRISTS[y][x];
+    /// Pushes the stack up by `lines` rows filled but for `hole_column`, or
+    /// `None` if this pushes cells out of the top of the board or leaves no
+    /// room for the current piece to spawn.
+    pub fn add_garbage(&self, lines: usize, hole_column: usize) -> Option<Position> {
+        assert!(
+            hole_column < self.width,
+            "hole column {} is out of the board",
+            hole_column
+        );
+
+        if lines > self.height
+            || self.board[..lines]
+                .iter()
+                .any(|row| row[..self.width].iter().any(|cell| !cell.is_empty()))
+        {
+            return None;
+        }
+
+        let mut board = self.board;
+        board.copy_within(lines..self.height, 0);
+
+        for row in &mut board[self.height - lines..self.height] {
+            for (x, cell) in row[..self.width].iter_mut().enumerate() {
+                *cell = if x == hole_column {
+                    Color::Empty
+                } else {
+                    GARBAGE_COLOR
+                };
             }
         }
+
+        let pos = Position {
+            board,
+            hash: hash_board(&board),
+            heights: column_heights(&board, self.width, self.height),
+            features_cache: FeatureCache::default(),
+            min_y: self.min_y.saturating_sub(lines),
+            bag: self.bag.clone(),
+            queue: self.queue.clone(),
+            ..*self
+        };
+
+        (!pos.is_game_over()).then_some(pos)
     }
 
-    hash
-}
+    /// Left-right reflection of the position, every piece being replaced by
+    /// its `Color::mirror`, on the board as well as in the queue, bag and
+    /// pocket. Pieces drawn later from the seed are not mirrored.
+    pub fn mirror(&self) -> Position {
+        let mut board = self.board;
+        for row in &mut board[..self.height] {
+            row[..self.width].reverse();
+            for cell in &mut row[..self.width] {
+                *cell = cell.mirror();
+            }
+        }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        Position {
+            board,
+            hash: hash_board(&board),
+            heights: column_heights(&board, self.width, self.height),
+            features_cache: FeatureCache::default(),
+            last_piece: self.last_piece.mirror(),
+            current_piece: self.current_piece.mirror(),
+            next_piece: self.next_piece.mirror(),
+            pocket: self.pocket.map(Color::mirror),
+            bag: self.bag.iter().map(|piece| piece.mirror()).collect(),
+            queue: self.queue.iter().map(|piece| piece.mirror()).collect(),
+            ..*self
+        }
+    }
 
-    #[test]
-    fn test_empty_tpn() {
-        let pos = Position::default();
-        assert_eq!(
-            pos.to_string(),
-            format!(
-                "////////////////////// {} {} 0",
-                pos.current_piece, pos.next_piece
-            )
-        );
+    /// Placement on the `mirror` of this position covering the reflection
+    /// of the cells `placement` covers here. It rests on the stack as well,
+    /// but may not be reachable from the spawn.
+    pub fn mirror_placement(&self, (piece, x, y, rotation, swap): Placement) -> Placement {
+        let piece_idx = piece as usize - 1;
+        let rot_num = PIECES[piece_idx].len();
+        let width = PIECES[piece_idx][rotation][0].len();
+
+        // Shapes are tight, the reflection of rotation r is rotation -r of
+        // the mirrored piece
+        (
+            piece.mirror(),
+            self.width - x - width,
+            y,
+            (rot_num - rotation) % rot_num,
+            swap,
+        )
     }
 
-    #[test]
-    fn test_hash_empty() {
-        let pos1 = Position::default();
-        let pos2 = Position::from_str(&pos1.to_string()).unwrap();
+    /// Checked version of `apply_move`. The piece must be the one played, and
+    /// rest on the stack or the floor without overlapping it. Whether the
+    /// piece can actually get there is not checked.
+    pub fn try_apply_move(
+        &self,
+        piece_color: Color,
+        x: usize,
+        y: usize,
+        rot: usize,
+        swap: bool,
+        gen_next: bool,
+    ) -> MoveResult {
+        let Some(shape) = piece_shape(piece_color as usize, rot) else {
+            return MoveResult::Illegal;
+        };
 
-        assert!(pos1.hash == pos2.hash)
+        let right_piece = if self.current_piece == Color::Random {
+            !swap && self.possible_pieces().contains(&piece_color)
+        } else {
+            (!swap || self.can_swap()) && self.played_piece(swap) == piece_color
+        };
+
+        let (x, y) = (x as i32, y as i32);
+        if !right_piece
+            || self.collides(&self.board, shape, x, y)
+            || !self.collides(&self.board, shape, x, y + 1)
+        {
+            return MoveResult::Illegal;
+        }
+
+        let pos = self.apply_move(piece_color, x as usize, y as usize, rot, swap, gen_next);
+
+        if pos.is_game_over() {
+            MoveResult::GameOver(pos)
+        } else {
+            MoveResult::Ok(pos)
+        }
+    }
+
+    /// Plays a placement as given by `legal_moves`, or `None` if it is
+    /// illegal. Topping out is not an error, the game over position is
+    /// returned. The pieces after the known ones are left unknown, as in the
+    /// search.
+    pub fn apply(&self, (piece, x, y, rot, swap): Placement) -> Option<Position> {
+        match self.try_apply_move(piece, x, y, rot, swap, false) {
+            MoveResult::Ok(pos) | MoveResult::GameOver(pos) => Some(pos),
+            MoveResult::Illegal => None,
+        }
+    }
+
+    /// Whether the T `shape` placed at `(x, y)` is a T-spin, using the 3-corner
+    /// rule. The last action has to be a rotation, which is only certain when
+    /// the piece could not have been shifted or dropped there, so that is what
+    /// gets checked.
+    fn is_t_spin(&self, shape: &Piece, x: i32, y: i32) -> bool {
+        let immobile = self.collides(&self.board, shape, x - 1, y)
+            && self.collides(&self.board, shape, x + 1, y)
+            && self.collides(&self.board, shape, x, y - 1);
+
+        if !immobile {
+            return false;
+        }
+
+        // The center sits on the full row or column of the T
+        let (center_x, center_y) = if shape[0].len() == 3 {
+            (1, if shape[0][0].is_empty() { 1 } else { 0 })
+        } else {
+            (if shape[0][0].is_empty() { 1 } else { 0 }, 1)
+        };
+
+        // Cells outside of the board count as filled
+        let corners = [(-1, -1), (1, -1), (-1, 1), (1, 1)]
+            .iter()
+            .filter(|(dx, dy)| {
+                let corner_x = x + center_x + dx;
+                let corner_y = y + center_y + dy;
+
+                corner_x < 0
+                    || corner_y < 0
+                    || corner_x >= self.width as i32
+                    || corner_y >= self.height as i32
+                    || !self.board[corner_y as usize][corner_x as usize].is_empty()
+            })
+            .count();
+
+        corners >= 3
+    }
+
+    /// Same as `apply_move`, also reporting the cleared lines.
+    pub fn apply_move_detailed(
+        &self,
+        piece_color: Color,
+        x: usize,
+        y: usize,
+        rot: usize,
+        swap: bool,
+        gen_next: bool,
+    ) -> MoveOutcome {
+        let piece = &PIECES[piece_color as usize - 1][rot];
+        let size_x = piece[0].len();
+        let size_y = piece.len();
+
+        assert!(
+            x + size_x <= self.width && y + size_y <= self.height,
+            "{:?} at ({}, {}) in rotation {} is out of the board",
+            piece_color,
+            x,
+            y,
+            rot
+        );
+
+        let mut new_board = self.board;
+        let mut new_score = self.score;
+        let mut new_hash = self.hash;
+        let mut new_heights = self.heights;
+
+        let mut new_min_y = self.min_y.min(y);
+
+        let t_spin = piece_color == Color::T && self.is_t_spin(piece, x as i32, y as i32);
+
+        // Place the piece
+        for (cell_x, cell_y) in self.piece_cells(piece_color as usize, rot, x, y) {
+            if new_board[cell_y][cell_x].is_empty() {
+                new_board[cell_y][cell_x] = piece_color;
+                new_hash ^= zobrist_key(cell_x, cell_y, piece_color);
+                new_heights[cell_x] = new_heights[cell_x].max(self.height - cell_y);
+            }
+        }
+
+        // Update lines, rows only ever move down when clearing so the rows
+        // found full on the placed board are the ones cleared
+        let mut bits = self.bitboard();
+        bits.place(piece_mask(piece_color, rot), x, y);
+
+        let cleared_rows: Vec<usize> = bits.full_rows().to_vec();
+
+        // Move each row down past the cleared ones under it in a single pass
+        // from the bottom, then empty the rows left at the top
+        if !cleared_rows.is_empty() {
+            let mut dest = self.height;
+
+            for src in (0..self.height).rev() {
+                if cleared_rows.contains(&src) {
+                    continue;
+                }
+
+                dest -= 1;
+                if dest == src {
+                    continue;
+                }
+
+                for x in 0..self.width {
+                    let old_piece_type = new_board[dest][x];
+                    let piece_type = new_board[src][x];
+
+                    if !old_piece_type.is_empty() {
+                        new_hash ^= zobrist_key(x, dest, old_piece_type);
+                    }
+
+                    if !piece_type.is_empty() {
+                        new_hash ^= zobrist_key(x, dest, piece_type);
+                    }
+
+                    new_board[dest][x] = piece_type;
+                }
+            }
+
+            for y in 0..dest {
+                for x in 0..self.width {
+                    if !new_board[y][x].is_empty() {
+                        new_hash ^= zobrist_key(x, y, new_board[y][x]);
+                        new_board[y][x] = Color::Empty;
+                    }
+                }
+            }
+
+            // Columns lose the cleared rows under their top, and those whose
+            // top cell was cleared drop further to the next filled cell
+            for (x, height) in new_heights[..self.width].iter_mut().enumerate() {
+                let top = self.height - *height;
+                *height -= cleared_rows.iter().filter(|&&j| j >= top).count();
+
+                while *height > 0 && new_board[self.height - *height][x].is_empty() {
+                    *height -= 1;
+                }
+            }
+        }
+
+        let mut line_count = cleared_rows.len();
+        if self.gravity == Gravity::Sticky && line_count > 0 {
+            line_count += settle(&mut new_board, self.width, self.height);
+            new_hash = hash_board(&new_board);
+            new_heights = column_heights(&new_board, self.width, self.height);
+            new_min_y = (0..self.height)
+                .find(|&y| {
+                    new_board[y][..self.width]
+                        .iter()
+                        .any(|cell| !cell.is_empty())
+                })
+                .unwrap_or(self.height - 1);
+        } else {
+            new_min_y += line_count;
+        }
+
+        let level = self.level();
+        let mut score_delta = self.scoring.clear_points(line_count, t_spin, level);
+
+        let (combo, b2b) = if line_count == 0 {
+            (0, self.b2b)
+        } else if t_spin || line_count == 4 {
+            (self.combo + 1, self.b2b + 1)
+        } else {
+            (self.combo + 1, 0)
+        };
+
+        // Chained difficult clears are worth half more, and every clear in a
+        // row after the first one adds a combo bonus
+        if b2b > 1 {
+            score_delta += score_delta / 2;
+        }
+
+        if combo > 1 {
+            score_delta += 50 * (combo as i64 - 1) * self.scoring.level_factor(level);
+        }
+
+        let perfect_clear = line_count > 0
+            && new_board[..self.height]
+                .iter()
+                .all(|row| row[..self.width].iter().all(|cell| cell.is_empty()));
+
+        if perfect_clear {
+            score_delta += self.perfect_clear_bonus;
+        }
+
+        let drop_points = self.hard_drop_points
+            * y.saturating_sub(SPAWNS[piece_color as usize - 1] as usize) as i64;
+        score_delta += drop_points;
+
+        new_score += score_delta;
+
+        // The unknown current piece turning out to be this one means it was
+        // drawn from the bag
+        let mut bag = self.bag.clone();
+        if self.current_piece == Color::Random && !swap {
+            if let Some(i) = bag.iter().position(|&piece| piece == piece_color) {
+                bag.remove(i);
+            }
+        }
+
+        let mut rng = match self.seed {
+            Some(seed) => ChaCha8Rng::seed_from_u64(seed),
+            None => ChaCha8Rng::from_entropy(),
+        };
+        let mut gen_piece = || {
+            if gen_next {
+                draw_piece(&mut bag, &mut rng)
+            } else {
+                Color::Random
+            }
+        };
+
+        let mut queue = self.queue.clone();
+        let mut take_piece = |queue: &mut VecDeque<Color>| {
+            let piece = queue.pop_front();
+            piece.unwrap_or_else(&mut gen_piece)
+        };
+
+        // Holding with an empty pocket plays the next piece
+        let (current_piece, next_piece) = if swap && self.pocket.is_none() {
+            (take_piece(&mut queue), take_piece(&mut queue))
+        } else {
+            (self.next_piece, take_piece(&mut queue))
+        };
+
+        while queue.len() + 1 < self.queue_length {
+            queue.push_back(gen_piece());
+        }
+
+        let seed = match (self.seed, gen_next) {
+            (Some(_), true) => Some(rng.gen()),
+            _ => self.seed,
+        };
+
+        let position = Position {
+            last_piece: piece_color,
+            current_piece,
+            next_piece,
+            queue,
+            queue_length: self.queue_length,
+            pocket: if swap {
+                Some(self.current_piece)
+            } else {
+                self.pocket
+            },
+            bag,
+            score: new_score,
+            board: new_board,
+            width: self.width,
+            height: self.height,
+            hash: new_hash,
+            heights: new_heights,
+            features_cache: FeatureCache::default(),
+            min_y: new_min_y,
+            combo,
+            b2b,
+            perfect_clear_bonus: self.perfect_clear_bonus,
+            hard_drop_points: self.hard_drop_points,
+            scoring: self.scoring,
+            gravity: self.gravity,
+            hold_used: swap,
+            seed,
+            lines_cleared: line_count,
+            total_lines: self.total_lines + line_count,
+        };
+
+        MoveOutcome {
+            position,
+            cleared_rows,
+            lines_cleared: line_count,
+            score_delta,
+            drop_points,
+            t_spin,
+            perfect_clear,
+            attack: attack(line_count, t_spin, combo, b2b),
+            landing_height: (2 * (self.height - y) - size_y + 1) as f64 / 2.,
+        }
+    }
+}
+
+impl Default for Position {
+    fn default() -> Self {
+        let board = [[Color::Empty; BOARD_WIDTH]; BOARD_HEIGHT];
+
+        // TODO: Fix random
+        Self {
+            last_piece: Color::Empty,
+            current_piece: Color::I,
+            next_piece: Color::J,
+            pocket: None,
+            bag: [Color::O, Color::L, Color::S, Color::T, Color::Z]
+                .into_iter()
+                .collect(),
+            score: 0,
+            hash: hash_board(&board),
+            heights: [0; BOARD_WIDTH],
+            features_cache: FeatureCache::default(),
+            board,
+            width: BOARD_WIDTH,
+            height: BOARD_HEIGHT,
+            min_y: BOARD_HEIGHT - 1,
+            combo: 0,
+            b2b: 0,
+            perfect_clear_bonus: PERFECT_CLEAR_BONUS,
+            hard_drop_points: 0,
+            scoring: ScoringProfile::default(),
+            gravity: Gravity::default(),
+            hold_used: false,
+            seed: None,
+            queue: VecDeque::new(),
+            queue_length: 1,
+            lines_cleared: 0,
+            total_lines: 0,
+        }
+    }
+}
+
+// The board is already summarized by its Zobrist hash, so there is no need
+// to feed every cell to the hasher.
+impl Hash for Position {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.hash.hash(state);
+        self.current_piece.hash(state);
+        self.next_piece.hash(state);
+        self.queue.hash(state);
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut empty_cells = 0;
+        for y in 0..self.height {
+            for x in 0..self.width {
+                if !self.board[y][x].is_empty() && empty_cells > 0 {
+                    write!(f, "{}", empty_cells)?;
+                    empty_cells = 0;
+                }
+
+                if !self.board[y][x].is_empty() {
+                    write!(f, "{}", self.board[y][x])?;
+                } else {
+                    empty_cells += 1;
+                }
+            }
+
+            // Empty rows are left blank, unless the board is narrower than
+            // the default one, which a blank row would read back as
+            if empty_cells > 0 && (empty_cells < self.width || self.width != BOARD_WIDTH) {
+                write!(f, "{}", empty_cells)?;
+            }
+
+            write!(f, "/")?;
+            empty_cells = 0;
+        }
+
+        write!(
+            f,
+            " {} {} {}",
+            self.current_piece, self.next_piece, self.score
+        )?;
+
+        Ok(())
+    }
+}
+
+/// Summary line followed by the board drawn as by `to_ascii`, so that failed
+/// assertions stay readable. The alternate form `{:#?}` lists every field,
+/// see `debug_verbose`.
+impl fmt::Debug for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if f.alternate() {
+            return f
+                .debug_struct("Position")
+                .field("score", &self.score)
+                .field("last_piece", &self.last_piece)
+                .field("current_piece", &self.current_piece)
+                .field("next_piece", &self.next_piece)
+                .field("queue", &self.queue)
+                .field("queue_length", &self.queue_length)
+                .field("pocket", &self.pocket)
+                .field("bag", &self.bag)
+                .field("board", &self.board)
+                .field("width", &self.width)
+                .field("height", &self.height)
+                .field("hash", &self.hash)
+                .field("min_y", &self.min_y)
+                .field("combo", &self.combo)
+                .field("b2b", &self.b2b)
+                .field("perfect_clear_bonus", &self.perfect_clear_bonus)
+                .field("hard_drop_points", &self.hard_drop_points)
+                .field("scoring", &self.scoring)
+                .field("gravity", &self.gravity)
+                .field("hold_used", &self.hold_used)
+                .field("seed", &self.seed)
+                .field("lines_cleared", &self.lines_cleared)
+                .field("total_lines", &self.total_lines)
+                .field("heights", &self.heights)
+                .finish();
+        }
+
+        writeln!(
+            f,
+            "Position {{ score: {}, lines: {}, current: {:?}, next: {:?}, pocket: {:?} }}",
+            self.score, self.total_lines, self.current_piece, self.next_piece, self.pocket
+        )?;
+        write!(f, "{}", self.to_ascii())
+    }
+}
+
+/// The board height is the number of rows, and its width that of the
+/// non-empty rows (the default one if they are all empty).
+impl FromStr for Position {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut board = [[Color::Empty; BOARD_WIDTH]; BOARD_HEIGHT];
+        let mut curr_x = 0;
+        let mut curr_y = 0;
+        let mut width = None;
+
+        let tokens: Vec<&str> = s.split(' ').collect();
+        if tokens.len() < 4 {
+            return Err(());
+        }
+
+        let board_tok = tokens[0];
+        let curr_piece_tok = tokens[1];
+        let next_piece_tok = tokens[2];
+        let score_tok = tokens[3];
+
+        for x in board_tok.chars() {
+            match x {
+                '/' => {
+                    if curr_x > 0 {
+                        if width.is_some_and(|width| width != curr_x) {
+                            return Err(());
+                        }
+                        width = Some(curr_x);
+                    }
+
+                    curr_x = 0;
+                    curr_y += 1;
+                }
+                '1' | '2' | '3' | '4' | '5' | '6' | '7' | '8' | '9' => {
+                    curr_x += (x as usize) - ('0' as usize)
+                }
+                _ => {
+                    let piece = Color::try_from(x)?;
+                    if curr_y >= BOARD_HEIGHT || curr_x >= BOARD_WIDTH {
+                        return Err(());
+                    }
+                    board[curr_y][curr_x] = piece;
+                    curr_x += 1;
+                }
+            }
+        }
+
+        let width = width.unwrap_or(BOARD_WIDTH);
+        if curr_y == 0 || curr_y > BOARD_HEIGHT || width > BOARD_WIDTH {
+            return Err(());
+        }
+
+        let current_piece = Color::try_from(curr_piece_tok.chars().next().ok_or(())?)?;
+        let next_piece = Color::try_from(next_piece_tok.chars().next().ok_or(())?)?;
+        let score = i64::from_str(score_tok).map_err(|_| ())?;
+
+        Ok(Position::new(
+            Color::Empty,
+            current_piece,
+            next_piece,
+            score,
+            board,
+            width,
+            curr_y,
+        ))
+    }
+}
+
+fn default_perfect_clear_bonus() -> i64 {
+    PERFECT_CLEAR_BONUS
+}
+
+fn default_queue_length() -> usize {
+    1
+}
+
+fn check_collision<T: Cell>(
+    board: &Board<T>,
+    piece: &Piece,
+    x: i32,
+    y: i32,
+    width: usize,
+    height: usize,
+) -> bool {
+    let size_x = piece[0].len() as i32;
+    let size_y = piece.len() as i32;
+
+    if x < 0 || x > width as i32 - size_x || y < 0 || y > height as i32 - size_y {
+        return true;
+    }
+
+    for i in 0..size_x {
+        for j in 0..size_y {
+            if !board[(y + j) as usize][(x + i) as usize].is_empty()
+                && !piece[j as usize][i as usize].is_empty()
+            {
+                return true;
+            }
+        }
+    }
+
+    false
+}
+
+fn draw_piece<R: Rng + ?Sized>(bag: &mut ArrayVec<Color, PIECE_COUNT>, rng: &mut R) -> Color {
+    if bag.is_empty() {
+        *bag = ArrayVec::from(PIECE_ARRAY);
+    }
+
+    // Sampled as a u32, as a usize would draw differently on 32-bit targets
+    bag.remove(rng.gen_range(0..bag.len() as u32) as usize)
+}
+
+fn wrap_rot(rot: i32, dim: i32) -> i32 {
+    (rot % dim + dim) % dim
+}
+
+/// Columns of `free` connected to one of `seeds` without crossing a column
+/// that is not free, `seeds` being free.
+fn spread(seeds: u16, free: u16) -> u16 {
+    let (mut left, mut right) = (seeds, seeds);
+    let (mut left_free, mut right_free) = (free, free);
+
+    // Each step doubles the distance covered
+    for step in [1, 2, 4, 8] {
+        left |= left_free & left << step;
+        left_free &= left_free << step;
+        right |= right_free & right >> step;
+        right_free &= right_free >> step;
+    }
+
+    left | right
+}
+
+/// Translation of the top left corner when rotating `piece_idx` from `rot`,
+/// before any kick.
+fn rotation_offset(piece_idx: usize, rot: i32, clockwise: bool) -> (i32, i32) {
+    let offsets = &ROTATION_OFFSETS[piece_idx];
+
+    if clockwise {
+        offsets[rot as usize]
+    } else {
+        let (x, y) = offsets[wrap_rot(rot - 1, offsets.len() as i32) as usize];
+        (-x, -y)
+    }
+}
+
+fn proximity(a: (i32, i32, i32), b: (i32, i32, i32), rot_dim: i32) -> i32 {
+    (a.0 - b.0).abs() + cmp::min(wrap_rot(a.2 - b.2, rot_dim), wrap_rot(b.2 - a.2, rot_dim))
+}
+
+#[inline]
+fn zobrist_key(x: usize, y: usize, color: Color) -> u64 {
+    ZOBRISTS[(y * BOARD_WIDTH + x) * COLOR_NUMBER + color as usize]
+}
+
+fn hash_board(board: &Board<Color>) -> u64 {
+    let mut hash = 0;
+
+    for (y, row) in board.iter().enumerate() {
+        for (x, &piece) in row.iter().enumerate() {
+            if !piece.is_empty() {
+                hash ^= zobrist_key(x, y, piece);
+            }
+        }
+    }
+
+    hash
+}
+
+/// Garbage lines sent by a clear: none for a single, one for a double, two
+/// for a triple and four for a tetris, twice the lines for a T-spin. Chained
+/// difficult clears add one, and combos add more as they grow.
+fn attack(line_count: usize, t_spin: bool, combo: i32, b2b: i32) -> usize {
+    if line_count == 0 {
+        return 0;
+    }
+
+    let mut attack = match (t_spin, line_count) {
+        (true, _) => 2 * line_count,
+        (false, 1) => 0,
+        (false, 2) => 1,
+        (false, 3) => 2,
+        (false, _) => 4,
+    };
+
+    if b2b > 1 {
+        attack += 1;
+    }
+
+    let combo = (combo as usize).clamp(1, COMBO_ATTACK.len());
+    attack + COMBO_ATTACK[combo - 1]
+}
+
+/// Rows a piece falls by each tick at `level`, following the guideline
+/// curve and capped at the height of the board.
+pub fn gravity_cells_per_tick(level: usize) -> f64 {
+    let level = level.clamp(1, MAX_GRAVITY_LEVEL) as f64;
+    let seconds_per_row = (0.8 - (level - 1.) * 0.007).powf(level - 1.);
+
+    (1. / (seconds_per_row * TICKS_PER_SECOND)).min(BOARD_HEIGHT as f64)
+}
+
+/// Lets the groups of connected filled cells of `board` fall until they rest
+/// on the floor or another group, clearing the rows they fill, until nothing
+/// moves anymore. Returns the number of rows cleared.
+fn settle(board: &mut Board<Color>, width: usize, height: usize) -> usize {
+    let mut cleared = 0;
+
+    loop {
+        while drop_group(board, width, height) {}
+
+        let full: Vec<usize> = (0..height)
+            .filter(|&y| board[y][..width].iter().all(|cell| !cell.is_empty()))
+            .collect();
+        if full.is_empty() {
+            return cleared;
+        }
+
+        cleared += full.len();
+        let old = *board;
+        let mut dest = height;
+        for src in (0..height).rev().filter(|y| !full.contains(y)) {
+            dest -= 1;
+            board[dest][..width].copy_from_slice(&old[src][..width]);
+        }
+        for row in &mut board[..dest] {
+            row[..width].fill(Color::Empty);
+        }
+    }
+}
+
+/// Drops the first group of connected filled cells of `board` that is not
+/// resting on anything as far as it falls. Returns whether one did.
+fn drop_group(board: &mut Board<Color>, width: usize, height: usize) -> bool {
+    let mut seen = [[false; BOARD_WIDTH]; BOARD_HEIGHT];
+
+    for y in 0..height {
+        for x in 0..width {
+            if board[y][x].is_empty() || seen[y][x] {
+                continue;
+            }
+
+            let mut group = vec![(x, y)];
+            seen[y][x] = true;
+            let mut i = 0;
+            while let Some(&(cx, cy)) = group.get(i) {
+                i += 1;
+                let neighbors = [
+                    (cx.wrapping_sub(1), cy),
+                    (cx + 1, cy),
+                    (cx, cy.wrapping_sub(1)),
+                    (cx, cy + 1),
+                ];
+                for (nx, ny) in neighbors {
+                    if nx < width && ny < height && !seen[ny][nx] && !board[ny][nx].is_empty() {
+                        seen[ny][nx] = true;
+                        group.push((nx, ny));
+                    }
+                }
+            }
+
+            let fall = (1..)
+                .take_while(|&dy| {
+                    group.iter().all(|&(cx, cy)| {
+                        cy + dy < height
+                            && (board[cy + dy][cx].is_empty() || group.contains(&(cx, cy + dy)))
+                    })
+                })
+                .last();
+
+            if let Some(dy) = fall {
+                let cells: Vec<_> = group.iter().map(|&(cx, cy)| board[cy][cx]).collect();
+                for &(cx, cy) in &group {
+                    board[cy][cx] = Color::Empty;
+                }
+                for (&(cx, cy), cell) in group.iter().zip(cells) {
+                    board[cy + dy][cx] = cell;
+                }
+                return true;
+            }
+        }
+    }
+
+    false
+}
+
+/// Height of each column, computed from scratch.
+pub(crate) fn column_heights(
+    board: &Board<Color>,
+    width: usize,
+    height: usize,
+) -> [usize; BOARD_WIDTH] {
+    let mut heights = [0; BOARD_WIDTH];
+
+    for (x, column_height) in heights[..width].iter_mut().enumerate() {
+        *column_height = (0..height)
+            .find(|&y| !board[y][x].is_empty())
+            .map_or(0, |y| height - y);
+    }
+
+    heights
+}
+
+#[cfg(test)]
+mod tests {
+    use rustc_hash::FxHashSet;
+
+    use super::*;
+
+    #[test]
+    fn test_empty_tpn() {
+        let pos = Position::default();
+        assert_eq!(
+            pos.to_string(),
+            format!(
+                "////////////////////// {} {} 0",
+                pos.current_piece, pos.next_piece
+            )
+        );
+    }
+
+    #[test]
+    fn test_hash_empty() {
+        let pos1 = Position::default();
+        let pos2 = Position::from_str(&pos1.to_string()).unwrap();
+
+        assert!(pos1.hash == pos2.hash)
+    }
+
+    #[test]
+    fn test_zobrist_equal_boards() {
+        let pos1 = Position::from_str("/////////////////////IJ3ZZSS1/ T O 0").unwrap();
+        let pos2 = Position::from_str("/////////////////////IJ3ZZSS1/ L I 40").unwrap();
+
+        assert_eq!(pos1.zobrist(), pos2.zobrist());
+        assert_ne!(pos1.zobrist(), 0);
+    }
+
+    #[test]
+    fn test_zobrist_differing_cell() {
+        let pos1 = Position::from_str("/////////////////////IJ3ZZSS1/ T O 0").unwrap();
+        let pos2 = Position::from_str("/////////////////////IJ3ZZSSO/ T O 0").unwrap();
+        let pos3 = Position::from_str("/////////////////////IL3ZZSS1/ T O 0").unwrap();
+
+        assert_ne!(pos1.zobrist(), pos2.zobrist());
+        assert_ne!(pos1.zobrist(), pos3.zobrist());
+    }
+
+    #[test]
+    fn test_zobrist_incremental() {
+        let pos = Position::from_str("////////////////////2Z7/2IIIIIIII/ O I 0").unwrap();
+        let pos = pos.apply_move(Color::O, 0, 20, 0, false, false);
+
+        assert_eq!(pos.hash, pos.zobrist());
+        assert_eq!(pos.score, 40);
+    }
+
+    #[test]
+    fn test_hash_set() {
+        let mut set = FxHashSet::default();
+
+        set.insert(Position::from_str("/////////////////////IJ3ZZSS1/ T O 0").unwrap());
+        set.insert(Position::from_str("/////////////////////IJ3ZZSS1/ T O 0").unwrap());
+        assert_eq!(set.len(), 1);
+
+        set.insert(Position::from_str("/////////////////////IJ3ZZSS1/ T I 0").unwrap());
+        assert_eq!(set.len(), 2);
+    }
+
+    #[test]
+    fn test_transitions() {
+        let pos = Position::from_str("////////////////////4I5/IIII1IIIII/ T O 0").unwrap();
+        let features = pos.features();
+
+        assert_eq!(features.row_transitions, 46.);
+        assert_eq!(features.column_transitions, 12.);
+    }
+
+    #[test]
+    fn test_apply_move_every_placement() {
+        let boards = [
+            "////////////////////// T O 0",
+            "//////////////4I5/3III4/2IIIII3/1IIII1III1/IIII2IIII/IIIII1IIII/IIIIII1III/IIIIIII1II/ T O 0",
+            "/////////////////I9/I1IIIIIIII/II1IIIIIII/IIII1IIIII/IIIIIIIII1/ T O 0",
+        ];
+
+        for tpn in boards {
+            let pos = Position::from_str(tpn).unwrap();
+
+            for piece in 1..=PIECE_COUNT {
+                let color = Color::piece(piece).unwrap();
+
+                for (rot, shape) in (0..).map_while(|rot| piece_shape(piece, rot)).enumerate() {
+                    for x in 0..=pos.width - shape[0].len() {
+                        let Some(y) = (0..=pos.height - shape.len())
+                            .take_while(|&y| !pos.collides(&pos.board, shape, x as i32, y as i32))
+                            .last()
+                        else {
+                            continue;
+                        };
+
+                        // Place the piece and drop the full rows cell by cell
+                        let mut board = pos.board;
+                        for (j, row) in shape.iter().enumerate() {
+                            for (i, cell) in row.iter().enumerate() {
+                                if !cell.is_empty() {
+                                    board[y + j][x + i] = *cell;
+                                }
+                            }
+                        }
+
+                        let kept: Vec<_> = board[..pos.height]
+                            .iter()
+                            .filter(|row| row[..pos.width].iter().any(|cell| cell.is_empty()))
+                            .copied()
+                            .collect();
+                        let mut expected = [[Color::Empty; BOARD_WIDTH]; BOARD_HEIGHT];
+                        expected[pos.height - kept.len()..pos.height].copy_from_slice(&kept);
+
+                        let new_pos = pos.apply_move(color, x, y, rot, false, false);
+                        assert_eq!(new_pos.board, expected);
+                        assert_eq!(new_pos.hash, hash_board(&expected));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_incremental_heights() {
+        // The cleared row caps a hole in the first column
+        let pos = Position::from_str("///////////////////IIIIIIIII1/1I8/I9/ I O 0").unwrap();
+        assert_eq!(pos.heights, [3, 3, 3, 3, 3, 3, 3, 3, 3, 0]);
+
+        let pos = pos.apply_move(Color::I, 9, 18, 1, false, false);
+        assert_eq!(pos.heights, [1, 2, 0, 0, 0, 0, 0, 0, 0, 3]);
+
+        let mut pos = Position::with_seed(3);
+        let mut lines = 0;
+
+        // Dropping every piece as low as possible fills rows up quickly
+        for _ in 0..200 {
+            let Some(&(piece, x, y, rot, swap)) = pos.legal_moves()[0].iter().max_by_key(|mv| mv.2)
+            else {
+                break;
+            };
+
+            pos = pos.apply_move(piece, x, y, rot, swap, true);
+            lines += pos.lines_cleared;
+            assert_eq!(
+                pos.heights,
+                column_heights(&pos.board, pos.width, pos.height)
+            );
+        }
+        assert!(lines > 0);
+
+        let json = pos.to_json();
+        assert_eq!(Position::from_json(&json).unwrap().heights, pos.heights);
+    }
+
+    #[test]
+    fn test_clone() {
+        let pos = Position::from_str("////////////////////I9/IIIIII4/ T O 0").unwrap();
+        let features = pos.features();
+
+        let mut copy = pos.clone();
+        assert_eq!(copy, pos);
+
+        copy = copy.apply_move(Color::T, 6, 19, 0, false, false);
+        assert_ne!(copy, pos);
+        assert_eq!(
+            pos,
+            Position::from_str("////////////////////I9/IIIIII4/ T O 0").unwrap()
+        );
+
+        assert!(pos.features().approx_eq(&features, 0.));
+        assert!(!copy.features().approx_eq(&features, 0.5));
+        assert!(features.approx_eq(
+            &Features {
+                holes: features.holes + 1e-9,
+                ..features.clone()
+            },
+            1e-6
+        ));
+    }
+
+    #[test]
+    fn test_ascii() {
+        let ascii = "\
+            ......
+            .6....
+            666..1
+            .55..1
+            55.2.1
+            ...221
+        ";
+
+        let pos = Position::from_ascii(ascii).unwrap();
+        assert_eq!((pos.width, pos.height), (6, 6));
+        assert_eq!(pos.board[1][1], Color::T);
+        assert_eq!(pos.board[5][3], Color::O);
+        assert_eq!(Position::from_ascii(&pos.to_ascii()).unwrap(), pos);
+
+        let features = pos.features();
+        assert_eq!(features.holes, 5.);
+        assert_eq!(pos.heights[..6], [4, 5, 4, 2, 1, 4]);
+        assert_eq!(features.aggregate_height, 20.);
+        assert_eq!(features.bumpiness, 1. + 1. + 2. + 1. + 3.);
+
+        assert!(Position::from_ascii("").is_none());
+        assert!(Position::from_ascii("...\n..").is_none());
+        assert!(Position::from_ascii("..8").is_none());
+    }
+
+    #[test]
+    fn test_render_full() {
+        let mut pos = Position::from_str("////////////////////I9/IIIIII4/ T O 0").unwrap();
+        pos.queue = VecDeque::from([Color::L, Color::Z]);
+        pos.pocket = Some(Color::S);
+        pos.score = 1234;
+
+        let rendered = pos.render_full();
+        let lines: Vec<&str> = rendered.lines().collect();
+        assert_eq!(lines.len(), pos.height + 2);
+        assert_eq!(lines[0], "┌──────────┐");
+        assert_eq!(lines[1], "│          │  Current T");
+        assert_eq!(lines[2], "│          │  Hold    S");
+        assert_eq!(lines[3], "│          │  Next    O L Z");
+        assert!(rendered.contains("Score   1234"));
+        assert_eq!(lines[pos.height], "│IIIIII    │");
+    }
+
+    #[test]
+    fn test_mirror() {
+        let pos = Position::from_str("4/4/4/4/1J2/SSLI/ T Z 0").unwrap();
+        let mirrored = pos.mirror();
+        assert_eq!(mirrored.to_string(), "4/4/4/4/2L1/IJZZ/ T S 0");
+        assert_eq!(mirrored.column_heights()[..4], [1, 1, 2, 1]);
+
+        let mut rng = ChaCha8Rng::seed_from_u64(5);
+        for seed in 0..8 {
+            let mut pos = Position::with_seed(seed);
+            for _ in 0..rng.gen_range(0..12) {
+                let moves = pos.legal_moves().concat();
+                let (p, x, y, rot, swap) = moves[rng.gen_range(0..moves.len())];
+                pos = pos.apply_move(p, x, y, rot, swap, true);
+            }
+
+            let mirrored = pos.mirror();
+            assert_eq!(mirrored.mirror(), pos);
+
+            // Mirrored moves lead to the mirrored positions. They are not
+            // always legal, spawns and kicks not being symmetric.
+            for mv in pos.legal_moves().concat() {
+                let mirrored_mv = mirrored.mirror_placement(mv);
+                assert_eq!(
+                    mirrored.apply(mirrored_mv).unwrap().board,
+                    pos.apply(mv).unwrap().mirror().board
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_add_garbage() {
+        let pos = Position::from_ascii(
+            "
+            ....
+            ....
+            ....
+            ....
+            .6..
+            666.
+            ",
+        )
+        .unwrap();
+
+        let one = pos.add_garbage(1, 3).unwrap();
+        assert_eq!(one.to_ascii(), "....\n....\n....\n.6..\n666.\n777.\n");
+        assert_eq!(one.hash, one.zobrist());
+        assert_eq!(one.heights[..4], [2, 3, 2, 0]);
+
+        let two = pos.add_garbage(2, 0).unwrap();
+        assert_eq!(two.to_ascii(), "....\n....\n.6..\n666.\n.777\n.777\n");
+        assert_eq!(two.heights[..4], [3, 4, 3, 2]);
+
+        // The I no longer spawns, then the stack is pushed out of the board
+        assert!(pos.add_garbage(3, 3).is_none());
+        assert!(pos.add_garbage(5, 3).is_none());
+        assert!(pos.add_garbage(7, 3).is_none());
+    }
+
+    #[test]
+    fn test_reachable_empty_cells() {
+        let pos = Position::from_ascii(
+            "
+            ......
+            66..77
+            ...1..
+            .5.1.4
+            ",
+        )
+        .unwrap();
+
+        // The cells under the 6 can be reached from the third column, those
+        // under the 7 are sealed, the hole counter sees no difference
+        assert_eq!(pos.features().holes, 6.);
+        assert_eq!(pos.reachable_empty_cells(), 13);
+
+        assert_eq!(Position::default().reachable_empty_cells(), 220);
+    }
+
+    #[test]
+    fn test_separated_holes() {
+        let pos = Position::from_ascii(
+            "
+            ...
+            1..
+            ...
+            ...
+            1..
+            ...
+            1.1
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(pos.features().holes, 3.);
+    }
+
+    #[test]
+    fn test_covered_cells() {
+        let shallow = Position::from_ascii(
+            "
+            ...
+            ...
+            ...
+            1..
+            .11
+            ",
+        )
+        .unwrap();
+        let buried = Position::from_ascii(
+            "
+            1..
+            1..
+            1..
+            1..
+            .11
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(shallow.features().holes, buried.features().holes);
+        assert_eq!(shallow.features().covered_cells, 1.);
+        assert_eq!(buried.features().covered_cells, 4.);
+    }
+
+    #[test]
+    fn test_max_height() {
+        let pos = Position::from_ascii(
+            "
+            .....
+            ..1..
+            ..1..
+            ..1..
+            ..1..
+            22122
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(pos.column_heights()[..5], [1, 1, 5, 1, 1]);
+
+        let features = pos.features();
+        assert_eq!(features.max_height, 5.);
+        assert_eq!(features.aggregate_height, 9.);
+    }
+
+    #[test]
+    fn test_feature_vector() {
+        let pos = Position::from_str("////////////////////I9/IIIIII4/ I O 0").unwrap();
+        let pos = pos.apply_move(Color::I, 6, 21, 0, false, false);
+
+        let features = pos.features();
+        let vector = pos.feature_vector();
+
+        assert_eq!(vector.len(), 30);
+        assert_eq!(vector.len(), FEATURE_COUNT);
+        assert_eq!(&vector[..4], &[0., 1., 1., 1.]);
+        assert_eq!(
+            vector[..9],
+            [
+                features.holes,
+                features.bumpiness,
+                features.aggregate_height,
+                features.completed_lines,
+                features.row_transitions,
+                features.column_transitions,
+                features.cumulative_wells,
+                features.covered_cells,
+                features.max_height,
+            ]
+        );
+
+        // The I was played, the O is current and nothing is held
+        assert_eq!(vector[9..16], [0., 1., 0., 0., 0., 0., 0.]);
+        assert_eq!(vector[16..23], [0.; 7]);
+        assert_eq!(vector[23..30], [0.; 7]);
+
+        let pos = pos.apply_move(Color::O, 0, 19, 0, false, false);
+        let vector = pos.feature_vector();
+        assert_eq!(vector[9..16], [0.; 7]);
+
+        let pos = Position {
+            current_piece: Color::T,
+            next_piece: Color::Z,
+            pocket: Some(Color::I),
+            ..pos
+        };
+        let vector = pos.feature_vector();
+        assert_eq!(vector[9..16], [0., 0., 0., 0., 0., 1., 0.]);
+        assert_eq!(vector[16..23], [1., 0., 0., 0., 0., 0., 0.]);
+        assert_eq!(vector[23..30], [0., 0., 0., 0., 0., 0., 1.]);
+    }
+
+    #[test]
+    fn test_piece_shape() {
+        let i = piece_shape(Color::I as usize, 0).unwrap();
+        assert_eq!(i, &vec![vec![Color::I; 4]]);
+        assert_eq!(piece_shape(Color::I as usize, 1).unwrap().len(), 4);
+
+        assert!(piece_shape(Color::Z as usize, 1).is_some());
+        assert!(piece_shape(Color::O as usize, 1).is_none());
+        assert!(piece_shape(0, 0).is_none());
+        assert!(piece_shape(PIECE_COUNT + 1, 0).is_none());
+    }
+
+    #[test]
+    fn test_custom_size() {
+        let pos = Position::with_size(8, 16);
+        assert_eq!(pos.current_piece, Color::I);
+
+        let moves = &pos.legal_moves()[0];
+
+        // 5 horizontal and 8 vertical placements, plus those of the J when holding
+        assert_eq!(moves.iter().filter(|mv| !mv.4).count(), 13);
+        for &(p, x, y, rot, _) in moves {
+            let piece = piece_shape(p as usize, rot).unwrap();
+            assert!(x + piece[0].len() <= 8);
+            assert_eq!(y + piece.len(), 16);
+        }
+
+        // Blank rows keep their width, an empty board would read back ten
+        // cells wide otherwise
+        let empty = Position::from_str(&pos.to_string()).unwrap();
+        assert_eq!((empty.width, empty.height), (8, 16));
+        assert_eq!(empty.legal_moves(), pos.legal_moves());
+
+        let narrow = Position::from_str("4/4/4/4/4/4/ T O 0").unwrap();
+        assert_eq!(narrow.to_string(), "4/4/4/4/4/4/ T O 0");
+
+        let pos = pos.apply_move(Color::I, 4, 15, 0, false, false);
+        assert_eq!(pos.to_string(), format!("{}4IIII/ J ? 0", "8/".repeat(15)));
+    }
+
+    #[test]
+    fn test_landing_row() {
+        let pos = Position::default();
+
+        assert_eq!(pos.landing_row(0, 0, false), Some(21));
+        assert_eq!(pos.landing_row(9, 1, false), Some(18));
+        assert_eq!(pos.landing_row(7, 0, false), None);
+        assert_eq!(pos.landing_row(0, 2, false), None);
+        // J
+        assert_eq!(pos.landing_row(0, 1, true), Some(19));
+
+        let pos = Position::from_str("///////////////////I9/I9/III2IIIII/ T O 0").unwrap();
+
+        assert_eq!(pos.landing_row(0, 0, false), Some(18));
+        assert_eq!(pos.landing_row(1, 0, false), Some(19));
+        assert_eq!(pos.landing_row(2, 2, false), Some(19));
+        assert_eq!(pos.landing_row(3, 1, false), Some(19));
+    }
+
+    fn drop_everywhere(pos: &Position) {
+        for color in PIECE_ARRAY {
+            let pos = Position {
+                current_piece: color,
+                ..pos.clone()
+            };
+
+            for (rot, piece) in PIECES[color as usize - 1].iter().enumerate() {
+                for x in 0..=(pos.width - piece[0].len()) {
+                    if let Some(y) = pos.landing_row(x, rot, false) {
+                        pos.apply_move(color, x, y, rot, false, false);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_drop_everywhere_empty() {
+        drop_everywhere(&Position::default());
+        drop_everywhere(&Position::with_size(6, 10));
+    }
+
+    #[test]
+    fn test_drop_everywhere_nearly_full() {
+        let pos = Position::from_str(
+            "///1IIIIIIIII/I1IIIIIIII/II1IIIIIII/III1IIIIII/IIII1IIIII/IIIII1IIII/IIIIII1III/IIIIIII1II/IIIIIIII1I/IIIIIIIII1/1IIIIIIIII/I1IIIIIIII/II1IIIIIII/III1IIIIII/IIII1IIIII/IIIII1IIII/IIIIII1III/IIIIIII1II/IIIIIIII1I/ ? O 0",
+        )
+        .unwrap();
+
+        drop_everywhere(&pos);
+
+        // Move generation on a high stack goes through the exact pathfinding
+        assert!(pos.min_y < 4);
+        assert_eq!(pos.legal_moves().len(), PIECE_COUNT);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_apply_move_out_of_board() {
+        Position::with_size(8, 16).apply_move(Color::I, 5, 15, 0, false, false);
+    }
+
+    #[test]
+    fn test_swap() {
+        let pos = Position::from_str("////////////////////// S O 0").unwrap();
+
+        // The pocket is empty so the next piece gets played
+        let pos = pos.apply_move(Color::O, 0, 20, 0, true, true);
+        assert_eq!(pos.pocket, Some(Color::S));
+        assert_ne!(pos.current_piece, Color::Random);
+
+        // Holding twice in a row is not allowed
+        assert!(pos.hold_used);
+        assert!(pos.legal_moves()[0].iter().all(|mv| !mv.4));
+
+        let pos = Position {
+            current_piece: Color::T,
+            next_piece: Color::I,
+            hold_used: false,
+            ..pos
+        };
+        assert!(pos.legal_moves()[0]
+            .iter()
+            .any(|mv| mv.0 == Color::S && mv.4));
+
+        let pos = pos.apply_move(Color::S, 4, 20, 0, true, false);
+        assert_eq!(pos.pocket, Some(Color::T));
+        assert_eq!(pos.current_piece, Color::I);
+    }
+
+    #[test]
+    fn test_bag() {
+        let mut pos = Position::default();
+        let mut pieces = vec![pos.current_piece, pos.next_piece];
+
+        while pieces.len() < 3 * PIECE_COUNT {
+            let moves = pos.legal_moves();
+            let &(p, x, y, rot, swap) = moves[0]
+                .iter()
+                .filter(|mv| !mv.4)
+                .max_by_key(|mv| mv.2)
+                .unwrap();
+            pos = pos.apply_move(p, x, y, rot, swap, true);
+            pieces.push(pos.next_piece);
+        }
+
+        for bag in pieces.chunks(PIECE_COUNT) {
+            for piece in PIECE_ARRAY {
+                assert!(bag.contains(&piece));
+            }
+        }
+    }
+
+    #[test]
+    fn test_unknown_piece_from_bag() {
+        let mut pos = Position::from_str("////////////////////// ? ? 0").unwrap();
+        pos.bag = [Color::I, Color::T].into_iter().collect();
+
+        let moves = pos.legal_moves();
+        assert_eq!(moves.len(), 2);
+        assert_eq!(pos.draw_probability(Color::T), 0.5);
+        assert_eq!(pos.draw_probability(Color::O), 0.);
+
+        let (p, x, y, rot, swap) = moves[1][0];
+        let pos = pos.apply_move(p, x, y, rot, swap, false);
+        assert_eq!(pos.bag.as_slice(), &[Color::I]);
+    }
+
+    #[test]
+    fn test_wells() {
+        let pos = Position::from_str("///////////////////1IIIIIIIII/1IIIIIIIII/1IIIIIIIII/ T O 0")
+            .unwrap();
+
+        assert_eq!(pos.features().cumulative_wells, 6.);
+    }
+
+    #[test]
+    fn test_t_spin_triple() {
+        let pos =
+            Position::from_str("/////////////////II8/I9/I1IIIIIIII/I2IIIIIII/I1IIIIIIII/ T O 0")
+                .unwrap();
+
+        // Once slid under the overhang, flat side down, the T kicks two rows
+        // down into the slot
+        assert_eq!(pos.landing_row(2, 2, false), Some(17));
+        assert_eq!(pos.rotate_piece(2, 3, 1, 17), Some((1, 19)));
+        assert_eq!(pos.rotate_piece(2, 0, 1, 17), None);
+
+        assert!(pos.legal_moves()[0].contains(&(Color::T, 1, 19, 3, false)));
+
+        let path = pos.path(false, (1, 19, 3)).unwrap();
+        assert_eq!(path.last(), Some(&Action::RotateClockwise));
+
+        let pos = pos.apply_move(Color::T, 1, 19, 3, false, false);
+        assert_eq!(pos.score, 1600);
+        assert_eq!(pos.to_string(), "////////////////////II8/I9/ O ? 1600");
+    }
+
+    #[test]
+    fn test_path_tall_stack() {
+        let pos = Position::from_str(
+            "///3SS5/2SS6/3ZZ4I/4ZZ3I/4LL3I/1J3L3I/1JJJ1L2OO/OO3JJ1OO/OO1L1J2Z1/1LLL1JTZZ1/\
+             TTT2TTZ2/1T1I2TOO1/JJJI1SSOO1/2JISSLLL1/2SI2L3/2SS2I3/3S2IT2/2ZZ2ITT1/3ZZ1IT2/ T L 0",
+        )
+        .unwrap();
+
+        // No inputs from the spawn bring the T there, moves used to be
+        // generated searching back from the placement and listed it anyway
+        let unreachable = (Color::T, 3, 9, 1, false);
+        assert_eq!(pos.path(false, (3, 9, 1)), None);
+
+        let moves = &pos.legal_moves()[0];
+        assert!(!moves.is_empty());
+        assert!(!moves.contains(&unreachable));
+        for &(_, x, y, rot, swap) in moves {
+            assert!(pos.path(swap, (x as i32, y as i32, rot as i32)).is_some());
+        }
+    }
+
+    #[test]
+    fn test_reachable_grid() {
+        for tpn in [
+            "////////////////////// T L 0",
+            "/////////////////II8/I9/I1IIIIIIII/I2IIIIIII/I1IIIIIIII/ T O 0",
+            "///////////////////II8/4IIIIII/4IIIIII/ O I 0",
+            "///3SS5/2SS6/3ZZ4I/4ZZ3I/4LL3I/1J3L3I/1JJJ1L2OO/OO3JJ1OO/OO1L1J2Z1/1LLL1JTZZ1/\
+             TTT2TTZ2/1T1I2TOO1/JJJI1SSOO1/2JISSLLL1/2SI2L3/2SS2I3/3S2IT2/2ZZ2ITT1/3ZZ1IT2/ T L 0",
+        ] {
+            let pos = Position::from_str(tpn).unwrap();
+            let bits = pos.bitboard();
+
+            for piece in PIECE_ARRAY {
+                let mut reached = [[0u16; BOARD_HEIGHT]; 4];
+                for (x, y, rot) in pos.reachable_states(&bits, piece) {
+                    reached[rot as usize][y as usize] |= 1 << x;
+                }
+                assert_eq!(
+                    pos.reachable_grid(&bits, piece),
+                    reached,
+                    "{:?} on {}",
+                    piece,
+                    tpn
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_reachable_tucks() {
+        let pos = Position::from_str("///////////////////II8/4IIIIII/4IIIIII/ O I 0").unwrap();
+
+        // Dropping straight down leaves the O on top of the overhang
+        assert_eq!(pos.landing_row(0, 0, false), Some(17));
+
+        let moves = pos.reachable_moves();
+        assert!(moves.contains(&((Color::O, 0, 20, 0, false), true)));
+        assert!(moves.contains(&((Color::O, 1, 20, 0, false), true)));
+        assert!(moves.contains(&((Color::O, 2, 20, 0, false), false)));
+        assert!(moves.contains(&((Color::O, 0, 17, 0, false), false)));
+
+        let pos = pos.apply_move(Color::O, 0, 20, 0, false, false);
+        assert_eq!(pos.score, 0);
+        assert_eq!(
+            pos.to_string(),
+            "///////////////////II8/OO2IIIIII/OO2IIIIII/ I ? 0"
+        );
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let mut pos = Position::from_str("////////////////////2Z7/2IIIIIIII/ O I 0").unwrap();
+        pos.pocket = Some(Color::T);
+        pos = pos.apply_move(Color::O, 0, 20, 0, false, false);
+        pos = pos.apply_move(Color::T, 4, 20, 2, true, false);
+
+        let json = pos.to_json();
+        let loaded = Position::from_json(&json).unwrap();
+
+        assert_eq!(loaded, pos);
+        assert_eq!(loaded.pocket, Some(Color::I));
+        assert_eq!(loaded.to_json(), json);
+
+        // The hash is not trusted
+        let tampered = json.replace(&format!(r#""hash":{}"#, pos.hash), r#""hash":42"#);
+        assert_ne!(tampered, json);
+        assert_eq!(Position::from_json(&tampered).unwrap().hash, pos.hash);
+
+        assert!(Position::from_json("{}").is_err());
+    }
+
+    #[test]
+    fn test_seeded_pieces() {
+        // Fixed values, which must come out the same on every target
+        let mut pos = Position::with_seed(42);
+        let mut pieces = vec![pos.current_piece, pos.next_piece];
+        for _ in 0..12 {
+            let (p, x, y, rot, swap) = pos.legal_moves()[0][0];
+            pos = pos.apply_move(p, x, y, rot, swap, true);
+            pieces.push(pos.next_piece);
+        }
+        use Color::*;
+        assert_eq!(pieces, [O, T, I, Z, J, S, L, L, S, T, Z, J, O, I]);
+
+        let pos = Position::from_str("IIIIIIIII1/ T O 0").unwrap();
+        assert_eq!(pos.hash, 0xb6b85267c74e7170);
+    }
+
+    #[test]
+    fn test_cleared_rows() {
+        let pos = Position::from_str("///////////////////2Z7/IIIIIIII2/IIIIIIII2/ O I 0").unwrap();
+        let outcome = pos.apply_move_detailed(Color::O, 8, 20, 0, false, false);
+
+        assert_eq!(outcome.cleared_rows, vec![20, 21]);
+        assert_eq!(outcome.lines_cleared, 2);
+        assert_eq!(outcome.score_delta, 100);
+        assert_eq!(outcome.position.score, 100);
+        assert_eq!(
+            outcome.position.to_string(),
+            "/////////////////////2Z7/ I ? 100"
+        );
+    }
+
+    #[test]
+    fn test_t_spin_double() {
+        let pos = Position::from_str("///////////////////I9/3IIIIIII/I1IIIIIIII/ T O 0").unwrap();
+
+        // The overhang makes the T pointing down stuck, it had to rotate in
+        assert!(pos.legal_moves()[0].contains(&(Color::T, 0, 20, 0, false)));
+        let outcome = pos.apply_move_detailed(Color::T, 0, 20, 0, false, false);
+        assert!(outcome.t_spin);
+        assert_eq!(outcome.lines_cleared, 2);
+        assert_eq!(outcome.score_delta, 1200);
+        assert_eq!(outcome.attack, 4);
+
+        // Following a tetris it is worth one more line
+        let chained = Position {
+            b2b: 1,
+            ..pos.clone()
+        };
+        assert_eq!(
+            chained
+                .apply_move_detailed(Color::T, 0, 20, 0, false, false)
+                .attack,
+            5
+        );
+
+        // Without it the T simply drops in
+        let pos = Position::from_str("///////////////////9I/3IIIIIII/I1IIIIIIII/ T O 0").unwrap();
+        let outcome = pos.apply_move_detailed(Color::T, 0, 20, 0, false, false);
+        assert!(!outcome.t_spin);
+        assert_eq!(outcome.score_delta, 100);
+    }
+
+    const SINGLE: &str = "/////////////////////IIIIIIII2/ O I 0";
+    const TETRIS: &str = "/////////////////I9/IIIIIIIII1/IIIIIIIII1/IIIIIIIII1/IIIIIIIII1/ I O 0";
+
+    /// Keeps the score, the chains and the scoring of `pos` on a new board.
+    fn carry(pos: &Position, tpn: &str) -> Position {
+        Position {
+            score: pos.score,
+            combo: pos.combo,
+            b2b: pos.b2b,
+            total_lines: pos.total_lines,
+            scoring: pos.scoring,
+            ..Position::from_str(tpn).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_combo() {
+        let mut pos = Position::default();
+
+        for (combo, score, attack) in [(1, 40, 0), (2, 130, 0), (3, 270, 1)] {
+            let outcome = carry(&pos, SINGLE).apply_move_detailed(Color::O, 8, 20, 0, false, false);
+            pos = outcome.position;
+            assert_eq!(
+                (pos.combo, pos.score, outcome.attack),
+                (combo, score, attack)
+            );
+        }
+
+        pos = carry(&pos, SINGLE).apply_move(Color::O, 0, 19, 0, false, false);
+        assert_eq!((pos.combo, pos.score), (0, 270));
+    }
+
+    #[test]
+    fn test_back_to_back() {
+        let tetris =
+            |pos: &Position| carry(pos, TETRIS).apply_move(Color::I, 9, 18, 1, false, false);
+        let no_clear =
+            |pos: &Position| carry(pos, SINGLE).apply_move(Color::O, 0, 19, 0, false, false);
+
+        let pos = no_clear(&tetris(&Position::default()));
+        assert_eq!((pos.b2b, pos.score), (1, 1200));
+
+        let pos = no_clear(&tetris(&pos));
+        assert_eq!((pos.b2b, pos.score), (2, 3000));
+
+        // A single breaks the chain
+        let pos = carry(&pos, SINGLE).apply_move(Color::O, 8, 20, 0, false, false);
+        assert_eq!((pos.b2b, pos.score), (0, 3040));
+
+        let pos = tetris(&no_clear(&pos));
+        assert_eq!((pos.b2b, pos.score), (1, 4240));
+    }
+
+    #[test]
+    fn test_perfect_clear() {
+        let mut pos = Position::from_str("////////////////////IIIIIIII2/IIIIIIII2/ O I 0").unwrap();
+        pos.perfect_clear_bonus = 500;
+
+        let outcome = pos.apply_move_detailed(Color::O, 8, 20, 0, false, false);
+        assert!(outcome.perfect_clear);
+        assert_eq!(outcome.score_delta, 600);
+        assert_eq!(
+            outcome.position.to_string(),
+            "////////////////////// I ? 600"
+        );
+
+        // The board staying empty is not another perfect clear
+        let outcome = outcome
+            .position
+            .apply_move_detailed(Color::I, 0, 21, 0, false, false);
+        assert!(!outcome.perfect_clear);
+        assert_eq!(outcome.position.score, 600);
+    }
+
+    #[test]
+    fn test_landing_height() {
+        let pos = Position::default();
+
+        let outcome = pos.apply_move_detailed(Color::I, 3, 21, 0, false, false);
+        assert_eq!(outcome.landing_height, 1.);
+
+        let outcome = pos.apply_move_detailed(Color::I, 3, 18, 1, false, false);
+        assert_eq!(outcome.landing_height, 2.5);
+
+        // Measured on the board the piece lands on, before the clear
+        let pos = Position::from_str(TETRIS).unwrap();
+        let outcome = pos.apply_move_detailed(Color::I, 9, 18, 1, false, false);
+        assert_eq!(outcome.landing_height, 2.5);
+        let outcome = pos.apply_move_detailed(Color::I, 0, 16, 0, false, false);
+        assert_eq!(outcome.landing_height, 6.);
+    }
+
+    #[test]
+    fn test_move_sequences() {
+        let pos = Position::from_str("////////////////////I9/IIIIII4/ T O 0").unwrap();
+
+        let single = pos.move_sequences(1, None);
+        assert_eq!(single.len(), pos.legal_moves()[0].len());
+        assert!(single.iter().all(|moves| moves.len() == 1));
+
+        // The O is known, the piece after it is not, nor the one after
+        // holding the T since that plays the O
+        let sequences = pos.move_sequences(3, None);
+        assert!(sequences
+            .iter()
+            .all(|moves| moves.len() == if moves[0].4 { 1 } else { 2 }));
+        for moves in sequences.iter().filter(|moves| moves.len() == 2).take(20) {
+            let (piece, x, y, rot, swap) = moves[0];
+            let child = pos.apply_move(piece, x, y, rot, swap, false);
+            assert!(child.legal_moves()[0].contains(&moves[1]));
+        }
+
+        let beam = pos.move_sequences(2, Some(5));
+        assert_eq!(beam.len(), 5);
+        assert!(beam.iter().all(|moves| sequences.contains(moves)));
+
+        // Placing on top of the stack can top out, dropping the I down the
+        // well does not
+        let tpn = format!("///{} I O 0", "IIIIIIIII1/".repeat(19));
+        let pos = Position::from_str(&tpn).unwrap();
+        let sequences = pos.move_sequences(1, None);
+        assert!(sequences.len() < pos.legal_moves()[0].len());
+        assert!(sequences.contains(&vec![(Color::I, 9, 18, 1, false)]));
+        for mv in pos.legal_moves()[0].iter() {
+            let (piece, x, y, rot, swap) = *mv;
+            let topped_out = pos.apply_move(piece, x, y, rot, swap, false).is_game_over();
+            assert_eq!(sequences.contains(&vec![*mv]), !topped_out);
+        }
+    }
+
+    #[test]
+    fn test_piece_cells() {
+        let pos = Position::default();
+
+        assert_eq!(
+            pos.piece_cells(Color::S as usize, 1, 4, 10).as_slice(),
+            &[(4, 10), (4, 11), (5, 11), (5, 12)]
+        );
+        assert_eq!(
+            pos.piece_cells(Color::I as usize, 0, 0, 21).as_slice(),
+            &[(0, 21), (1, 21), (2, 21), (3, 21)]
+        );
+    }
+
+    #[test]
+    fn test_level() {
+        let mut pos = Position::default();
+        let mut levels = Vec::new();
+
+        for _ in 0..6 {
+            pos = carry(&pos, TETRIS).apply_move(Color::I, 9, 18, 1, false, false);
+            levels.push((pos.total_lines, pos.level()));
+        }
+
+        assert_eq!(levels, [(4, 1), (8, 1), (12, 2), (16, 2), (20, 3), (24, 3)]);
+
+        assert!((gravity_cells_per_tick(1) - 1. / 60.).abs() < 1e-9);
+        assert!(gravity_cells_per_tick(10) > gravity_cells_per_tick(9));
+        assert_eq!(gravity_cells_per_tick(30), BOARD_HEIGHT as f64);
+        // Endless games get there, past where the curve turns negative
+        assert_eq!(gravity_cells_per_tick(116), BOARD_HEIGHT as f64);
+        assert_eq!(gravity_cells_per_tick(200), BOARD_HEIGHT as f64);
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut pos = Position::from_str("////////////////////I9/IIIIII4/ T O 0").unwrap();
+        pos.pocket = Some(Color::S);
+
+        for mv in pos.legal_moves()[0].iter().copied() {
+            let (piece, x, y, rot, swap) = mv;
+            let next = pos.apply(mv).unwrap();
+            assert_eq!(next, pos.apply_move(piece, x, y, rot, swap, false));
+        }
+
+        assert!(pos.apply((Color::T, 0, 0, 0, false)).is_none());
+        assert!(pos.apply((Color::O, 8, 19, 0, false)).is_none());
+    }
+
+    #[test]
+    fn test_debug() {
+        let pos = Position::from_str("////////////////////I9/IIIIII4/ T O 40").unwrap();
+        let debug = format!("{:?}", pos);
+
+        assert_eq!(debug.lines().count(), 1 + BOARD_HEIGHT);
+        assert!(debug.starts_with("Position { score: 40, lines: 0, current: T, next: O"));
+        assert!(debug.ends_with("1.........\n111111....\n"));
+
+        let verbose = pos.debug_verbose();
+        assert!(verbose.contains("perfect_clear_bonus"));
+        assert!(verbose.lines().count() > debug.lines().count());
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut pos = Position::from_str("4/4/4/4/1I2/II1I/ T S 0").unwrap();
+        assert_eq!(pos.validate(), Ok(()));
+
+        pos.board[5][2] = Color::Random;
+        assert_eq!(
+            pos.validate(),
+            Err(PositionError::InvalidCell {
+                x: 2,
+                y: 5,
+                color: Color::Random
+            })
+        );
+
+        // A row wider than the board
+        pos.board[5][2] = Color::Empty;
+        pos.board[4][6] = Color::I;
+        assert_eq!(
+            pos.validate(),
+            Err(PositionError::CellOutsideBoard { x: 6, y: 4 })
+        );
+
+        pos.board[4][6] = Color::Empty;
+        pos.width = BOARD_WIDTH + 1;
+        assert!(matches!(
+            pos.validate(),
+            Err(PositionError::Dimensions { .. })
+        ));
+        assert!(Position::from_json(&pos.to_json()).is_err());
+
+        // Boards a piece does not fit in
+        for tpn in ["3/3/3/3/ T S 0", "/ T O 0", "4/4/4/ T O 0"] {
+            assert!(matches!(
+                Position::from_str(tpn).unwrap().validate(),
+                Err(PositionError::Dimensions { .. })
+            ));
+        }
+
+        assert!(Position::from_str("?9/ T S 0").unwrap().validate().is_err());
+    }
+
+    #[test]
+    fn test_cached_features() {
+        let mut pos = Position::from_str("////////////////////I9/IIIIII4/ T O 0").unwrap();
+        assert_eq!(Features::from(&pos), pos.features());
+        assert_eq!(pos.cached_features(), pos.features());
+        assert_eq!(pos.cached_features(), pos.features());
+
+        // The placed position starts without the cache of its parent
+        let mut next = pos.apply_move(Color::T, 6, 19, 2, false, false);
+        assert_ne!(next.features(), pos.features());
+        assert_eq!(next.cached_features(), next.features());
+
+        // Nor does editing the board by hand leave it stale
+        pos.board[0][0] = Color::I;
+        pos.heights = column_heights(&pos.board, pos.width, pos.height);
+        assert_eq!(pos.cached_features(), pos.features());
+    }
+
+    #[test]
+    fn test_binary_grid() {
+        let pos = Position::from_str("4/4/4/4/1I2/II1I/ T S 0").unwrap();
+
+        let grid = pos.to_binary_grid();
+        assert_eq!(grid.len(), pos.height);
+        assert!(grid.iter().all(|row| row.len() == pos.width));
+        assert_eq!(grid[4], [0, 1, 0, 0]);
+        assert_eq!(grid[5], [1, 1, 0, 1]);
+        assert_eq!(grid.concat().iter().sum::<u8>(), 4);
+
+        let bits = pos.to_flat_bits();
+        assert_eq!(bits.len(), pos.width * pos.height);
+        assert_eq!(bits[20..], [1., 1., 0., 1.]);
+
+        let grid = Position::default().to_binary_grid();
+        assert_eq!((grid.len(), grid[0].len()), (BOARD_HEIGHT, BOARD_WIDTH));
+    }
+
+    #[test]
+    fn test_scaled_feature_vector() {
+        let full = "IIIIIIIIII/".repeat(BOARD_HEIGHT);
+        let checkers = "I1I1I1I1I1/1I1I1I1I1I/".repeat(BOARD_HEIGHT / 2);
+        let columns = "I1I1I1I1I1/".repeat(BOARD_HEIGHT);
+        let covered = format!("{}{}", "IIIII5/".repeat(11), "5IIIII/".repeat(11));
+
+        for board in [full, checkers, columns, covered] {
+            let mut pos = Position::from_str(&format!("{} T O 0", board)).unwrap();
+            pos.lines_cleared = 4;
+
+            let scaled = pos.scaled_feature_vector();
+            assert_eq!(scaled.len(), pos.feature_vector().len());
+            assert!(
+                scaled.iter().all(|feature| (0.0..=1.0).contains(feature)),
+                "{:?} on {}",
+                scaled,
+                pos
+            );
+        }
+
+        let pos = Position::from_str(&format!("{}IIIIIIIIII/ T O 0", "/".repeat(21))).unwrap();
+        let scaled = pos.scaled_feature_vector();
+        assert_eq!(scaled[2], 10. / 220.);
+        assert_eq!(scaled[9..], pos.feature_vector()[9..]);
+    }
+
+    #[test]
+    fn test_distinct_rotations() {
+        // Flat board: one placement per column a rotation fits in, and no
+        // duplicate for symmetric rotations
+        for (piece, count) in [("O", 9), ("I", 17), ("S", 17), ("Z", 17), ("T", 34)] {
+            let pos = Position::from_str(&format!("////////////////////// {} J 0", piece)).unwrap();
+            let moves: Vec<_> = pos
+                .legal_moves()
+                .concat()
+                .into_iter()
+                .filter(|mv| !mv.4)
+                .collect();
+
+            assert_eq!(moves.len(), count, "{}", piece);
+            for (i, mv) in moves.iter().enumerate() {
+                assert!(!moves[..i].contains(mv));
+            }
+        }
+    }
+
+    #[test]
+    fn test_legal_moves_into() {
+        let mut buf = vec![(Color::Z, 0, 0, 0, false)];
+
+        for tpn in [
+            "////////////////////I9/IIIIII4/ T O 0",
+            "/////////////////II8/I9/I1IIIIIIII/I2IIIIIII/I1IIIIIIII/ T O 0",
+            "////////////////////I9/IIIIII4/ ? O 0",
+        ] {
+            let pos = Position::from_str(tpn).unwrap();
+            pos.legal_moves_into(&mut buf);
+            assert_eq!(buf, pos.legal_moves().concat());
+        }
+    }
+
+    #[test]
+    fn test_tetris() {
+        let outcome = Position::from_str(TETRIS).unwrap().apply_move_detailed(
+            Color::I,
+            9,
+            18,
+            1,
+            false,
+            false,
+        );
+
+        assert_eq!(outcome.cleared_rows, vec![18, 19, 20, 21]);
+        assert_eq!(outcome.position.score, 1200);
+        assert_eq!(outcome.attack, 4);
+        assert_eq!(
+            outcome.position.to_string(),
+            "/////////////////////I9/ O ? 1200"
+        );
+        assert_eq!(outcome.position.hash, outcome.position.zobrist());
+    }
+
+    #[test]
+    fn test_sticky_gravity() {
+        // The vertical I clears the second full row, leaving the pair of
+        // cells above it hanging over an empty column
+        for (bottom, naive, sticky, sticky_lines) in [
+            ("4", "4/4/4/II1I/3I/3I/", "4/4/4/3I/3I/II1I/", 1),
+            ("2I1", "4/4/4/II1I/3I/2II/", "4/4/4/4/3I/3I/", 2),
+        ] {
+            let tpn = format!("4/4/II2/III1/4/{}/ I O 0", bottom);
+            let pos = Position::from_str(&tpn).unwrap();
+
+            let outcome = pos.apply_move_detailed(Color::I, 3, 2, 1, false, false);
+            assert_eq!(outcome.lines_cleared, 1);
+            assert!(
+                outcome.position.to_string().starts_with(naive),
+                "{}",
+                outcome.position
+            );
+
+            let pos = Position {
+                gravity: Gravity::Sticky,
+                ..pos
+            };
+            let outcome = pos.apply_move_detailed(Color::I, 3, 2, 1, false, false);
+            let end = &outcome.position;
+            assert_eq!(outcome.lines_cleared, sticky_lines);
+            // Only the row filled by the I, the others fill as groups fall
+            assert_eq!(outcome.cleared_rows, [3]);
+            assert!(end.to_string().starts_with(sticky), "{}", end);
+            assert_eq!(end.hash, end.zobrist());
+            assert_eq!(
+                end.heights,
+                column_heights(&end.board, end.width, end.height)
+            );
+        }
+    }
+
+    #[test]
+    fn test_scoring_profiles() {
+        let tetris = |scoring, total_lines| {
+            let pos = Position {
+                total_lines,
+                ..carry(&Position::with_scoring(scoring), TETRIS)
+            };
+            pos.apply_move_detailed(Color::I, 9, 18, 1, false, false)
+                .score_delta
+        };
+
+        assert_eq!(tetris(ScoringProfile::Nes, 0), 1200);
+        assert_eq!(tetris(ScoringProfile::Nes, 20), 1200);
+        assert_eq!(tetris(ScoringProfile::Guideline, 0), 800);
+        assert_eq!(tetris(ScoringProfile::Guideline, 20), 2400);
+        assert_eq!(tetris(ScoringProfile::Custom([1, 2, 3, 4]), 0), 4);
+
+        assert_eq!(ScoringProfile::Guideline.clear_points(1, false, 2), 200);
+        assert_eq!(ScoringProfile::Guideline.clear_points(2, true, 1), 1200);
+        assert_eq!(ScoringProfile::Nes.clear_points(0, false, 5), 0);
+
+        // Older states score as before
+        let json = Position::default().to_json();
+        assert!(json.contains(r#","scoring":"Nes""#));
+        let pos = Position::from_json(&json.replace(r#","scoring":"Nes""#, "")).unwrap();
+        assert_eq!(pos.scoring, ScoringProfile::Nes);
+    }
+
+    #[test]
+    fn test_try_apply_move() {
+        let pos = Position::from_str("/////////////////////IIIIIIII2/ O I 0").unwrap();
+
+        // Floating, overlapping, out of the board and wrong piece
+        assert!(matches!(
+            pos.try_apply_move(Color::O, 8, 19, 0, false, false),
+            MoveResult::Illegal
+        ));
+        assert!(matches!(
+            pos.try_apply_move(Color::O, 7, 20, 0, false, false),
+            MoveResult::Illegal
+        ));
+        assert!(matches!(
+            pos.try_apply_move(Color::O, 9, 20, 0, false, false),
+            MoveResult::Illegal
+        ));
+        assert!(matches!(
+            pos.try_apply_move(Color::O, 8, 20, 1, false, false),
+            MoveResult::Illegal
+        ));
+        assert!(matches!(
+            pos.try_apply_move(Color::T, 8, 20, 0, false, false),
+            MoveResult::Illegal
+        ));
+
+        let MoveResult::Ok(pos) = pos.try_apply_move(Color::O, 8, 20, 0, false, false) else {
+            panic!()
+        };
+        assert_eq!(pos.score, 40);
+        assert!(!pos.is_game_over());
+
+        // Stacking up to the spawn row of the I tops out
+        let pos = Position::from_str("//4I5/4I5/4I5/4I5/4I5/4I5/4I5/4I5/4I5/4I5/4I5/4I5/4I5/4I5/4I5/4I5/4I5/4I5/4I5/4I5/ O I 0").unwrap();
+        assert!(!pos.is_game_over());
+        let MoveResult::GameOver(pos) = pos.try_apply_move(Color::O, 4, 0, 0, false, false) else {
+            panic!()
+        };
+        assert!(pos.is_game_over());
+    }
+
+    #[test]
+    fn test_score_consistency() {
+        for hard_drop_points in [0, 2] {
+            let mut pos = Position::with_seed(11);
+            pos.hard_drop_points = hard_drop_points;
+            let (mut lines, mut score, mut drop_points) = (0, 0, 0);
+
+            for _ in 0..100 {
+                if pos.is_game_over() {
+                    break;
+                }
+
+                // Greedy play keeping the stack low, so that lines get cleared
+                let (p, x, y, rot, swap) = pos
+                    .legal_moves()
+                    .concat()
+                    .into_iter()
+                    .min_by_key(|&(p, x, y, rot, swap)| {
+                        let features = pos.apply_move(p, x, y, rot, swap, false).features();
+                        (4. * features.holes + features.aggregate_height) as i64
+                    })
+                    .unwrap();
+
+                let outcome = pos.apply_move_detailed(p, x, y, rot, swap, true);
+                lines += outcome.lines_cleared;
+                score += outcome.score_delta;
+                drop_points += outcome.drop_points;
+                pos = outcome.position;
+            }
+
+            assert!(lines > 0);
+            assert_eq!(pos.total_lines, lines);
+            assert_eq!(pos.score, score);
+            assert_eq!(drop_points > 0, hard_drop_points > 0);
+        }
+    }
+
+    #[test]
+    fn test_spawn_overlap() {
+        let rows = "/".repeat(BOARD_HEIGHT - 2);
+
+        // Only the cells of the spawning piece matter, not how high the stack is
+        let edges = Position::from_str(&format!("I8I/I8I/{} T O 0", rows)).unwrap();
+        assert!(!edges.is_game_over());
+
+        // T spawns over (3, 0) to (5, 0) and (4, 1), I over (3, 1) to (6, 1)
+        let board = format!("/3I6/{}", rows);
+        let t = Position::from_str(&format!("{} T O 0", board)).unwrap();
+        assert!(!t.is_game_over());
+        let i = Position::from_str(&format!("{} I O 0", board)).unwrap();
+        assert!(i.is_game_over());
+
+        // An unknown piece ends the game if any piece it may be cannot spawn
+        let random = Position::from_str(&format!("{} ? O 0", board)).unwrap();
+        assert!(random.is_game_over());
+    }
+
+    #[test]
+    fn test_hold_used() {
+        let mut pos = Position::from_str("////////////////////// S O 0").unwrap();
+        pos.pocket = Some(Color::I);
+
+        let pos = pos.apply_move(Color::I, 0, 21, 0, true, false);
+        assert!(pos.hold_used);
+        assert_eq!(pos.pocket, Some(Color::S));
+
+        let pos = Position {
+            next_piece: Color::T,
+            ..pos
+        };
+        assert!(pos.legal_moves()[0].iter().all(|mv| !mv.4));
+        assert!(pos.reachable_moves().iter().all(|(mv, _)| !mv.4));
+        assert!(matches!(
+            pos.try_apply_move(Color::S, 4, 19, 0, true, false),
+            MoveResult::Illegal
+        ));
+
+        let pos = pos.apply_move(Color::O, 4, 20, 0, false, false);
+        assert!(!pos.hold_used);
+
+        let pos = Position {
+            current_piece: Color::T,
+            next_piece: Color::Z,
+            ..pos
+        };
+        assert!(pos.legal_moves()[0]
+            .iter()
+            .any(|mv| mv.0 == Color::S && mv.4));
+    }
+
+    fn play_seeded(seed: u64) -> Vec<Position> {
+        let mut pos = Position::with_seed(seed);
+        let mut history = Vec::new();
+
+        for _ in 0..50 {
+            let moves = pos.legal_moves();
+            let Some(&(p, x, y, rot, swap)) =
+                moves[0].iter().filter(|mv| !mv.4).max_by_key(|mv| mv.2)
+            else {
+                break;
+            };
+
+            let next_pos = pos.apply_move(p, x, y, rot, swap, true);
+            history.push(pos);
+            pos = next_pos;
+        }
+
+        history
+    }
+
+    #[test]
+    fn test_seed() {
+        let history = play_seeded(42);
+
+        assert_eq!(history.len(), 50);
+        assert_eq!(history, play_seeded(42));
+        let pieces = |history: &[Position]| -> Vec<Color> {
+            history.iter().map(|pos| pos.current_piece).collect()
+        };
+        assert_ne!(pieces(&history), pieces(&play_seeded(43)));
+    }
+
+    #[test]
+    fn test_bag_distribution() {
+        let mut pos = Position::with_seed(7);
+        let mut drawn = vec![pos.current_piece, pos.next_piece];
+
+        let mut i = 0;
+        while drawn.len() < 1000 * PIECE_COUNT {
+            // Holding with an empty pocket draws two pieces at once
+            let swap = i % 3 == 0;
+            let piece = pos.played_piece(swap);
+            let next_pos = pos.apply_move(piece, 0, 0, 0, swap, true);
+
+            if swap {
+                drawn.push(next_pos.current_piece);
+            }
+            drawn.push(next_pos.next_piece);
+
+            pos = Position {
+                current_piece: next_pos.current_piece,
+                next_piece: next_pos.next_piece,
+                bag: next_pos.bag,
+                seed: next_pos.seed,
+                ..Position::default()
+            };
+            i += 1;
+        }
+
+        drawn.truncate(1000 * PIECE_COUNT);
+
+        for bag in drawn.chunks(PIECE_COUNT) {
+            for piece in PIECE_ARRAY {
+                assert!(bag.contains(&piece));
+            }
+        }
+
+        for piece in PIECE_ARRAY {
+            let count = drawn.iter().filter(|&&p| p == piece).count();
+            let share = count as f64 / drawn.len() as f64;
+            assert!((share - 1. / PIECE_COUNT as f64).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn test_swap_first_move_bag() {
+        for seed in 0..20 {
+            let pos = Position::with_seed(seed);
+            let mut drawn = vec![pos.current_piece, pos.next_piece];
+
+            // Holding with an empty pocket plays the next piece and draws two
+            let (p, x, y, rot, swap) = pos.legal_moves()[0]
+                .iter()
+                .copied()
+                .find(|mv| mv.4)
+                .unwrap();
+            let mut pos = pos.apply_move(p, x, y, rot, swap, true);
+            drawn.extend([pos.current_piece, pos.next_piece]);
+
+            while drawn.len() < 2 * PIECE_COUNT {
+                let (p, x, y, rot, swap) = pos.legal_moves()[0][0];
+                pos = pos.apply_move(p, x, y, rot, swap, true);
+                drawn.push(pos.next_piece);
+            }
+
+            for bag in drawn.chunks(PIECE_COUNT) {
+                let mut bag = bag.to_vec();
+                bag.sort_by_key(|&piece| piece as u8);
+                bag.dedup();
+                assert_eq!(bag.len(), PIECE_COUNT, "seed {}", seed);
+            }
+        }
+    }
+
+    #[test]
+    fn test_queue_length() {
+        let mut pos = Position::with_queue_length(6);
+
+        for i in 0..10 {
+            assert_eq!(pos.queue.len(), 5);
+            assert!(!pos.queue.contains(&Color::Random));
+
+            let previews: Vec<Color> = [pos.next_piece]
+                .into_iter()
+                .chain(pos.queue.clone())
+                .collect();
+
+            // Holding with an empty pocket plays the next piece
+            let swap = i == 3 && pos.next_piece != pos.current_piece;
+            let &(p, x, y, rot, swap) = pos.legal_moves()[0]
+                .iter()
+                .filter(|mv| mv.4 == swap)
+                .max_by_key(|mv| mv.2)
+                .unwrap();
+            pos = pos.apply_move(p, x, y, rot, swap, true);
+
+            let shift = if swap { 2 } else { 1 };
+            assert_eq!(pos.current_piece, previews[shift - 1]);
+            assert_eq!(pos.next_piece, previews[shift]);
+            assert!(pos
+                .queue
+                .iter()
+                .zip(&previews[shift + 1..])
+                .all(|(a, b)| a == b));
+        }
+
+        // Pieces the search does not know about are left unknown
+        let pos = pos.apply_move(Color::I, 0, 0, 0, false, false);
+        assert_eq!(pos.queue.len(), 5);
+        assert_eq!(pos.queue.back(), Some(&Color::Random));
+        assert_ne!(pos.next_piece, Color::Random);
     }
 }
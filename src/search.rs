@@ -1,98 +1,1033 @@
+use std::{
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use log::{debug, trace};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use rustc_hash::FxHasher;
+
 use crate::{
     net::FeedForwardNetwork,
-    pos::{Action, Position},
+    pos::{Action, Color, Placement, Position, BOARD_WIDTH, FEATURE_COUNT},
     transposition::TranspositionTable,
 };
 
 const MAX_DEPTH: usize = 3;
 
+/// Deepest iteration of a timed search, whatever the budget
+const MAX_TIMED_DEPTH: usize = 8;
+
+/// Evaluation of positions where the next piece cannot spawn
+const GAME_OVER_SCORE: f64 = -1e9;
+
+/// Largest number of rotations of a piece
+const MAX_ROTATIONS: usize = 4;
+
+/// Outputs of a `MovePolicy` network, one per column, rotation and hold,
+/// see `move_index`
+pub const MOVE_SPACE: usize = 2 * MAX_ROTATIONS * BOARD_WIDTH;
+
+/// How the outputs of the network are read by `Search`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvalMode {
+    /// A single output scoring the positions the moves lead to, which the
+    /// search maximizes
+    BoardValue,
+    /// `MOVE_SPACE` outputs scoring the moves of the current position, the
+    /// best legal one being played without lookahead
+    MovePolicy,
+}
+
+impl EvalMode {
+    /// Mode matching the number of outputs of `net`.
+    pub fn for_network(net: &FeedForwardNetwork) -> EvalMode {
+        if net.num_outputs() == MOVE_SPACE {
+            EvalMode::MovePolicy
+        } else {
+            EvalMode::BoardValue
+        }
+    }
+}
+
+/// Output of a `MovePolicy` network scoring `placement`. Placements only
+/// differing by their row share it.
+pub fn move_index((_, x, _, rotation, swap): Placement) -> usize {
+    (swap as usize * MAX_ROTATIONS + rotation) * BOARD_WIDTH + x
+}
+
+/// Default weights of `LinearEvaluator`, indexed like
+/// `Position::feature_vector`. They follow Dellacherie's evaluation, the
+/// landing height and eroded cells being replaced by the completed lines.
+pub const DEFAULT_WEIGHTS: [f64; 9] = [-4., 0., 0., 1., -1., -1., -1., 0., 0.];
+
+/// Scores positions for `Search`. Evaluators of different kinds can be
+/// mixed as `Box<dyn Evaluator>`.
+pub trait Evaluator: BoxClone + Send + Sync {
+    /// Value of `pos`, which the search maximizes in `EvalMode::BoardValue`.
+    fn score(&mut self, pos: &Position) -> f64;
+
+    /// Every output for `pos`, which `EvalMode::MovePolicy` reads. Only
+    /// the score by default.
+    fn evaluate(&mut self, pos: &Position) -> Vec<f64> {
+        vec![self.score(pos)]
+    }
+
+    /// Length of the outputs of `evaluate`.
+    fn num_outputs(&self) -> usize {
+        1
+    }
+}
+
+/// Copy of an evaluator behind a box, implemented for every `Evaluator`
+/// that is `Clone`.
+pub trait BoxClone {
+    fn box_clone(&self) -> Box<dyn Evaluator>;
+}
+
+impl<E: Evaluator + Clone + 'static> BoxClone for E {
+    fn box_clone(&self) -> Box<dyn Evaluator> {
+        Box::new(self.clone())
+    }
+}
+
+impl Clone for Box<dyn Evaluator> {
+    fn clone(&self) -> Self {
+        (**self).box_clone()
+    }
+}
+
+impl Evaluator for Box<dyn Evaluator> {
+    fn score(&mut self, pos: &Position) -> f64 {
+        (**self).score(pos)
+    }
+
+    fn evaluate(&mut self, pos: &Position) -> Vec<f64> {
+        (**self).evaluate(pos)
+    }
+
+    fn num_outputs(&self) -> usize {
+        (**self).num_outputs()
+    }
+}
+
+impl Evaluator for FeedForwardNetwork {
+    fn score(&mut self, pos: &Position) -> f64 {
+        self.evaluate(pos)[0]
+    }
+
+    fn evaluate(&mut self, pos: &Position) -> Vec<f64> {
+        let inputs = network_inputs(self, pos);
+        self.activate(inputs)
+    }
+
+    fn num_outputs(&self) -> usize {
+        FeedForwardNetwork::num_outputs(self)
+    }
+}
+
+/// Leading entries of the feature vector `net` takes, one per input of the
+/// network, scaled or not.
+fn network_inputs(net: &FeedForwardNetwork, pos: &Position) -> Vec<f64> {
+    let mut inputs = if net.scaled_inputs() {
+        pos.scaled_feature_vector()
+    } else {
+        pos.feature_vector()
+    };
+    inputs.truncate(net.num_inputs());
+
+    inputs
+}
+
+/// Checks that `net` takes no more inputs than there are features to feed
+/// it, see `network_inputs`.
+pub fn check_network_inputs(net: &FeedForwardNetwork) -> Result<(), String> {
+    if net.num_inputs() > FEATURE_COUNT {
+        return Err(format!(
+            "Expected at most {} inputs, got {}",
+            FEATURE_COUNT,
+            net.num_inputs()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Weighted sum of the features, to play without a network.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinearEvaluator {
+    /// Weights of the leading entries of `Position::feature_vector`
+    pub weights: Vec<f64>,
+}
+
+impl Default for LinearEvaluator {
+    fn default() -> Self {
+        LinearEvaluator {
+            weights: DEFAULT_WEIGHTS.to_vec(),
+        }
+    }
+}
+
+impl Evaluator for LinearEvaluator {
+    fn score(&mut self, pos: &Position) -> f64 {
+        linear_eval(&pos.feature_vector(), &self.weights)
+    }
+}
+
+/// How `Ensemble` combines the outputs of its members.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EnsembleMode {
+    #[default]
+    Mean,
+    /// Mean of the two middle values for an even number of members
+    Median,
+    Max,
+}
+
+impl EnsembleMode {
+    fn combine(self, mut values: Vec<f64>) -> f64 {
+        match self {
+            EnsembleMode::Mean => values.iter().sum::<f64>() / values.len() as f64,
+            EnsembleMode::Median => {
+                values.sort_by(f64::total_cmp);
+                let mid = values.len() / 2;
+                if values.len().is_multiple_of(2) {
+                    (values[mid - 1] + values[mid]) / 2.
+                } else {
+                    values[mid]
+                }
+            }
+            EnsembleMode::Max => values.into_iter().fold(f64::NEG_INFINITY, f64::max),
+        }
+    }
+}
+
+/// Several evaluators voting on each position, networks and linear ones
+/// alike. Outputs are combined one by one according to `mode`, so the
+/// members must have as many of them. Without members, the ensemble plays
+/// like `LinearEvaluator::default`.
+#[derive(Clone)]
+pub struct Ensemble {
+    pub nets: Vec<Box<dyn Evaluator>>,
+    pub mode: EnsembleMode,
+}
+
+impl Default for Ensemble {
+    fn default() -> Self {
+        Ensemble::new(Vec::new(), EnsembleMode::default())
+    }
+}
+
+impl Ensemble {
+    pub fn new(nets: Vec<Box<dyn Evaluator>>, mode: EnsembleMode) -> Self {
+        Ensemble { nets, mode }
+    }
+}
+
+impl Evaluator for Ensemble {
+    fn score(&mut self, pos: &Position) -> f64 {
+        if self.nets.is_empty() {
+            return LinearEvaluator::default().score(pos);
+        }
+
+        let scores = self.nets.iter_mut().map(|net| net.score(pos)).collect();
+        self.mode.combine(scores)
+    }
+
+    fn evaluate(&mut self, pos: &Position) -> Vec<f64> {
+        if self.nets.is_empty() {
+            return LinearEvaluator::default().evaluate(pos);
+        }
+
+        let outputs: Vec<_> = self.nets.iter_mut().map(|net| net.evaluate(pos)).collect();
+        // Members are meant to have as many outputs, only the ones they all
+        // have are combined otherwise
+        let len = outputs.iter().map(Vec::len).min().unwrap_or(0);
+
+        (0..len)
+            .map(|i| {
+                self.mode
+                    .combine(outputs.iter().map(|output| output[i]).collect())
+            })
+            .collect()
+    }
+
+    fn num_outputs(&self) -> usize {
+        self.nets
+            .iter()
+            .map(|net| net.num_outputs())
+            .min()
+            .unwrap_or(1)
+    }
+}
+
+/// Sum of `features` weighted by `weights`, features without a weight
+/// being ignored.
+pub fn linear_eval(features: &[f64], weights: &[f64]) -> f64 {
+    features
+        .iter()
+        .zip(weights)
+        .map(|(feature, weight)| feature * weight)
+        .sum()
+}
+
+/// Line of play kept in the beam: its first placement, the position it
+/// leads to and that position's evaluation.
+type BeamNode = (Placement, Position, f64);
+
+/// Looks up to `depth` placements ahead, only expanding the `width` best
+/// positions of each ply according to `net`. The lookahead stops early once
+/// the piece to play is not known anymore. Returns the first placement of
+/// the best line found.
+pub fn beam_search(
+    net: &FeedForwardNetwork,
+    pos: &Position,
+    depth: usize,
+    width: usize,
+) -> Option<Placement> {
+    let roots: Vec<_> = current_moves(pos)
+        .into_iter()
+        .map(|mv| (mv, play(pos, mv)))
+        .collect();
+    let mut beam = select(net, Vec::new(), roots, width);
+
+    for _ in 1..depth {
+        // Lines that cannot be extended keep competing with their last evaluation
+        let (open, mut settled): (Vec<_>, Vec<_>) =
+            beam.into_iter().partition(|(_, pos, score)| {
+                *score > GAME_OVER_SCORE && pos.current_piece != Color::Random
+            });
+
+        if open.is_empty() {
+            beam = settled;
+            break;
+        }
+
+        let mut children = Vec::new();
+        for (first_move, pos, score) in open {
+            let moves = current_moves(&pos);
+
+            if moves.is_empty() {
+                settled.push((first_move, pos, score));
+            } else {
+                children.extend(moves.into_iter().map(|mv| (first_move, play(&pos, mv))));
+            }
+        }
+
+        beam = select(net, settled, children, width);
+    }
+
+    beam.first().map(|&(first_move, _, _)| first_move)
+}
+
+/// Averages, for each legal placement, the evaluation reached by `rollouts`
+/// games played `depth` pieces further. Pieces are drawn from the position's
+/// seed, or from a random one if it has none, each rollout using the same
+/// pieces for every placement. The network plays the rollouts greedily.
+/// Returns the placement with the best average.
+pub fn monte_carlo_move(
+    net: &FeedForwardNetwork,
+    pos: &Position,
+    rollouts: usize,
+    depth: usize,
+) -> Option<Placement> {
+    let mut rng = match pos.seed {
+        Some(seed) => ChaCha8Rng::seed_from_u64(seed),
+        None => ChaCha8Rng::from_entropy(),
+    };
+    let seeds: Vec<u64> = (0..rollouts.max(1)).map(|_| rng.gen()).collect();
+
+    let mut maxscore = -f64::INFINITY;
+    let mut best_move = None;
+
+    for mv in current_moves(pos) {
+        let total: f64 = seeds
+            .iter()
+            .map(|&seed| {
+                let pos = Position {
+                    seed: Some(seed),
+                    ..pos.clone()
+                };
+                rollout(net, &pos, mv, depth)
+            })
+            .sum();
+        let score = total / seeds.len() as f64;
+
+        if score > maxscore {
+            maxscore = score;
+            best_move = Some(mv);
+        }
+    }
+
+    best_move
+}
+
+/// Evaluation of the position reached by playing `mv` then `depth` more
+/// pieces, each one at the placement `net` prefers.
+fn rollout(net: &FeedForwardNetwork, pos: &Position, mv: Placement, depth: usize) -> f64 {
+    let (p, x, y, rot, swap) = mv;
+    let mut pos = pos.apply_move(p, x, y, rot, swap, true);
+
+    for _ in 0..depth {
+        if pos.is_game_over() {
+            break;
+        }
+
+        let children: Vec<Position> = current_moves(&pos)
+            .into_iter()
+            .map(|(p, x, y, rot, swap)| pos.apply_move(p, x, y, rot, swap, true))
+            .collect();
+        let scores = evaluate_batch(net, children.iter());
+
+        let mut maxscore = -f64::INFINITY;
+        let mut best_child = None;
+
+        for (child, score) in children.into_iter().zip(scores) {
+            if score > maxscore {
+                maxscore = score;
+                best_child = Some(child);
+            }
+        }
+
+        match best_child {
+            Some(child) => pos = child,
+            None => break,
+        }
+    }
+
+    evaluate_batch(net, [&pos].into_iter())[0]
+}
+
+/// Line of play `Search` expects when looking `depth` placements ahead,
+/// each placement with the position it leads to. The line stops early once
+/// the piece to play is not known anymore, as the best reply then depends on
+/// the piece drawn. Only the first placement may hold, like in the search.
+pub fn principal_variation<E: Evaluator>(
+    eval: &mut E,
+    pos: &Position,
+    depth: usize,
+) -> Vec<(Placement, Position)> {
+    let mut search = Search::new();
+    let mut line: Vec<(Placement, Position)> = Vec::new();
+
+    for ply in 0..depth {
+        let pos = line.last().map_or(pos, |(_, pos)| pos);
+        if pos.current_piece == Color::Random {
+            break;
+        }
+
+        let mut moves = current_moves(pos);
+        if ply > 0 {
+            moves.retain(|mv| !mv.4);
+        }
+
+        match search.search_moves(eval, depth - ply, pos, moves) {
+            Some((mv, _)) => line.push((mv, play(pos, mv))),
+            None => break,
+        }
+    }
+
+    line
+}
+
+/// Placement to play when the opponent answers each placement with
+/// `garbage` lines, their hole in whichever column is worst for us, looking
+/// `depth` placements ahead with alpha-beta pruning. Garbage that tops out
+/// the board scores as a loss. The lookahead stops early once the piece to
+/// play is not known anymore, and only the first placement may hold.
+///
+/// With `ordered`, placements are tried from the best to the worst for
+/// `LinearEvaluator::default`, which prunes more of the tree the closer it
+/// agrees with `eval`. The placement found is the same either way.
+pub fn minimax_vs<E: Evaluator>(
+    eval: &mut E,
+    pos: &Position,
+    depth: usize,
+    garbage: usize,
+    ordered: bool,
+) -> Option<Placement> {
+    let mut minimax = Minimax {
+        eval,
+        garbage,
+        ordered,
+        moves: Vec::new(),
+        children: Vec::new(),
+    };
+    let mut best = None;
+
+    let children = minimax.children(pos, depth, true);
+    for (_, mv, child) in &children {
+        // A move scoring at least the best one gets its exact score, which
+        // `beats` then breaks ties on, and a worse one gets less
+        let alpha = best.map_or(-f64::INFINITY, |(_, maxscore)| maxscore);
+        let score = minimax.garbage_reply(child, depth, alpha, f64::INFINITY);
+        if beats((*mv, score), best) {
+            best = Some((*mv, score));
+        }
+    }
+
+    best.map(|(mv, _)| mv)
+}
+
+/// Search of `minimax_vs`, with the buffers its nodes reuse.
+struct Minimax<'a, E> {
+    eval: &'a mut E,
+    garbage: usize,
+    ordered: bool,
+    /// Moves of the node being expanded
+    moves: Vec<Placement>,
+    /// Children of the node being searched at each depth, with their
+    /// heuristic score
+    children: Vec<Vec<(f64, Placement, Position)>>,
+}
+
+impl<E: Evaluator> Minimax<'_, E> {
+    /// Placements of `pos`, holding ones only with `hold`, with the
+    /// positions they lead to. They are sorted from the best to the worst
+    /// for `LinearEvaluator::default` when ordered, and left in the order
+    /// they are generated in otherwise. The list must be given back with
+    /// `restore` for the next node at `depth` to reuse.
+    fn children(
+        &mut self,
+        pos: &Position,
+        depth: usize,
+        hold: bool,
+    ) -> Vec<(f64, Placement, Position)> {
+        if self.children.len() <= depth {
+            self.children.resize_with(depth + 1, Vec::new);
+        }
+        let mut children = std::mem::take(&mut self.children[depth]);
+        children.clear();
+
+        current_moves_into(pos, &mut self.moves);
+        for &mv in self.moves.iter().filter(|mv| hold || !mv.4) {
+            let child = play(pos, mv);
+            let score = if self.ordered {
+                linear_eval(&child.feature_vector(), &DEFAULT_WEIGHTS)
+            } else {
+                0.
+            };
+            children.push((score, mv, child));
+        }
+
+        if self.ordered {
+            children.sort_by(|(a, ..), (b, ..)| b.total_cmp(a));
+        }
+
+        children
+    }
+
+    fn restore(&mut self, depth: usize, children: Vec<(f64, Placement, Position)>) {
+        self.children[depth] = children;
+    }
+
+    /// Value of the best placement of `pos`, within the `alpha`, `beta`
+    /// window. Values inside the window, bounds included, are exact, so
+    /// that pruning never hides a tie.
+    fn placement_reply(&mut self, pos: &Position, depth: usize, mut alpha: f64, beta: f64) -> f64 {
+        if depth == 0 || pos.current_piece == Color::Random {
+            return self.eval.score(pos);
+        }
+
+        let children = self.children(pos, depth, false);
+        let mut maxscore = GAME_OVER_SCORE;
+        for (_, _, child) in &children {
+            let score = self.garbage_reply(child, depth, alpha, beta);
+            maxscore = maxscore.max(score);
+            alpha = alpha.max(score);
+
+            if alpha > beta {
+                break;
+            }
+        }
+        self.restore(depth, children);
+
+        maxscore
+    }
+
+    /// Value of the worst garbage hole column for `pos`, which was reached
+    /// by the placement of the ply `depth` counts down from, within the
+    /// `alpha`, `beta` window.
+    fn garbage_reply(&mut self, pos: &Position, depth: usize, alpha: f64, mut beta: f64) -> f64 {
+        let mut minscore = f64::INFINITY;
+        for column in 0..pos.width {
+            let score = match pos.add_garbage(self.garbage, column) {
+                Some(pos) => self.placement_reply(&pos, depth - 1, alpha, beta),
+                None => GAME_OVER_SCORE,
+            };
+            minscore = minscore.min(score);
+            beta = beta.min(score);
+
+            if alpha > beta {
+                break;
+            }
+        }
+
+        minscore
+    }
+}
+
+/// Whether `mv` scoring `score` should replace `best` as the best move so
+/// far. Equal scores go to the lower rotation, then the lower column, then
+/// the move that does not hold, then the higher row (a tuck loses to the
+/// drop above it) and the lower piece, so that the move found does not
+/// depend on the order moves are generated in.
+fn beats((mv, score): (Placement, f64), best: Option<(Placement, f64)>) -> bool {
+    let tie_key = |(piece, x, y, rotation, swap): Placement| (rotation, x, swap, y, piece as usize);
+
+    match best {
+        None => true,
+        Some((best_mv, maxscore)) => {
+            score > maxscore || (score == maxscore && tie_key(mv) < tie_key(best_mv))
+        }
+    }
+}
+
+/// Placements of the current piece, swaps included.
+fn current_moves(pos: &Position) -> Vec<Placement> {
+    let mut moves = Vec::new();
+    current_moves_into(pos, &mut moves);
+    moves
+}
+
+/// Same as `current_moves`, in `buf` which is cleared first.
+fn current_moves_into(pos: &Position, buf: &mut Vec<Placement>) {
+    pos.legal_moves_into(buf);
+
+    if pos.current_piece == Color::Random {
+        let first = pos.possible_pieces().first().copied();
+        buf.retain(|mv| Some(mv.0) == first);
+    }
+}
+
+fn play(pos: &Position, (p, x, y, rot, swap): Placement) -> Position {
+    pos.apply_move(p, x, y, rot, swap, false)
+}
+
+/// Evaluates `children` and keeps the `width` best nodes among them and
+/// `settled`.
+fn select(
+    net: &FeedForwardNetwork,
+    mut settled: Vec<BeamNode>,
+    children: Vec<(Placement, Position)>,
+    width: usize,
+) -> Vec<BeamNode> {
+    let scores = evaluate_batch(net, children.iter().map(|(_, pos)| pos));
+
+    let mut beam = children
+        .into_iter()
+        .zip(scores)
+        .map(|((first_move, pos), score)| (first_move, pos, score))
+        .collect::<Vec<_>>();
+    beam.append(&mut settled);
+
+    // The sort is stable, so ties go to the earliest generated move
+    beam.sort_by(|a, b| b.2.total_cmp(&a.2));
+    beam.truncate(width.max(1));
+
+    beam
+}
+
+fn evaluate_batch<'a>(
+    net: &FeedForwardNetwork,
+    positions: impl Iterator<Item = &'a Position>,
+) -> Vec<f64> {
+    let mut game_over = Vec::new();
+    let mut inputs = Vec::new();
+
+    for pos in positions {
+        game_over.push(pos.is_game_over());
+        inputs.push(network_inputs(net, pos));
+    }
+
+    net.evaluate_batch(&inputs)
+        .into_iter()
+        .zip(game_over)
+        .map(|(outputs, game_over)| {
+            if game_over {
+                GAME_OVER_SCORE
+            } else {
+                outputs[0]
+            }
+        })
+        .collect()
+}
+
+/// Move chosen by `Search::run`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BestMove {
+    pub piece: Color,
+    pub x: usize,
+    pub y: usize,
+    pub rotation: usize,
+    pub swap: bool,
+    /// Evaluation of the move by the search
+    pub score: f64,
+    /// Inputs bringing the piece from its spawn to its placement
+    pub action_list: Vec<Action>,
+}
+
+impl BestMove {
+    /// `None` if the placement cannot be reached from the spawn.
+    fn new(
+        pos: &Position,
+        (piece, x, y, rotation, swap): Placement,
+        score: f64,
+    ) -> Option<BestMove> {
+        Some(BestMove {
+            piece,
+            x,
+            y,
+            rotation,
+            swap,
+            score,
+            action_list: pos.path(swap, (x as i32, y as i32, rotation as i32))?,
+        })
+    }
+
+    pub fn placement(&self) -> Placement {
+        (self.piece, self.x, self.y, self.rotation, self.swap)
+    }
+}
+
+/// Key of the evaluation of `pos` in the transposition table: the Zobrist
+/// hash of the board mixed with the rest of what `Position::feature_vector`
+/// reads, the lines just cleared and the pieces.
+fn tt_key(pos: &Position) -> u64 {
+    let mut hasher = FxHasher::default();
+    (
+        pos.hash,
+        pos.lines_cleared,
+        pos.current_piece,
+        pos.pocket,
+        pos.next_piece,
+    )
+        .hash(&mut hasher);
+
+    hasher.finish()
+}
+
 pub struct Search {
-    tt: TranspositionTable,
+    /// Leaf evaluations keyed by `tt_key`, shared with the threads searching
+    /// in parallel. They stay valid until the evaluator changes, see
+    /// `clear_tt`.
+    tt: Option<Arc<Mutex<TranspositionTable>>>,
+    /// Number of positions evaluated by the network
+    evaluations: usize,
+    /// Depth of the last completed iteration
+    depth: usize,
+    /// Time at which a timed search gives up on its current iteration
+    deadline: Option<Instant>,
+    timed_out: bool,
+    mode: EvalMode,
+    /// Moves of the node being searched at each depth, reused from one node
+    /// to the next
+    moves: Vec<Vec<Placement>>,
+}
+
+impl Default for Search {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Search {
     pub fn new() -> Search {
         Search {
-            tt: TranspositionTable::new(16384),
+            tt: Some(Arc::new(Mutex::new(TranspositionTable::new(16384)))),
+            evaluations: 0,
+            depth: 0,
+            deadline: None,
+            timed_out: false,
+            mode: EvalMode::BoardValue,
+            moves: Vec::new(),
         }
     }
 
-    pub fn run(
-        &mut self,
-        net: &mut FeedForwardNetwork,
-        pos: &Position,
-    ) -> Option<((usize, usize, usize), Vec<Action>)> {
+    /// Search evaluating every leaf, even the ones already seen.
+    pub fn without_tt() -> Search {
+        Search {
+            tt: None,
+            evaluations: 0,
+            depth: 0,
+            deadline: None,
+            timed_out: false,
+            mode: EvalMode::BoardValue,
+            moves: Vec::new(),
+        }
+    }
+
+    /// Forgets the evaluations made so far, which must be done whenever the
+    /// evaluator changes.
+    pub fn clear_tt(&mut self) {
+        if let Some(tt) = &self.tt {
+            tt.lock().unwrap().clear();
+        }
+    }
+
+    pub fn evaluations(&self) -> usize {
+        self.evaluations
+    }
+
+    /// Lookahead of the last move found, the root placement included.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn mode(&self) -> EvalMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: EvalMode) {
+        self.mode = mode;
+    }
+
+    pub fn run<E: Evaluator>(&mut self, net: &mut E, pos: &Position) -> Option<BestMove> {
+        if self.mode == EvalMode::MovePolicy {
+            return self.policy_move(net, pos);
+        }
+
         let mut best_move = None;
-        for depth in 2..4 {
+        for depth in 2..=MAX_DEPTH {
             best_move = self.search_root(net, depth, pos);
+            self.depth = depth;
+            trace!("Depth {}: best move {:?}", depth, best_move);
+        }
+        debug!(
+            "Searched {} plies, {} evaluations so far",
+            self.depth, self.evaluations
+        );
+
+        let (mv, score) = best_move?;
+
+        BestMove::new(pos, mv, score)
+    }
+
+    /// Deepens the search one ply at a time until `budget` runs out, keeping
+    /// the move of the deepest iteration that completed. The first ply is
+    /// always searched to the end, so a move is found even if the budget is
+    /// already exhausted.
+    pub fn run_timed<E: Evaluator>(
+        &mut self,
+        net: &mut E,
+        pos: &Position,
+        budget: Duration,
+    ) -> Option<BestMove> {
+        if self.mode == EvalMode::MovePolicy {
+            return self.policy_move(net, pos);
+        }
+
+        let deadline = Instant::now() + budget;
+
+        let mut best_move = self.search_root(net, 1, pos)?;
+        self.depth = 1;
+
+        self.deadline = Some(deadline);
+        for depth in 2..=MAX_TIMED_DEPTH {
+            if Instant::now() >= deadline {
+                break;
+            }
+
+            let mv = self.search_root(net, depth, pos);
+            if self.timed_out {
+                break;
+            }
+
+            if let Some(mv) = mv {
+                best_move = mv;
+                self.depth = depth;
+            }
+        }
+
+        debug!(
+            "Timed search completed depth {}, timed out: {}",
+            self.depth, self.timed_out
+        );
+        self.deadline = None;
+        self.timed_out = false;
+
+        let (mv, score) = best_move;
+
+        BestMove::new(pos, mv, score)
+    }
+
+    /// Legal move with the highest output of a `MovePolicy` network, ties
+    /// broken by `beats`.
+    fn policy_move<E: Evaluator>(&mut self, net: &mut E, pos: &Position) -> Option<BestMove> {
+        let outputs = net.evaluate(pos);
+        self.evaluations += 1;
+        self.depth = 1;
+
+        let mut best_move: Option<(Placement, f64)> = None;
+        for mv in current_moves(pos) {
+            let score = outputs[move_index(mv)];
+            if beats((mv, score), best_move) {
+                best_move = Some((mv, score));
+            }
         }
 
-        let best_move = best_move?;
+        let (mv, score) = best_move?;
 
-        Some((
-            best_move,
-            pos.path((best_move.0 as i32, best_move.1 as i32, best_move.2 as i32)),
-        ))
+        BestMove::new(pos, mv, score)
     }
 
-    fn search_root(
+    fn search_root<E: Evaluator>(
         &mut self,
-        net: &mut FeedForwardNetwork,
+        net: &mut E,
         depth: usize,
         pos: &Position,
-    ) -> Option<(usize, usize, usize)> {
-        let mut maxscore = -f64::INFINITY;
-        let mut best_move = None;
+    ) -> Option<(Placement, f64)> {
+        self.search_moves(net, depth, pos, current_moves(pos))
+    }
 
-        for &(p, x, y, rot) in pos.legal_moves()[0].iter() {
-            let pos = pos.apply_move(p, x, y, rot, false);
-            let score = self.search(net, pos, depth - 1);
+    /// Best of `moves` when looking `depth` placements ahead, `moves`
+    /// included.
+    fn search_moves<E: Evaluator>(
+        &mut self,
+        net: &mut E,
+        depth: usize,
+        pos: &Position,
+        moves: Vec<Placement>,
+    ) -> Option<(Placement, f64)> {
+        #[cfg(feature = "rayon")]
+        let scores = self.score_moves_parallel(net, depth, pos, &moves);
+        #[cfg(not(feature = "rayon"))]
+        let scores = self.score_moves(net, depth, pos, &moves);
 
-            if score > maxscore {
-                maxscore = score;
-                best_move = Some((x, y, rot));
+        let mut best_move: Option<(Placement, f64)> = None;
+
+        for (mv, score) in moves.into_iter().zip(scores) {
+            if beats((mv, score), best_move) {
+                best_move = Some((mv, score));
             }
         }
 
         best_move
     }
 
-    fn search(&mut self, net: &mut FeedForwardNetwork, pos: Position, depth: usize) -> f64 {
-        if depth == 0 {
-            if let Some(score) = self.tt.get(pos.hash) {
-                return score;
-            } else {
-                let features = pos.features();
+    #[cfg(any(not(feature = "rayon"), test))]
+    fn score_moves<E: Evaluator>(
+        &mut self,
+        net: &mut E,
+        depth: usize,
+        pos: &Position,
+        moves: &[Placement],
+    ) -> Vec<f64> {
+        moves
+            .iter()
+            .map(|&mv| self.search(net, play(pos, mv), depth - 1))
+            .collect()
+    }
 
-                // let score = features.aggregate_height * -0.510066
-                //     + features.holes * -0.35663
-                //     + features.bumpiness * -0.184483;
+    /// Same as `score_moves`, each thread searching with its own copy of the
+    /// network. The transposition table is shared.
+    #[cfg(feature = "rayon")]
+    fn score_moves_parallel<E: Evaluator>(
+        &mut self,
+        net: &E,
+        depth: usize,
+        pos: &Position,
+        moves: &[Placement],
+    ) -> Vec<f64> {
+        use rayon::prelude::*;
 
-                let score = net.activate(vec![
-                    features.holes,
-                    features.bumpiness,
-                    features.aggregate_height,
-                ])[0];
+        let tt = &self.tt;
+        let deadline = self.deadline;
+        let results: Vec<(f64, usize, bool)> = moves
+            .par_iter()
+            .map_init(
+                || {
+                    let worker = Search {
+                        tt: tt.clone(),
+                        deadline,
+                        ..Search::without_tt()
+                    };
+                    (worker, net.box_clone())
+                },
+                |(worker, net), &mv| {
+                    let evaluations = worker.evaluations;
+                    let score = worker.search(net, play(pos, mv), depth - 1);
 
-                self.tt.set(pos.hash, score);
+                    (score, worker.evaluations - evaluations, worker.timed_out)
+                },
+            )
+            .collect();
 
+        results
+            .into_iter()
+            .map(|(score, evaluations, timed_out)| {
+                self.evaluations += evaluations;
+                self.timed_out |= timed_out;
+                score
+            })
+            .collect()
+    }
+
+    fn search<E: Evaluator>(&mut self, net: &mut E, pos: Position, depth: usize) -> f64 {
+        if depth == 0 {
+            // Like in `evaluate_batch`, whatever the evaluator thinks of it
+            if pos.is_game_over() {
+                return GAME_OVER_SCORE;
+            }
+
+            let key = tt_key(&pos);
+            let signature = pos.signature();
+
+            let cached = self
+                .tt
+                .as_ref()
+                .and_then(|tt| tt.lock().unwrap().get(key, signature));
+
+            if let Some(score) = cached {
                 return score;
             }
-        }
 
-        let mut maxscore = 0.;
-        let piece_list = pos.legal_moves();
-        for piece_moves in piece_list {
-            if piece_moves.is_empty() {
-                continue;
+            let score = net.score(&pos);
+            self.evaluations += 1;
+            trace!("Leaf {:016x} scored {}", pos.hash, score);
+
+            if let Some(tt) = &self.tt {
+                tt.lock().unwrap().set(key, signature, score);
             }
 
-            let mut piece_maxscore = -f64::INFINITY;
+            return score;
+        }
+
+        // The score does not matter anymore once out of time, the whole
+        // iteration gets discarded
+        if self.timed_out
+            || self
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            self.timed_out = true;
+            return 0.;
+        }
 
-            let piece_color = piece_moves[0].0;
+        let known_piece = pos.current_piece != Color::Random;
+        let pieces = if known_piece {
+            [pos.current_piece].into_iter().collect()
+        } else {
+            pos.possible_pieces()
+        };
 
-            for (p, x, y, rot) in piece_moves {
-                let pos = pos.apply_move(p, x, y, rot, false);
+        if self.moves.len() <= depth {
+            self.moves.resize_with(depth + 1, Vec::new);
+        }
+        let mut moves = std::mem::take(&mut self.moves[depth]);
+        pos.legal_moves_into(&mut moves);
+
+        let mut maxscore = 0.;
+        for piece_color in pieces {
+            // A piece that cannot be placed ends the game
+            let mut piece_maxscore = GAME_OVER_SCORE;
+
+            // Holding is only considered at the root, it would more than
+            // double the size of the tree at every ply otherwise
+            for &(p, x, y, rot, _) in moves.iter().filter(|mv| !mv.4 && mv.0 == piece_color) {
+                let pos = pos.apply_move(p, x, y, rot, false, false);
                 let score = self.search(net, pos, depth - 1);
 
                 if score > piece_maxscore {
@@ -100,15 +1035,551 @@ impl Search {
                 }
             }
 
-            let prob = if pos.last_piece == piece_color {
-                3.57
+            let prob = if known_piece {
+                1.
             } else {
-                16.07
+                pos.draw_probability(piece_color)
             };
 
-            maxscore += piece_maxscore / prob;
+            maxscore += piece_maxscore * prob;
         }
+        self.moves[depth] = moves;
 
         maxscore
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::net::{Activation, Aggregation};
+
+    // Penalizes holes and height
+    const NET: &str = r#"{
+        "input_nodes": [-1, -2, -3],
+        "output_nodes": [0],
+        "node_evals": [[0, "tanh", "sum", 0.0, 1.0, [[-1, -0.5], [-3, -0.1]]]]
+    }"#;
+
+    const TWO_OUTPUTS_NET: &str = r#"{
+        "input_nodes": [-1, -2, -3],
+        "output_nodes": [0, 1],
+        "node_evals": [
+            [0, "tanh", "sum", 0.0, 1.0, [[-1, -0.5]]],
+            [1, "tanh", "sum", 0.0, 1.0, [[-3, -0.1]]]
+        ]
+    }"#;
+
+    #[test]
+    fn test_current_moves_into() {
+        let mut buf = vec![(Color::Z, 0, 0, 0, false)];
+
+        for tpn in ["4/4/4/4/1I2/II1I/ T S 0", "4/4/4/4/1I2/II1I/ ? S 0"] {
+            let pos = Position::from_str(tpn).unwrap();
+            current_moves_into(&pos, &mut buf);
+            assert!(!buf.is_empty());
+            assert_eq!(Some(buf.clone()), pos.legal_moves().into_iter().next());
+        }
+    }
+
+    #[test]
+    fn test_minimax_vs() {
+        // Stacking the O on the left scores more height, but the garbage
+        // then leaves a hole under it. Clearing both rows leaves nothing for
+        // the garbage hole to hide under.
+        let mut eval = LinearEvaluator {
+            weights: vec![-4., 0., 0.1],
+        };
+        let pos = Position::from_str("4/4/4/4/II2/II2/ O O 0").unwrap();
+
+        let (_, x, _, _, _) = minimax_vs(&mut eval, &pos, 1, 0, false).unwrap();
+        assert_eq!(x, 0);
+        let (_, x, _, _, _) = minimax_vs(&mut eval, &pos, 1, 1, false).unwrap();
+        assert_eq!(x, 2);
+
+        let (_, x, _, _, _) = minimax_vs(&mut eval, &pos, 2, 1, false).unwrap();
+        assert_eq!(x, 2);
+
+        // A move is still picked when every reply tops out
+        assert!(minimax_vs(&mut eval, &pos, 1, 6, false).is_some());
+    }
+
+    /// Counts the positions `eval` scores.
+    #[derive(Clone)]
+    struct Counting<E> {
+        eval: E,
+        evaluations: usize,
+    }
+
+    impl<E: Evaluator + Clone + 'static> Evaluator for Counting<E> {
+        fn score(&mut self, pos: &Position) -> f64 {
+            self.evaluations += 1;
+            self.eval.score(pos)
+        }
+    }
+
+    /// Placement `minimax_vs` finds two placements ahead against a garbage
+    /// line, with the number of positions `eval` scored to find it.
+    fn counted_minimax<E: Evaluator + Clone + 'static>(
+        eval: E,
+        pos: &Position,
+        ordered: bool,
+    ) -> (Option<Placement>, usize) {
+        let mut eval = Counting {
+            eval,
+            evaluations: 0,
+        };
+        let mv = minimax_vs(&mut eval, pos, 2, 1, ordered);
+        (mv, eval.evaluations)
+    }
+
+    #[test]
+    fn test_minimax_vs_ordering() {
+        let net = FeedForwardNetwork::from_json(NET).unwrap();
+        let mut linear_counts = [0; 2];
+        let mut net_counts = [0; 2];
+
+        // Along a game, ordering picks the same placements with fewer
+        // evaluations, be it with the heuristic itself or another evaluator
+        let mut pos = Position::with_seed(7);
+        for _ in 0..4 {
+            let (mv, unordered) = counted_minimax(LinearEvaluator::default(), &pos, false);
+            let (ordered_mv, ordered) = counted_minimax(LinearEvaluator::default(), &pos, true);
+            assert_eq!(mv, ordered_mv);
+            linear_counts[0] += unordered;
+            linear_counts[1] += ordered;
+
+            let (net_mv, unordered) = counted_minimax(net.clone(), &pos, false);
+            let (ordered_mv, ordered) = counted_minimax(net.clone(), &pos, true);
+            assert_eq!(net_mv, ordered_mv);
+            net_counts[0] += unordered;
+            net_counts[1] += ordered;
+
+            let (p, x, y, rot, swap) = mv.unwrap();
+            pos = pos.apply_move(p, x, y, rot, swap, true);
+        }
+
+        assert!(linear_counts[1] < linear_counts[0]);
+        assert!(net_counts[1] < net_counts[0]);
+    }
+
+    #[test]
+    fn test_network_inputs() {
+        let pos = Position::from_str("4/4/4/4/1I2/II1I/ T S 0").unwrap();
+
+        // Reads the row transitions and whether the current piece is a T
+        let mut net = FeedForwardNetwork::new(
+            (1..=30).map(|i| -i).collect(),
+            vec![0],
+            vec![(
+                0,
+                Activation::Relu,
+                Aggregation::Sum,
+                0.,
+                1.,
+                vec![(-5, 1.), (-15, 2.)],
+            )],
+        );
+        assert_eq!(network_inputs(&net, &pos), pos.feature_vector());
+        assert_eq!(net.score(&pos), pos.features().row_transitions + 2.);
+
+        let net = FeedForwardNetwork::from_json(NET).unwrap();
+        assert_eq!(network_inputs(&net, &pos), pos.feature_vector()[..3]);
+    }
+
+    #[test]
+    fn test_beam_search_lookahead() {
+        let net = FeedForwardNetwork::from_json(NET).unwrap();
+        let pos = Position::from_str("///1I2/3I/ L Z 0").unwrap();
+
+        // The L can clear the bottom row right away, but standing it up in
+        // the corner lets the Z clear two rows instead
+        assert_eq!(
+            beam_search(&net, &pos, 1, 8),
+            Some((Color::L, 0, 3, 2, false))
+        );
+        assert_eq!(
+            beam_search(&net, &pos, 2, 8),
+            Some((Color::L, 0, 2, 3, false))
+        );
+
+        let pos = pos.apply_move(Color::L, 0, 2, 3, false, false);
+        let outcome = pos.apply_move_detailed(Color::Z, 2, 2, 1, false, false);
+        assert_eq!(outcome.lines_cleared, 2);
+    }
+
+    #[test]
+    fn test_tt_transpositions() {
+        let mut net = FeedForwardNetwork::from_json(NET).unwrap();
+
+        // Holding plays the O first, after which the I can be dropped on the
+        // same boards as when it is played first
+        let pos = Position::from_str("4/4/4/4/4/4/ I O 0").unwrap();
+
+        let mut cached = Search::new();
+        let mut uncached = Search::without_tt();
+
+        let cached_move = cached.run(&mut net, &pos);
+        let uncached_move = uncached.run(&mut net, &pos);
+
+        assert_eq!(cached_move, uncached_move);
+        assert!(cached.evaluations() < uncached.evaluations());
+    }
+
+    #[test]
+    fn test_tt_key() {
+        let pos = Position::from_str("4/4/4/4/1I2/II1I/ T S 0").unwrap();
+        let other_pieces = Position::from_str("4/4/4/4/1I2/II1I/ S T 0").unwrap();
+        let cleared = Position {
+            lines_cleared: 1,
+            ..pos.clone()
+        };
+        assert_eq!(pos.hash, other_pieces.hash);
+
+        assert_ne!(tt_key(&pos), tt_key(&other_pieces));
+        assert_ne!(tt_key(&pos), tt_key(&cleared));
+    }
+
+    #[test]
+    fn test_clear_tt() {
+        let pos = Position::from_str("4/4/4/4/1I2/II1I/ T S 0").unwrap();
+        let moves = current_moves(&pos);
+        let mut flat = MockEvaluator { scores: Vec::new() };
+        let mut picky = MockEvaluator {
+            scores: vec![(play(&pos, moves[3]).hash, 2.)],
+        };
+
+        // The scores of the first evaluator are kept until cleared
+        let mut search = Search::new();
+        search.search_root(&mut flat, 1, &pos);
+        assert_eq!(search.search_root(&mut picky, 1, &pos).unwrap().1, 0.);
+
+        search.clear_tt();
+        assert_eq!(
+            search.search_root(&mut picky, 1, &pos),
+            Some((moves[3], 2.))
+        );
+    }
+
+    #[test]
+    fn test_run_timed() {
+        let mut net = FeedForwardNetwork::from_json(NET).unwrap();
+        let pos = Position::from_str("4/4/4/4/4/4/ T O 0").unwrap();
+
+        let mut search = Search::new();
+        let mv = search
+            .run_timed(&mut net, &pos, Duration::from_millis(1))
+            .unwrap();
+        assert!(pos.legal_moves()[0].contains(&mv.placement()));
+        assert!(search.depth() >= 1);
+
+        let mut search = Search::new();
+        let mv = search
+            .run_timed(&mut net, &pos, Duration::from_millis(500))
+            .unwrap();
+        assert!(pos.legal_moves()[0].contains(&mv.placement()));
+        assert!(search.depth() >= 2);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_parallel_root() {
+        let mut net = FeedForwardNetwork::from_json(NET).unwrap();
+        let pos = Position::from_str("4/4/4/4/1I2/II1I/ T S 0").unwrap();
+        let moves = current_moves(&pos);
+
+        let sequential = Search::new().score_moves(&mut net, 2, &pos, &moves);
+        let parallel = Search::new().score_moves_parallel(&net, 2, &pos, &moves);
+        assert_eq!(sequential, parallel);
+
+        let best_move = Search::new()
+            .search_root(&mut net, 2, &pos)
+            .map(|(mv, _)| mv);
+        let first_best = moves
+            .iter()
+            .zip(&sequential)
+            .fold((None, -f64::INFINITY), |best, (&mv, &score)| {
+                if score > best.1 {
+                    (Some(mv), score)
+                } else {
+                    best
+                }
+            })
+            .0;
+        assert_eq!(best_move, first_best);
+    }
+
+    #[test]
+    fn test_monte_carlo_move() {
+        let net = FeedForwardNetwork::from_json(NET).unwrap();
+        let pos = Position::from_str("4/4/4/4/1I2/II1I/ T S 0").unwrap();
+
+        let play = |seed| {
+            let pos = Position {
+                seed: Some(seed),
+                ..pos.clone()
+            };
+            monte_carlo_move(&net, &pos, 4, 2)
+        };
+
+        let mv = play(7).unwrap();
+        assert!(current_moves(&pos).contains(&mv));
+        assert_eq!(play(7), Some(mv));
+    }
+
+    #[test]
+    fn test_eval_mode() {
+        let mut net = FeedForwardNetwork::from_json(NET).unwrap();
+        let pos = Position::from_str("4/4/4/4/1I2/II1I/ T S 0").unwrap();
+
+        let mut search = Search::new();
+        search.set_mode(EvalMode::for_network(&net));
+        assert_eq!(search.mode(), EvalMode::BoardValue);
+        assert_eq!(
+            search.run(&mut net, &pos),
+            Search::new().run(&mut net, &pos)
+        );
+
+        // Only the output of the T pointing up on the left is set
+        let target = (Color::T, 0, 2, 2, false);
+        let node_evals = (0..MOVE_SPACE as i64)
+            .map(|node| {
+                let bias = if node as usize == move_index(target) {
+                    1.
+                } else {
+                    0.
+                };
+                (node, Activation::Relu, Aggregation::Sum, bias, 1., vec![])
+            })
+            .collect();
+        let mut policy = FeedForwardNetwork::new(
+            vec![-1, -2, -3],
+            (0..MOVE_SPACE as i64).collect(),
+            node_evals,
+        );
+
+        let mut search = Search::new();
+        search.set_mode(EvalMode::for_network(&policy));
+        assert_eq!(search.mode(), EvalMode::MovePolicy);
+
+        let best_move = search.run(&mut policy, &pos).unwrap();
+        assert_eq!(best_move.placement(), target);
+        assert_eq!(best_move.score, 1.);
+        assert_eq!(search.evaluations(), 1);
+    }
+
+    #[test]
+    fn test_linear_evaluator() {
+        let pos = Position::from_str("4/4/4/4/4/II2/ O T 0").unwrap();
+
+        // The O fits flat in the gap, on top of the I it would cover a hole
+        let flat = play(&pos, (Color::O, 2, 4, 0, false));
+        let covering = play(&pos, (Color::O, 1, 3, 0, false));
+        assert_eq!(flat.features().holes, 0.);
+        assert!(covering.features().holes > 0.);
+
+        let mut linear = LinearEvaluator::default();
+        assert!(linear.score(&flat) > linear.score(&covering));
+
+        let best_move = Search::new().run(&mut linear, &pos).unwrap();
+        assert!(current_moves(&pos).contains(&best_move.placement()));
+
+        assert_eq!(linear_eval(&[1., 2., 3.], &[0.5, -1.]), -1.5);
+    }
+
+    #[test]
+    fn test_ensemble() {
+        let pos = Position::from_str("4/4/4/4/1I2/II1I/ T S 0").unwrap();
+        let mut flat = LinearEvaluator::default();
+        let mut holes = LinearEvaluator {
+            weights: vec![0., 0., 0., 0., 0., -3.],
+        };
+        let (a, b) = (flat.score(&pos), holes.score(&pos));
+        assert_ne!(a, b);
+
+        let mut ensemble = Ensemble::new(vec![Box::new(flat), Box::new(holes)], EnsembleMode::Mean);
+        assert_eq!(ensemble.score(&pos), (a + b) / 2.);
+        assert_eq!(ensemble.evaluate(&pos), vec![(a + b) / 2.]);
+
+        ensemble.mode = EnsembleMode::Max;
+        assert_eq!(ensemble.score(&pos), a.max(b));
+
+        let mut third = LinearEvaluator {
+            weights: vec![0., 0., 0., 0., 0., 0., -2.],
+        };
+        let c = third.score(&pos);
+        let mut sorted = [a, b, c];
+        sorted.sort_by(f64::total_cmp);
+        ensemble.nets.push(Box::new(third));
+        ensemble.mode = EnsembleMode::Median;
+        assert_eq!(ensemble.score(&pos), sorted[1]);
+
+        // Networks vote along with linear evaluators
+        let mut net = FeedForwardNetwork::from_json(NET).unwrap();
+        let d = net.score(&pos);
+        ensemble.nets.push(Box::new(net));
+        let mut sorted = [a, b, c, d];
+        sorted.sort_by(f64::total_cmp);
+        assert_eq!(ensemble.score(&pos), (sorted[1] + sorted[2]) / 2.);
+        assert_eq!(ensemble.clone().score(&pos), ensemble.score(&pos));
+        assert_eq!(ensemble.num_outputs(), 1);
+
+        // Only the outputs every member has are combined
+        let mut two_outputs = FeedForwardNetwork::from_json(TWO_OUTPUTS_NET).unwrap();
+        assert_eq!(two_outputs.evaluate(&pos).len(), 2);
+        let mut mixed = Ensemble::new(
+            vec![Box::new(two_outputs), Box::new(LinearEvaluator::default())],
+            EnsembleMode::Mean,
+        );
+        assert_eq!(mixed.num_outputs(), 1);
+        assert_eq!(mixed.evaluate(&pos).len(), 1);
+
+        // Without members, the built-in evaluation is used
+        let mut empty = Ensemble::default();
+        let linear = LinearEvaluator::default().score(&pos);
+        assert_eq!(empty.score(&pos), linear);
+        assert_eq!(empty.evaluate(&pos), vec![linear]);
+    }
+
+    #[test]
+    fn test_principal_variation() {
+        // Neither O clears a line on its own, the second one completes both rows
+        let pos = Position::from_str("5/5/5/5/I4/I4/ O O 0").unwrap();
+        let mut linear = LinearEvaluator {
+            weights: vec![-4., 0., -1.],
+        };
+
+        let line = principal_variation(&mut linear, &pos, 3);
+        assert_eq!(line.len(), 2);
+        assert_eq!(line[0].1.total_lines, 0);
+        assert_eq!(line[1].0 .0, Color::O);
+        assert_eq!(line[1].1.total_lines, 2);
+        assert!(line[1].1.column_heights().iter().all(|&height| height == 0));
+
+        // Each placement leads to the next position
+        assert_eq!(line[1].1, play(&line[0].1, line[1].0));
+
+        assert_eq!(principal_variation(&mut linear, &pos, 1).len(), 1);
+        assert!(principal_variation(&mut linear, &pos, 0).is_empty());
+    }
+
+    /// Scores the boards it knows, the others being worth 0.
+    #[derive(Clone)]
+    struct MockEvaluator {
+        scores: Vec<(u64, f64)>,
+    }
+
+    impl Evaluator for MockEvaluator {
+        fn score(&mut self, pos: &Position) -> f64 {
+            self.scores
+                .iter()
+                .find(|(hash, _)| *hash == pos.hash)
+                .map_or(0., |&(_, score)| score)
+        }
+    }
+
+    #[test]
+    fn test_mock_evaluator() {
+        let pos = Position::from_str("4/4/4/4/1I2/II1I/ T S 0").unwrap();
+        let moves = current_moves(&pos);
+        let board = |mv| play(&pos, mv).hash;
+
+        let mut mock = MockEvaluator {
+            scores: vec![(board(moves[3]), 2.), (board(moves[5]), 1.)],
+        };
+        let mut search = Search::without_tt();
+        assert_eq!(search.search_root(&mut mock, 1, &pos), Some((moves[3], 2.)));
+        // Boards the S cannot spawn on score as a loss without evaluation
+        let live = moves
+            .iter()
+            .filter(|&&mv| !play(&pos, mv).is_game_over())
+            .count();
+        assert!(live < moves.len());
+        assert_eq!(search.evaluations(), live);
+
+        // Every board worth the same, the tie-break picks the lowest
+        // rotation and column, then the lowest of the rest of its order
+        let lowest = *moves
+            .iter()
+            .min_by_key(|&&(piece, x, y, rotation, swap)| (rotation, x, swap, y, piece as usize))
+            .unwrap();
+        let mut mock = MockEvaluator { scores: Vec::new() };
+        assert_eq!(
+            Search::new().search_root(&mut mock, 1, &pos),
+            Some((lowest, 0.))
+        );
+    }
+
+    #[test]
+    fn test_tie_break() {
+        let pos = Position::from_str("4/4/4/4/1I2/II1I/ T S 0").unwrap();
+        let mut moves = current_moves(&pos);
+        let mut mock = MockEvaluator { scores: Vec::new() };
+
+        // The order of the moves does not matter
+        let expected = (Color::T, 0, 2, 0, false);
+        for _ in 0..2 {
+            let (mv, _) = Search::without_tt()
+                .search_moves(&mut mock, 1, &pos, moves.clone())
+                .unwrap();
+            assert_eq!(mv, expected);
+            moves.reverse();
+        }
+
+        // Lower rotation first, then lower column, then not holding
+        let ordered = [
+            (Color::T, 1, 2, 0, false),
+            (Color::S, 1, 2, 0, true),
+            (Color::T, 0, 2, 1, false),
+        ];
+        for pair in ordered.windows(2) {
+            assert!(beats((pair[0], 0.), Some((pair[1], 0.))));
+            assert!(!beats((pair[1], 0.), Some((pair[0], 0.))));
+        }
+        assert!(beats((ordered[2], 1.), Some((ordered[0], 0.))));
+
+        // Moves left tied by those, a drop and a tuck under it or the same
+        // placement of the pieces a random one can be, still have an order
+        let (drop, tuck) = ((Color::T, 1, 2, 0, false), (Color::T, 1, 4, 0, false));
+        assert!(beats((drop, 0.), Some((tuck, 0.))));
+        assert!(!beats((tuck, 0.), Some((drop, 0.))));
+        let (i, o) = ((Color::I, 1, 2, 0, false), (Color::O, 1, 2, 0, false));
+        assert_ne!(beats((i, 0.), Some((o, 0.))), beats((o, 0.), Some((i, 0.))));
+    }
+
+    #[test]
+    fn test_avoids_top_out() {
+        // Holding the T into the well leaves some of the pieces that may come
+        // next no room to spawn, however good the board looks to the evaluator
+        let pos: Position = format!("////{} O T 0", "IIII1IIIII/".repeat(18))
+            .parse()
+            .unwrap();
+        let top_out = (Color::T, 4, 1, 1, true);
+        assert!(current_moves(&pos).contains(&top_out));
+        assert!(play(&pos, top_out).is_game_over());
+
+        let mut linear = LinearEvaluator::default();
+        let best_move = Search::new().run(&mut linear, &pos).unwrap();
+        assert_ne!(best_move.placement(), top_out);
+        assert!(!play(&pos, best_move.placement()).is_game_over());
+
+        // The default weights score every board below 0, so only a line that
+        // tops out further down could score more than this
+        assert!(best_move.score < 0.);
+        assert!(best_move.score > GAME_OVER_SCORE);
+    }
+
+    #[test]
+    fn test_best_move_score() {
+        let mut net = FeedForwardNetwork::from_json(NET).unwrap();
+        let pos = Position::from_str("4/4/4/4/1I2/II1I/ T S 0").unwrap();
+
+        let best_move = Search::new().run(&mut net, &pos).unwrap();
+
+        let pos = play(&pos, best_move.placement());
+        let score = Search::without_tt().search(&mut net, pos, MAX_DEPTH - 1);
+        assert_eq!(best_move.score, score);
+    }
+}
@@ -0,0 +1,255 @@
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use rustc_hash::FxHashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    net::FeedForwardNetwork,
+    pos::{Color, MoveResult, Placement, Position},
+    search::beam_search,
+};
+
+/// Placements after which a game is stopped even if it is not over
+pub const MAX_PIECES: usize = 1000;
+
+/// Lookahead of the self-play games, which only covers the known pieces
+const SELF_PLAY_DEPTH: usize = 2;
+
+/// Lines kept at each ply of the self-play beam search
+const SELF_PLAY_WIDTH: usize = 8;
+
+/// Plays from `pos` the placements picked by `choose` until the game is
+/// over, or for at most `MAX_PIECES` placements, and returns the final
+/// position.
+pub fn play_game<F>(mut pos: Position, mut choose: F) -> Position
+where
+    F: FnMut(&Position) -> Option<Placement>,
+{
+    for _ in 0..MAX_PIECES {
+        let Some((p, x, y, rot, swap)) = choose(&pos) else {
+            break;
+        };
+
+        match pos.try_apply_move(p, x, y, rot, swap, true) {
+            MoveResult::Ok(new_pos) => pos = new_pos,
+            MoveResult::GameOver(new_pos) => return new_pos,
+            MoveResult::Illegal => break,
+        }
+    }
+
+    pos
+}
+
+/// Counts how often each board comes back with the same piece to play, so
+/// that games looping forever can be stopped as draws.
+#[derive(Debug, Clone, Default)]
+pub struct CycleDetector {
+    /// Occurrences allowed before a board counts as repeating, 0 for no limit
+    pub max_repeats: usize,
+    seen: FxHashMap<(u64, Color), usize>,
+}
+
+impl CycleDetector {
+    pub fn new(max_repeats: usize) -> CycleDetector {
+        CycleDetector {
+            max_repeats,
+            seen: FxHashMap::default(),
+        }
+    }
+
+    /// Records `pos`, and returns whether its board and current piece have
+    /// now been seen more than `max_repeats` times.
+    pub fn repeated(&mut self, pos: &Position) -> bool {
+        if self.max_repeats == 0 {
+            return false;
+        }
+
+        let count = self.seen.entry((pos.hash, pos.current_piece)).or_default();
+        *count += 1;
+        *count > self.max_repeats
+    }
+}
+
+/// Record of a game, enough to replay it exactly.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Recorder {
+    /// Position the game started from, whose seed draws all the pieces
+    #[serde(deserialize_with = "Position::deserialize_checked")]
+    pub start: Position,
+    pub seed: u64,
+    /// Current piece before each placement, i.e. the pieces dealt in order
+    pub pieces: Vec<Color>,
+    /// Placements chosen, the last one may have ended the game
+    pub moves: Vec<Placement>,
+}
+
+impl Recorder {
+    /// Every position of the game, from the start to the final one.
+    pub fn replay(&self) -> Vec<Position> {
+        let mut positions = vec![self.start.clone()];
+
+        for &(p, x, y, rot, swap) in &self.moves {
+            let pos = positions.last().unwrap();
+
+            match pos.try_apply_move(p, x, y, rot, swap, true) {
+                MoveResult::Ok(new_pos) => positions.push(new_pos),
+                MoveResult::GameOver(new_pos) => {
+                    positions.push(new_pos);
+                    break;
+                }
+                MoveResult::Illegal => break,
+            }
+        }
+
+        positions
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("Records always serialize")
+    }
+
+    pub fn from_json(s: &str) -> serde_json::Result<Recorder> {
+        serde_json::from_str(s)
+    }
+}
+
+/// Same as `play_game`, also returning a record of the game. Positions
+/// without a seed are given one, so that the pieces can be drawn again.
+pub fn record_game<F>(mut pos: Position, mut choose: F) -> (Position, Recorder)
+where
+    F: FnMut(&Position) -> Option<Placement>,
+{
+    let seed = *pos
+        .seed
+        .get_or_insert_with(|| ChaCha8Rng::from_entropy().gen());
+    let mut record = Recorder {
+        start: pos.clone(),
+        seed,
+        pieces: Vec::new(),
+        moves: Vec::new(),
+    };
+
+    let pos = play_game(pos, |pos| {
+        let mv = choose(pos)?;
+        record.pieces.push(pos.current_piece);
+        record.moves.push(mv);
+        Some(mv)
+    });
+
+    (pos, record)
+}
+
+/// Plays `games` games on empty boards and returns their final scores. Each
+/// game draws its pieces from a seed derived from `seed`, so a network always
+/// gets the same scores. Moves come from `beam_search` rather than the full
+/// expectimax `Search`, which would take minutes per game.
+pub fn self_play(net: &FeedForwardNetwork, games: usize, seed: u64) -> Vec<i64> {
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+
+    (0..games)
+        .map(|_| {
+            let pos = Position::with_seed(rng.gen());
+            let pos = play_game(pos, |pos| {
+                beam_search(net, pos, SELF_PLAY_DEPTH, SELF_PLAY_WIDTH)
+            });
+
+            pos.score
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    // Rewards height, so that games end quickly
+    const NET: &str = r#"{
+        "input_nodes": [-1, -2, -3],
+        "output_nodes": [0],
+        "node_evals": [[0, "tanh", "sum", 0.0, 1.0, [[-3, 0.01]]]]
+    }"#;
+
+    // Keeps the stack low and without holes
+    const FLAT_NET: &str = r#"{
+        "input_nodes": [-1, -2, -3],
+        "output_nodes": [0],
+        "node_evals": [[0, "tanh", "sum", 0.0, 1.0, [[-1, -0.1], [-3, -0.01]]]]
+    }"#;
+
+    #[test]
+    fn test_self_play() {
+        let net = FeedForwardNetwork::from_json(NET).unwrap();
+
+        let scores = self_play(&net, 5, 42);
+        assert_eq!(scores.len(), 5);
+        assert!(scores.iter().all(|&score| score >= 0));
+
+        assert_eq!(self_play(&net, 1, 42), scores[..1]);
+    }
+
+    #[test]
+    fn test_record_game() {
+        let net = FeedForwardNetwork::from_json(NET).unwrap();
+
+        let start = Position::from_str("////////////////////I9/IIIIII4/ T O 0").unwrap();
+        let (pos, record) = record_game(start, |pos| beam_search(&net, pos, 1, 4));
+        assert_eq!(record.start.seed, Some(record.seed));
+        assert_eq!(record.pieces.len(), record.moves.len());
+        assert!(!record.moves.is_empty());
+
+        let record = Recorder::from_json(&record.to_json()).unwrap();
+        let positions = record.replay();
+        assert_eq!(positions.len(), record.moves.len() + 1);
+        assert_eq!(positions.last(), Some(&pos));
+        assert_eq!(positions.last().unwrap().score, pos.score);
+
+        // The start board is checked like any position read from JSON
+        let mut corrupt = record.clone();
+        corrupt.start.board[0][0] = Color::Random;
+        assert!(Recorder::from_json(&corrupt.to_json()).is_err());
+    }
+
+    #[test]
+    fn test_cycle_detector() {
+        let pos = Position::from_str("////////////////////I9/IIIIII4/ T O 0").unwrap();
+        let mut detector = CycleDetector::new(2);
+        assert!(!detector.repeated(&pos));
+        assert!(!detector.repeated(&pos));
+        assert!(detector.repeated(&pos));
+
+        let mut other = pos.clone();
+        other.current_piece = Color::I;
+        assert!(!detector.repeated(&other));
+
+        let mut unlimited = CycleDetector::default();
+        assert!((0..10).all(|_| !unlimited.repeated(&pos)));
+    }
+
+    #[test]
+    fn test_cycle_ends_game() {
+        // Flat play on a board four cells wide soon comes back to a stack it
+        // already had with the same piece to play
+        let rows = "4/".repeat(12);
+        let mut start = Position::from_str(&format!("{} T O 0", rows)).unwrap();
+        start.seed = Some(5);
+
+        let net = FeedForwardNetwork::from_json(FLAT_NET).unwrap();
+        let mut detector = CycleDetector::new(2);
+        let (mut pieces, mut repeated) = (0, false);
+        let end = play_game(start, |pos| {
+            if detector.repeated(pos) {
+                repeated = true;
+                return None;
+            }
+            pieces += 1;
+
+            beam_search(&net, pos, 2, 8)
+        });
+
+        assert!(repeated);
+        assert!(!end.is_game_over());
+        assert!(pieces < MAX_PIECES);
+    }
+}
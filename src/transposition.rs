@@ -1,6 +1,7 @@
 #[derive(Clone)]
 pub struct Entry {
     hash: u64,
+    signature: u64,
     score: f64,
 }
 
@@ -12,17 +13,27 @@ impl TranspositionTable {
     }
 
     // Always replace
-    pub fn set(&mut self, hash: u64, score: f64) {
+    pub fn set(&mut self, hash: u64, signature: u64, score: f64) {
         let idx = hash as usize % self.0.len();
-        self.0[idx] = Some(Entry { hash, score });
+        self.0[idx] = Some(Entry {
+            hash,
+            signature,
+            score,
+        });
     }
 
-    pub fn get(&self, hash: u64) -> Option<f64> {
+    pub fn clear(&mut self) {
+        self.0.fill(None);
+    }
+
+    /// Score stored for `hash`, provided the entry also has the same
+    /// `signature`, which catches most collisions of the full hash.
+    pub fn get(&self, hash: u64, signature: u64) -> Option<f64> {
         let idx = hash as usize % self.0.len();
 
         self.0[idx]
             .clone()
-            .filter(|entry| entry.hash == hash)
+            .filter(|entry| entry.hash == hash && entry.signature == signature)
             .map(|entry| entry.score)
     }
 }
@@ -35,10 +46,16 @@ mod tests {
     fn test_get_set() {
         let mut tt = TranspositionTable::new(10);
 
-        tt.set(3, 22.);
+        tt.set(3, 7, 22.);
+
+        assert_eq!(tt.get(3, 7), Some(22.));
+        assert_eq!(tt.get(13, 7), None);
+        assert_eq!(tt.get(1, 7), None);
+
+        // Same hash, different board
+        assert_eq!(tt.get(3, 8), None);
 
-        assert_eq!(tt.get(3), Some(22.));
-        assert_eq!(tt.get(13), None);
-        assert_eq!(tt.get(1), None);
+        tt.clear();
+        assert_eq!(tt.get(3, 7), None);
     }
 }
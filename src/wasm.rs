@@ -0,0 +1,104 @@
+//! Bindings for running the engine in the browser. Positions, networks and
+//! results cross the JS boundary as JSON strings, errors as their message.
+
+use serde_json::json;
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    net::FeedForwardNetwork,
+    pos::Position,
+    search::{check_network_inputs, EvalMode, Search},
+};
+
+fn parse_position(position: &str) -> Result<Position, String> {
+    Position::from_json(position).map_err(|err| err.to_string())
+}
+
+/// Position of a TPN string, as the JSON the other functions take.
+#[wasm_bindgen]
+pub fn position_from_tpn(tpn: &str) -> Result<String, String> {
+    let pos: Position = tpn
+        .parse()
+        .map_err(|_| format!("Invalid position {:?}", tpn))?;
+    pos.validate().map_err(|err| err.to_string())?;
+
+    Ok(pos.to_json())
+}
+
+/// `Position::features` of a position given as JSON.
+#[wasm_bindgen]
+pub fn features(position: &str) -> Result<String, String> {
+    let pos = parse_position(position)?;
+
+    Ok(serde_json::to_string(&pos.features()).expect("Features always serialize"))
+}
+
+/// `Position::legal_moves` of a position given as JSON, as one list per
+/// piece the current one can be.
+#[wasm_bindgen]
+pub fn legal_moves(position: &str) -> Result<String, String> {
+    let pos = parse_position(position)?;
+
+    Ok(serde_json::to_string(&pos.legal_moves()).expect("Moves always serialize"))
+}
+
+/// Move `Search` picks with `network`, both given as JSON, with the same
+/// fields as `Out::Move`. `null` when there is no legal move.
+#[wasm_bindgen]
+pub fn best_move(position: &str, network: &str) -> Result<String, String> {
+    let pos = parse_position(position)?;
+    let mut net = FeedForwardNetwork::from_json(network).map_err(|err| err.to_string())?;
+    net.validate().map_err(|err| err.to_string())?;
+    check_network_inputs(&net)?;
+
+    let mut search = Search::new();
+    search.set_mode(EvalMode::for_network(&net));
+    let best_move = search.run(&mut net, &pos).map(|best_move| {
+        json!({
+            "col": best_move.x,
+            "rotation": best_move.rotation,
+            "swap": best_move.swap,
+            "action_list": best_move.action_list,
+            "score": best_move.score,
+        })
+    });
+
+    Ok(serde_json::to_string(&best_move).expect("Moves always serialize"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NET: &str = r#"{
+        "input_nodes": [-1, -2, -3],
+        "output_nodes": [0],
+        "node_evals": [[0, "tanh", "sum", 0.0, 1.0, [[-1, -0.5], [-3, -0.1]]]]
+    }"#;
+
+    #[test]
+    fn test_bindings() {
+        let pos = position_from_tpn("4/4/4/4/1I2/II1I/ T S 0").unwrap();
+        assert!(position_from_tpn("not a position").is_err());
+
+        let board: serde_json::Value = serde_json::from_str(&features(&pos).unwrap()).unwrap();
+        assert_eq!(board["aggregate_height"], 4.);
+
+        let moves: serde_json::Value = serde_json::from_str(&legal_moves(&pos).unwrap()).unwrap();
+        assert!(!moves[0].as_array().unwrap().is_empty());
+
+        let best: serde_json::Value = serde_json::from_str(&best_move(&pos, NET).unwrap()).unwrap();
+        assert!(best["col"].is_u64());
+        assert!(best_move(&pos, "{}").is_err());
+        assert!(features("{}").is_err());
+    }
+}
+
+// Keeps the signatures JS sees from changing by accident. Checked by
+// `cargo check --target wasm32-unknown-unknown --features wasm`.
+#[cfg(target_arch = "wasm32")]
+type Binding = fn(&str) -> Result<String, String>;
+#[cfg(target_arch = "wasm32")]
+const _: [Binding; 3] = [position_from_tpn, features, legal_moves];
+#[cfg(target_arch = "wasm32")]
+const _: fn(&str, &str) -> Result<String, String> = best_move;
@@ -40,4 +40,20 @@ def loads(s: str) -> Position:
     next_piece = piece_to_int[tokens[2][0]]
     score = int(tokens[3])
 
-    return Position(board, current_piece, next_piece, score)
\ No newline at end of file
+    return Position(board, current_piece, next_piece, score)
+
+
+def apply_diff(position: Position, diff: dict) -> Position:
+    for rows in diff["cleared_rows"]:
+        for row in rows:
+            del position.board[row]
+            position.board.insert(0, [0] * len(position.board[0]))
+
+    for x, y, piece in diff["cells"]:
+        position.board[y][x] = piece
+
+    position.current_piece = diff["current_piece"]
+    position.next_piece = diff["next_piece"]
+    position.score = diff["score"]
+
+    return position